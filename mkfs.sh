@@ -17,11 +17,13 @@ t
 b
 a
 w
-"|fdisk $hdd > /dev/null 2>&1 ;mkfs.vfat -F 32 $hdd > /dev/null 2>&1
+"|fdisk $hdd > /dev/null 2>&1
+# fdisk puts the first partition at sector 2048.
+mkfs.vfat -F 32 -n TRANSPARENT --offset 2048 $hdd > /dev/null 2>&1
 
 echo '[fs: 3/3] copying files to the disk'
 mkdir -p fs
-sudo mount $hdd fs/
+sudo mount -o loop,offset=$((2048 * 512)) $hdd fs/
 
 sudo cp README fs/README.TXT
 sudo cp README fs/this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt
@@ -51,4 +53,9 @@ echo 'hey, my name is file 21' | sudo tee -a fs/FILE21.TXT > /dev/null
 echo 'hey, my name is file 22' | sudo tee -a fs/FILE22.TXT > /dev/null
 echo 'hey, my name is file 23' | sudo tee -a fs/FILE23.TXT > /dev/null
 
+# Boot with init=/THREADS.ELF to run it, see user/build.ninja.
+if [ -f user/threads.elf ]; then
+    sudo cp user/threads.elf fs/THREADS.ELF
+fi
+
 sudo umount fs/
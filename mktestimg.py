@@ -0,0 +1,576 @@
+#!/usr/bin/env python3
+"""Build the filesystem images the kernel tests run against.
+
+The images are built byte by byte instead of with mkfs, so that they come
+out the same everywhere, and are stored sparse (only the blocks that aren't
+all zeros) so that they're small enough to embed in the test kernel.
+
+Sparse format, all little endian:
+
+    u32 block size
+    u32 number of blocks
+    (u32 block number, block) for every non-zero block, in order
+
+Usage: ./mktestimg.py [output directory, default testdata/]
+"""
+
+import os
+import struct
+import sys
+
+SECTOR = 512
+
+
+def lines(name, count):
+    """Test file contents that are easy to check from the kernel."""
+    return b"".join(b"%s line %03d\n" % (name, i) for i in range(count))
+
+
+# 2023-03-14 12:34:56
+TIME = (12 << 11) | (34 << 5) | (56 // 2)
+DATE = ((2023 - 1980) << 9) | (3 << 5) | 14
+
+ATTR_READ_ONLY = 0x01
+ATTR_HIDDEN = 0x02
+ATTR_SYSTEM = 0x04
+ATTR_VOLUME_ID = 0x08
+ATTR_DIRECTORY = 0x10
+ATTR_ARCHIVE = 0x20
+ATTR_LONG_NAME = 0x0F
+
+
+def short_entry(name, attr, cluster=0, size=0):
+    assert len(name) == 11
+    return struct.pack(
+        "<11sBBBHHHHHHHI",
+        name,
+        attr,
+        0,  # NTRes
+        100,  # creation time, tenths of a second
+        TIME,
+        DATE,
+        DATE,  # last access
+        cluster >> 16,
+        TIME,
+        DATE,
+        cluster & 0xFFFF,
+        size,
+    )
+
+
+def lfn_checksum(short_name):
+    s = 0
+    for c in short_name:
+        s = (((s & 1) << 7) + (s >> 1) + c) & 0xFF
+    return s
+
+
+def lfn_entries(long_name, short_name):
+    """The LFN entries for `long_name`, in on-disk order (last one first)."""
+    chars = [ord(c) for c in long_name]
+    if len(chars) % 13:
+        chars.append(0)
+    while len(chars) % 13:
+        chars.append(0xFFFF)
+    checksum = lfn_checksum(short_name)
+    entries = []
+    for i in range(len(chars) // 13):
+        part = chars[i * 13 : (i + 1) * 13]
+        order = i + 1
+        if i == len(chars) // 13 - 1:
+            order |= 0x40
+        entries.append(
+            struct.pack(
+                "<B10sBBB12sH4s",
+                order,
+                struct.pack("<5H", *part[0:5]),
+                ATTR_LONG_NAME,
+                0,
+                checksum,
+                struct.pack("<6H", *part[5:11]),
+                0,
+                struct.pack("<2H", *part[11:13]),
+            )
+        )
+    return b"".join(reversed(entries))
+
+
+class Fat32Image:
+    RESERVED = 32
+    FATS = 2
+    CLUSTERS = 65600  # Just above the 65525 FAT32 needs at least.
+    EOC = 0x0FFFFFFF
+
+    def __init__(self, sector=SECTOR, cluster_sectors=1, root_cluster=2, active_fat=None):
+        """`active_fat` turns off FAT mirroring, only that FAT is written."""
+        self.sector = sector
+        self.cluster_sectors = cluster_sectors
+        self.cluster = sector * cluster_sectors
+        self.root_cluster = root_cluster
+        self.active_fat = active_fat
+        self.fat_sectors = -(-(self.CLUSTERS + 2) * 4 // sector)
+        self.data_start = self.RESERVED + self.FATS * self.fat_sectors
+        self.total = self.data_start + self.CLUSTERS * cluster_sectors
+        self.sectors = {}
+        self.fat = [0x0FFFFFF8, 0x0FFFFFFF]
+
+    def write(self, sector, data):
+        """Write `data` at `sector`, in blocks of SECTOR bytes."""
+        first = sector * self.sector // SECTOR
+        for i in range(0, len(data), SECTOR):
+            self.sectors[first + i // SECTOR] = data[i : i + SECTOR].ljust(SECTOR, b"\0")
+
+    def store(self, clusters, data):
+        """Put `data` in `clusters`, chaining them in the FAT."""
+        assert len(data) <= len(clusters) * self.cluster
+        for i, cluster in enumerate(clusters):
+            while len(self.fat) <= cluster:
+                self.fat.append(0)
+            self.fat[cluster] = clusters[i + 1] if i + 1 < len(clusters) else self.EOC
+            chunk = data[i * self.cluster : (i + 1) * self.cluster]
+            if chunk:
+                self.write(self.data_start + (cluster - 2) * self.cluster_sectors, chunk)
+
+    def boot_sector(self):
+        bpb = struct.pack(
+            "<3s8sHBHBHHBHHHIIIHHIHH12sBBBI11s8s",
+            b"\xEB\x58\x90",
+            b"mkfs.fat",
+            self.sector,
+            self.cluster_sectors,
+            self.RESERVED,
+            self.FATS,
+            0,  # root directory entries
+            0,  # total sectors (16-bit)
+            0xF8,
+            0,  # sectors per FAT (16-bit)
+            32,  # sectors per track
+            64,  # heads
+            0,  # hidden sectors
+            self.total,
+            self.fat_sectors,
+            0 if self.active_fat is None else 0x80 | self.active_fat,  # extended flags
+            0,  # version
+            self.root_cluster,
+            1,  # FSInfo sector
+            6,  # backup boot sector
+            bytes(12),
+            0x80,  # drive number
+            0,
+            0x29,  # extended boot signature
+            0x1234ABCD,  # volume id
+            b"TRANSPARENT",
+            b"FAT32   ",
+        )
+        return (bpb.ljust(510, b"\0") + b"\x55\xAA").ljust(self.sector, b"\0")
+
+    def fsinfo(self):
+        used = sum(1 for e in self.fat[2:] if e)
+        info = bytearray(self.sector)
+        struct.pack_into("<I", info, 0, 0x41615252)
+        struct.pack_into("<III", info, 484, 0x61417272, self.CLUSTERS - used, len(self.fat))
+        struct.pack_into("<I", info, 508, 0xAA550000)
+        return bytes(info)
+
+    def finish(self):
+        self.write(0, self.boot_sector())
+        self.write(1, self.fsinfo())
+        self.write(6, self.boot_sector())
+        self.write(7, self.fsinfo())
+        fat = b"".join(struct.pack("<I", e) for e in self.fat)
+        for i in range(self.FATS):
+            if self.active_fat is not None and i != self.active_fat:
+                continue
+            self.write(self.RESERVED + i * self.fat_sectors, fat)
+        return self.sectors, self.total * self.sector // SECTOR
+
+
+class Fat16Image(Fat32Image):
+    """A FAT12 or FAT16 volume, with the root directory in a region of its
+    own instead of in clusters."""
+
+    def __init__(self, bits, clusters, cluster_sectors, root_entries, media):
+        self.bits = bits
+        self.sector = SECTOR
+        self.cluster_sectors = cluster_sectors
+        self.cluster = SECTOR * cluster_sectors
+        self.media = media
+        self.root_entries = root_entries
+        self.reserved = 1 if bits == 12 else 4
+        self.EOC = (1 << bits) - 1
+        self.fat_sectors = -(-((clusters + 2) * bits // 8 + 1) // SECTOR)
+        self.root_start = self.reserved + self.FATS * self.fat_sectors
+        self.data_start = self.root_start + root_entries * 32 // SECTOR
+        self.total = self.data_start + clusters * cluster_sectors
+        self.sectors = {}
+        self.fat = [self.EOC & ~0xFF | media, self.EOC]
+
+    def boot_sector(self):
+        bpb = struct.pack(
+            "<3s8sHBHBHHBHHHIIBBBI11s8s",
+            b"\xEB\x3C\x90",
+            b"mkfs.fat",
+            SECTOR,
+            self.cluster_sectors,
+            self.reserved,
+            self.FATS,
+            self.root_entries,
+            self.total if self.total < 0x10000 else 0,
+            self.media,
+            self.fat_sectors,
+            32,  # sectors per track
+            64,  # heads
+            0,  # hidden sectors
+            self.total if self.total >= 0x10000 else 0,
+            0x80,  # drive number
+            0,
+            0x29,  # extended boot signature
+            0x1234ABCD,  # volume id
+            b"TRANSPARENT",
+            b"FAT%d   " % self.bits,
+        )
+        return bpb.ljust(510, b"\0") + b"\x55\xAA"
+
+    def fat_bytes(self):
+        if self.bits == 16:
+            return b"".join(struct.pack("<H", e) for e in self.fat)
+        # Two 12-bit entries in three bytes.
+        fat = bytearray(len(self.fat) * 3 // 2 + 2)
+        for n, e in enumerate(self.fat):
+            off = n + n // 2
+            if n % 2:
+                fat[off] |= (e << 4) & 0xF0
+                fat[off + 1] = e >> 4
+            else:
+                fat[off] = e & 0xFF
+                fat[off + 1] |= e >> 8
+        return bytes(fat)
+
+    def finish(self, root):
+        self.write(0, self.boot_sector())
+        self.write(self.root_start, root)
+        for i in range(self.FATS):
+            self.write(self.reserved + i * self.fat_sectors, self.fat_bytes())
+        return self.sectors, self.total
+
+
+def fat16_image(bits):
+    """A small volume with the same kind of files as `fat32_image`, both in
+    the fixed root directory and in a subdirectory."""
+    if bits == 12:
+        # A 1.44M floppy.
+        img = Fat16Image(12, 2847, 1, 224, 0xF0)
+    else:
+        img = Fat16Image(16, 5000, 4, 512, 0xF8)
+
+    readme = lines(b"README", 100)
+    poem = lines(b"POEM", 100)
+    long_name = "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt"
+    long_short = b"THIS_I~1TXT"
+    # Backwards, so every chain link goes down, across odd and even entries.
+    readme_clusters = list(range(2 + -(-len(readme) // img.cluster), 2, -1))
+    dir_cluster = readme_clusters[0] + 1
+    poem_clusters = [dir_cluster + 1 + i for i in range(-(-len(poem) // img.cluster))]
+    long_cluster = poem_clusters[-1] + 1
+
+    test_dir = (
+        short_entry(b".          ", ATTR_DIRECTORY, dir_cluster)
+        + short_entry(b"..         ", ATTR_DIRECTORY, 0)
+        + short_entry(b"POEM    TXT", ATTR_ARCHIVE, poem_clusters[0], len(poem))
+    )
+    root = (
+        short_entry(b"TRANSPARENT", ATTR_VOLUME_ID)
+        + short_entry(b"README  TXT", ATTR_ARCHIVE, readme_clusters[0], len(readme))
+        + lfn_entries(long_name, long_short)
+        + short_entry(long_short, ATTR_ARCHIVE, long_cluster, 6)
+        + short_entry(b"TEST_DIR   ", ATTR_DIRECTORY, dir_cluster)
+    )
+    img.store(readme_clusters, readme)
+    img.store([dir_cluster], test_dir)
+    img.store(poem_clusters, poem)
+    img.store([long_cluster], b"hello\n")
+    return img.finish(root)
+
+
+def fat32_image():
+    """The volume `mkfs.sh` creates, scaled down a little.
+
+    Some chains are fragmented on purpose, and the root directory has a
+    volume label and a deleted entry in it.
+    """
+    img = Fat32Image()
+
+    readme = lines(b"README", 100)
+    poem = lines(b"POEM", 100)
+    long_name = "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt"
+    long_short = b"THIS_I~1TXT"
+
+    test_dir = (
+        short_entry(b".          ", ATTR_DIRECTORY, 12)
+        + short_entry(b"..         ", ATTR_DIRECTORY, 0)
+        + short_entry(b"POEM    TXT", ATTR_ARCHIVE, 13, len(poem))
+    )
+
+    root = (
+        short_entry(b"TRANSPARENT", ATTR_VOLUME_ID)
+        + short_entry(b"README  TXT", ATTR_ARCHIVE, 4, len(readme))
+        + lfn_entries(long_name, long_short)
+        + short_entry(long_short, ATTR_ARCHIVE, 8, len(readme))
+        + short_entry(b"TEST_DIR   ", ATTR_DIRECTORY, 12)
+        + short_entry(b"\xE5ELETED TXT", ATTR_ARCHIVE, 0, 0)
+    )
+    for i in range(1, 24):
+        content = b"hey, my name is file %d\n" % i
+        name = (b"FILE%d" % i).ljust(8) + b"TXT"
+        root += short_entry(name, ATTR_ARCHIVE, 14 + i, len(content))
+        img.store([14 + i], content)
+
+    img.store([2, 3, 38], root)
+    img.store([4, 5, 6, 7], readme)
+    img.store([8, 9, 10, 11], readme)
+    img.store([12], test_dir)
+    img.store([13, 14, 39], poem)
+    return img.finish()
+
+
+def fat32_big_sector_image():
+    """A volume with 4 KiB sectors and 32 KiB clusters, the root directory
+    somewhere other than cluster 2, and only the second FAT in use."""
+    img = Fat32Image(sector=4096, cluster_sectors=8, root_cluster=5, active_fat=1)
+
+    readme = lines(b"README", 100)
+    big = lines(b"BIG", 3000)
+    root = (
+        short_entry(b"TRANSPARENT", ATTR_VOLUME_ID)
+        + short_entry(b"README  TXT", ATTR_ARCHIVE, 6, len(readme))
+        + short_entry(b"BIG     TXT", ATTR_ARCHIVE, 7, len(big))
+    )
+    img.store([5], root)
+    img.store([6], readme)
+    img.store([7, 3], big)
+    return img.finish()
+
+
+def exfat_checksum(data, bits, skip=()):
+    """The rotate-and-add checksum exFAT uses for the boot region, the
+    up-case table (32 bits) and entry sets and name hashes (16 bits)."""
+    s = 0
+    for i, b in enumerate(data):
+        if i not in skip:
+            s = (((s & 1) << (bits - 1)) + (s >> 1) + b) & ((1 << bits) - 1)
+    return s
+
+
+def exfat_upcase(c):
+    u = chr(c).upper()
+    return ord(u) if len(u) == 1 and ord(u) < 0x10000 else c
+
+
+class ExFatImage(Fat32Image):
+    """An exFAT volume: 4 KiB clusters, two FATs and two allocation bitmaps
+    of which only the second ones are in use, and a compressed up-case
+    table."""
+
+    CLUSTER_SHIFT = 3
+    CLUSTERS = 1000
+    FAT_OFFSET = 32
+    EOC = 0xFFFFFFFF
+
+    def __init__(self):
+        self.sector = SECTOR
+        self.cluster_sectors = 1 << self.CLUSTER_SHIFT
+        self.cluster = SECTOR * self.cluster_sectors
+        self.fat_sectors = -(-(self.CLUSTERS + 2) * 4 // SECTOR)
+        self.data_start = self.FAT_OFFSET + self.FATS * self.fat_sectors
+        self.total = self.data_start + self.CLUSTERS * self.cluster_sectors
+        self.sectors = {}
+        self.fat = [0xFFFFFFF8, 0xFFFFFFFF]
+        self.used = set()
+
+    def store(self, clusters, data, chained=True):
+        """Like `Fat32Image.store`, but `chained=False` leaves the FAT alone
+        for a NoFatChain file, whose clusters have to be contiguous."""
+        assert chained or clusters == list(range(clusters[0], clusters[0] + len(clusters)))
+        self.used.update(clusters)
+        if chained:
+            return super().store(clusters, data)
+        self.write(self.data_start + (clusters[0] - 2) * self.cluster_sectors, data)
+
+    def upcase_table(self):
+        """Every character from 0 to 0xff, then the rest of them mapping to
+        themselves."""
+        table = [exfat_upcase(c) for c in range(0x100)] + [0xFFFF, 0x10000 - 0x100]
+        return struct.pack("<%dH" % len(table), *table)
+
+    def name_hash(self, name):
+        up = [exfat_upcase(ord(c)) if ord(c) < 0x100 else ord(c) for c in name]
+        return exfat_checksum(struct.pack("<%dH" % len(up), *up), 16)
+
+    def entry_set(self, name, attr, cluster, size, chained=True, valid=None):
+        """The file, stream extension and file name entries of a file."""
+        stamp = DATE << 16 | TIME
+        file = struct.pack(
+            "<BBHHHIIIBBBBB7s",
+            0x85,
+            0,  # secondary count, below
+            0,  # set checksum, below
+            attr,
+            0,
+            stamp,  # created
+            stamp,  # modified
+            stamp,  # accessed
+            100,  # created, 10ms increments
+            0,  # modified, 10ms increments
+            0,
+            0,
+            0,  # UTC offsets
+            bytes(7),
+        )
+        stream = struct.pack(
+            "<BBBBHHQIIQ",
+            0xC0,
+            0x01 | (0 if chained else 0x02),  # allocation possible, NoFatChain
+            0,
+            len(name),
+            self.name_hash(name),
+            0,
+            size if valid is None else valid,
+            0,
+            cluster,
+            size,
+        )
+        chars = [ord(c) for c in name]
+        chars += [0] * (-len(chars) % 15)
+        names = b"".join(
+            struct.pack("<BB30s", 0xC1, 0, struct.pack("<15H", *chars[i : i + 15]))
+            for i in range(0, len(chars), 15)
+        )
+        entries = bytearray(file + stream + names)
+        entries[1] = len(entries) // 32 - 1
+        struct.pack_into("<H", entries, 2, exfat_checksum(entries, 16, skip=(2, 3)))
+        return bytes(entries)
+
+    def boot_region(self):
+        boot = bytearray(SECTOR)
+        struct.pack_into(
+            "<3s8s53sQQIIIIIIHHBBBBB",
+            boot,
+            0,
+            b"\xEB\x76\x90",
+            b"EXFAT   ",
+            bytes(53),
+            0,  # partition offset
+            self.total,
+            self.FAT_OFFSET,
+            self.fat_sectors,
+            self.data_start,
+            self.CLUSTERS,
+            4,  # root directory cluster
+            0x1234ABCD,  # volume serial number
+            0x0100,  # revision 1.00
+            0x0001,  # volume flags: the second FAT is the active one
+            9,  # bytes per sector shift
+            self.CLUSTER_SHIFT,
+            self.FATS,
+            0x80,  # drive select
+            len(self.used) * 100 // self.CLUSTERS,  # percent in use
+        )
+        boot[510:512] = b"\x55\xAA"
+        region = bytes(boot)
+        for _ in range(8):
+            # Extended boot sectors, with nothing but their signature.
+            region += bytes(SECTOR - 4) + struct.pack("<I", 0xAA550000)
+        region += bytes(2 * SECTOR)  # OEM parameters, reserved
+        checksum = exfat_checksum(region, 32, skip=(106, 107, 112))
+        return region + struct.pack("<I", checksum) * (SECTOR // 4)
+
+    def finish(self, bitmap_cluster):
+        region = self.boot_region()
+        self.write(0, region)
+        self.write(12, region)
+        bitmap = bytearray(-(-self.CLUSTERS // 8))
+        for cluster in self.used:
+            bitmap[(cluster - 2) // 8] |= 1 << ((cluster - 2) % 8)
+        self.write(self.data_start + (bitmap_cluster - 2) * self.cluster_sectors, bytes(bitmap))
+        fat = b"".join(struct.pack("<I", e) for e in self.fat)
+        self.write(self.FAT_OFFSET + self.fat_sectors, fat)
+        return self.sectors, self.total
+
+
+def exfat_image():
+    """The same kind of files as `fat32_image`, plus what exFAT has that FAT
+    doesn't: NoFatChain files and directories, names that need the up-case
+    table, and a file with less valid data than its size."""
+    img = ExFatImage()
+
+    readme = lines(b"README", 1000)
+    poem = lines(b"POEM", 1000)
+    upcase = img.upcase_table()
+    # The bitmaps and the up-case table always have a FAT chain. The first
+    # bitmap goes with the first FAT, and is left empty.
+    img.store([2], b"")
+    img.store([19], b"")
+    img.store([3], upcase)
+    long_name = "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt"
+
+    test_dir = img.entry_set("POEM.TXT", ATTR_ARCHIVE, 11, len(poem), chained=False)
+    root = (
+        # The allocation bitmaps, then the up-case table.
+        struct.pack("<BB18sIQ", 0x81, 0, bytes(18), 2, -(-img.CLUSTERS // 8))
+        + struct.pack("<BB18sIQ", 0x81, 1, bytes(18), 19, -(-img.CLUSTERS // 8))
+        + struct.pack(
+            "<BBBBI12sIQ", 0x82, 0, 0, 0, exfat_checksum(upcase, 32), bytes(12), 3, len(upcase)
+        )
+        + struct.pack("<BB22s8s", 0x83, 11, "TRANSPARENT".encode("utf-16-le"), bytes(8))
+        + img.entry_set("README.TXT", ATTR_ARCHIVE, 8, len(readme))
+        + img.entry_set(long_name, ATTR_ARCHIVE, 15, 6)
+        + img.entry_set("TEST_DIR", ATTR_DIRECTORY, 10, img.cluster, chained=False)
+    )
+    # A deleted file: the in-use bit of every entry is clear.
+    deleted = img.entry_set("DELETED.TXT", ATTR_ARCHIVE, 0, 0)
+    root += bytes(b & 0x7F if i % 32 == 0 else b for i, b in enumerate(deleted))
+    root += (
+        img.entry_set("\u00dcn\u00efc\u00f6d\u00e9.txt", ATTR_ARCHIVE, 16, 10, chained=False)
+        + img.entry_set("SPARSE.BIN", ATTR_ARCHIVE, 17, 2 * img.cluster, valid=100)
+        + img.entry_set("EMPTY.TXT", ATTR_ARCHIVE, 0, 0)
+    )
+    for i in range(1, 41):
+        content = b"hey, my name is file %d\n" % i
+        root += img.entry_set("FILE%d.TXT" % i, ATTR_ARCHIVE, 19 + i, len(content), chained=False)
+        img.store([19 + i], content, chained=False)
+
+    img.store([4, 9], root)
+    # Backwards, so the chain can't be guessed.
+    img.store([8, 7, 6, 5], readme)
+    img.store([10], test_dir, chained=False)
+    img.store([11, 12, 13, 14], poem, chained=False)
+    img.store([15], b"hello\n")
+    img.store([16], "\u00fcnic\u00f6de\n".encode(), chained=False)
+    # Only the first 100 bytes are valid, what's on disk after that has to
+    # read as zeros.
+    img.store([17, 18], b"x" * 100 + b"\xAA" * (2 * img.cluster - 100))
+    return img.finish(19)
+
+
+def write_sparse(path, sectors, total):
+    with open(path, "wb") as f:
+        f.write(struct.pack("<II", SECTOR, total))
+        for sector in sorted(sectors):
+            data = sectors[sector]
+            if any(data):
+                f.write(struct.pack("<I", sector))
+                f.write(data)
+
+
+def main():
+    out = sys.argv[1] if len(sys.argv) > 1 else "testdata"
+    os.makedirs(out, exist_ok=True)
+    write_sparse(os.path.join(out, "fat32.img.sparse"), *fat32_image())
+    write_sparse(os.path.join(out, "fat32-4k.img.sparse"), *fat32_big_sector_image())
+    write_sparse(os.path.join(out, "fat16.img.sparse"), *fat16_image(16))
+    write_sparse(os.path.join(out, "fat12.img.sparse"), *fat16_image(12))
+    write_sparse(os.path.join(out, "exfat.img.sparse"), *exfat_image())
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,248 @@
+//! AES (FIPS-197), in software.
+//!
+//! NOTE: This is the textbook, table driven implementation, so it's
+//! neither fast nor constant time. Good enough to keep disk images
+//! confidential at rest, but don't use it where an attacker can time us.
+
+pub const BLOCK_SIZE: usize = 16;
+
+const MAX_ROUNDS: usize = 14;
+
+pub struct Aes {
+    round_keys: [[u8; BLOCK_SIZE]; MAX_ROUNDS + 1],
+    rounds: usize,
+}
+
+impl Aes {
+    /// AES-128, AES-192 or AES-256, depending on the length of `key`.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+
+        let mut words = [[0u8; 4]; 4 * (MAX_ROUNDS + 1)];
+        for (word, chunk) in words.iter_mut().zip(key.chunks(4)) {
+            word.copy_from_slice(chunk);
+        }
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut temp = words[i - 1];
+            if i % nk == 0 {
+                temp.rotate_left(1);
+                temp = temp.map(|b| SBOX[b as usize]);
+                temp[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                temp = temp.map(|b| SBOX[b as usize]);
+            }
+            let prev = words[i - nk];
+            for (w, (p, t)) in words[i].iter_mut().zip(prev.iter().zip(temp)) {
+                *w = p ^ t;
+            }
+        }
+
+        let mut round_keys = [[0; BLOCK_SIZE]; MAX_ROUNDS + 1];
+        for (round_key, words) in round_keys.iter_mut().zip(words.chunks(4)) {
+            for (i, word) in words.iter().enumerate() {
+                round_key[i * 4..i * 4 + 4].copy_from_slice(word);
+            }
+        }
+        Some(Aes { round_keys, rounds })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1..self.rounds {
+            sub_bytes(block, &SBOX);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &self.round_keys[round]);
+        }
+        sub_bytes(block, &SBOX);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[self.rounds]);
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[self.rounds]);
+        for round in (1..self.rounds).rev() {
+            inv_shift_rows(block);
+            sub_bytes(block, &INV_SBOX);
+            add_round_key(block, &self.round_keys[round]);
+            inv_mix_columns(block);
+        }
+        inv_shift_rows(block);
+        sub_bytes(block, &INV_SBOX);
+        add_round_key(block, &self.round_keys[0]);
+    }
+}
+
+impl Drop for Aes {
+    fn drop(&mut self) {
+        // Don't leave the key lying around in freed memory.
+        for round_key in self.round_keys.iter_mut() {
+            for b in round_key.iter_mut() {
+                unsafe { core::ptr::write_volatile(b, 0) };
+            }
+        }
+    }
+}
+
+// The state is stored column by column, like the input:
+// byte `c * 4 + r` is row `r` of column `c`.
+
+fn add_round_key(block: &mut [u8; BLOCK_SIZE], round_key: &[u8; BLOCK_SIZE]) {
+    for (b, k) in block.iter_mut().zip(round_key) {
+        *b ^= k;
+    }
+}
+
+fn sub_bytes(block: &mut [u8; BLOCK_SIZE], sbox: &[u8; 256]) {
+    for b in block.iter_mut() {
+        *b = sbox[*b as usize];
+    }
+}
+
+/// Rotate row `r` left by `r`.
+fn shift_rows(block: &mut [u8; BLOCK_SIZE]) {
+    let old = *block;
+    for c in 0..4 {
+        for r in 1..4 {
+            block[c * 4 + r] = old[((c + r) % 4) * 4 + r];
+        }
+    }
+}
+
+fn inv_shift_rows(block: &mut [u8; BLOCK_SIZE]) {
+    let old = *block;
+    for c in 0..4 {
+        for r in 1..4 {
+            block[((c + r) % 4) * 4 + r] = old[c * 4 + r];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; BLOCK_SIZE]) {
+    for col in block.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        for r in 0..4 {
+            col[r] = gmul(a[r], 2) ^ gmul(a[(r + 1) % 4], 3) ^ a[(r + 2) % 4] ^ a[(r + 3) % 4];
+        }
+    }
+}
+
+fn inv_mix_columns(block: &mut [u8; BLOCK_SIZE]) {
+    for col in block.chunks_mut(4) {
+        let a = [col[0], col[1], col[2], col[3]];
+        for r in 0..4 {
+            col[r] = gmul(a[r], 14)
+                ^ gmul(a[(r + 1) % 4], 11)
+                ^ gmul(a[(r + 2) % 4], 13)
+                ^ gmul(a[(r + 3) % 4], 9);
+        }
+    }
+}
+
+/// Multiply by x in GF(2^8).
+const fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// Multiply in GF(2^8).
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    p
+}
+
+static SBOX: [u8; 256] = make_sbox();
+static INV_SBOX: [u8; 256] = invert(&make_sbox());
+
+/// Walk the multiplicative group with generator 3, so that `p` and `q`
+/// stay each other's inverse, then apply the affine transformation.
+const fn make_sbox() -> [u8; 256] {
+    let mut sbox = [0; 256];
+    let mut p: u8 = 1;
+    let mut q: u8 = 1;
+    loop {
+        // p * 3
+        p ^= xtime(p);
+        // q / 3
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        sbox[p as usize] =
+            q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4) ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    // 0 has no inverse.
+    sbox[0] = 0x63;
+    sbox
+}
+
+const fn invert(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut inv = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        inv[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    inv
+}
+
+#[cfg(test)]
+fn check_known_answer(key: &[u8], expected: [u8; BLOCK_SIZE]) {
+    // FIPS-197, appendix C.
+    let plain = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    let aes = Aes::new(key).unwrap();
+    let mut block = plain;
+    aes.encrypt_block(&mut block);
+    assert_eq!(block, expected);
+    aes.decrypt_block(&mut block);
+    assert_eq!(block, plain);
+}
+
+#[test_case]
+fn test_aes_known_answers() {
+    let mut key = [0; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    check_known_answer(
+        &key[..16],
+        [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+            0xc5, 0x5a,
+        ],
+    );
+    check_known_answer(
+        &key[..24],
+        [
+            0xdd, 0xa9, 0x7c, 0xa4, 0x86, 0x4c, 0xdf, 0xe0, 0x6e, 0xaf, 0x70, 0xa0, 0xec, 0x0d,
+            0x71, 0x91,
+        ],
+    );
+    check_known_answer(
+        &key,
+        [
+            0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49,
+            0x60, 0x89,
+        ],
+    );
+}
@@ -0,0 +1,335 @@
+//! A buffer cache sitting on top of a block device.
+//!
+//! Cached blocks are reference counted and handed out as `BlockRef`s, whose
+//! data is reached through read/write guards, so nothing gets copied.
+//! Modified blocks are written back when evicted, or by `flush`/`sync`.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::warn;
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::block::{BlockDevice, BlockError};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+
+    /// Dirty blocks written back to the device.
+    pub writebacks: u64,
+}
+
+struct CachedBlock {
+    blk_id: usize,
+    data: RwLock<Vec<u8>>,
+
+    /// Kept out of the lock, so that writing a block back only needs to
+    /// read it.
+    dirty: AtomicBool,
+}
+
+/// A handle to a cached block.
+///
+/// The block stays in the cache for as long as there's a `BlockRef` to it.
+#[derive(Clone)]
+pub struct BlockRef(Arc<CachedBlock>);
+
+impl BlockRef {
+    /// Borrow the block's data.
+    pub fn read(&self) -> BlockReadGuard<'_> {
+        BlockReadGuard(self.0.data.read())
+    }
+
+    /// Borrow the block's data mutably, marking the block dirty.
+    pub fn write(&self) -> BlockWriteGuard<'_> {
+        let data = self.0.data.write();
+        self.0.dirty.store(true, Ordering::Relaxed);
+        BlockWriteGuard(data)
+    }
+}
+
+pub struct BlockReadGuard<'a>(RwLockReadGuard<'a, Vec<u8>>);
+
+impl Deref for BlockReadGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+pub struct BlockWriteGuard<'a>(RwLockWriteGuard<'a, Vec<u8>>);
+
+impl Deref for BlockWriteGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for BlockWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Caches up to `capacity` blocks of `dev`, evicting the least recently
+/// used one when full.
+///
+/// Blocks that are still referenced are never evicted, so the cache can
+/// grow past `capacity` if everything is in use.
+pub struct BufferCache<B>
+where
+    B: BlockDevice,
+{
+    dev: B,
+    capacity: usize,
+
+    /// Cached blocks, along with when they were last used.
+    blocks: BTreeMap<usize, (Arc<CachedBlock>, u64)>,
+
+    /// Cached blocks by when they were last used, oldest first.
+    lru: BTreeMap<u64, usize>,
+    clock: u64,
+
+    stats: CacheStats,
+}
+
+impl<B> BufferCache<B>
+where
+    B: BlockDevice,
+{
+    pub fn new(dev: B, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        BufferCache {
+            dev,
+            capacity,
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Get block `blk_id`, reading it from the device if it isn't cached.
+    pub fn get(&mut self, blk_id: usize) -> Result<BlockRef, BlockError> {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some((block, last_used)) = self.blocks.get_mut(&blk_id) {
+            self.stats.hits += 1;
+            self.lru.remove(last_used);
+            self.lru.insert(now, blk_id);
+            *last_used = now;
+            return Ok(BlockRef(block.clone()));
+        }
+
+        self.stats.misses += 1;
+        let mut buf = vec![0; self.dev.block_size()];
+        self.dev.read(blk_id, &mut buf)?;
+
+        if self.blocks.len() >= self.capacity {
+            self.evict()?;
+        }
+
+        let block = Arc::new(CachedBlock {
+            blk_id,
+            data: RwLock::new(buf),
+            dirty: AtomicBool::new(false),
+        });
+        self.blocks.insert(blk_id, (block.clone(), now));
+        self.lru.insert(now, blk_id);
+        Ok(BlockRef(block))
+    }
+
+    /// Write every dirty block back to the device.
+    ///
+    /// Blocks someone is in the middle of writing to, holding on to a
+    /// `BlockWriteGuard`, are skipped and stay dirty. Waiting for the guard
+    /// would never end, since nothing else runs until we're done. The rest
+    /// are still written back, and then `BlockError::Busy` says that some
+    /// weren't.
+    pub fn flush(&mut self) -> Result<(), BlockError> {
+        let mut skipped = false;
+        for (block, _) in self.blocks.values() {
+            skipped |= !Self::write_back(&mut self.dev, &mut self.stats, block)?;
+        }
+        match skipped {
+            true => Err(BlockError::Busy),
+            false => Ok(()),
+        }
+    }
+
+    /// Write every dirty block back, and make sure it reached the disk.
+    pub fn sync(&mut self) -> Result<(), BlockError> {
+        self.flush()?;
+        self.dev.flush()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn device(&self) -> &B {
+        &self.dev
+    }
+
+    /// Evict the least recently used block nobody holds a reference to.
+    ///
+    /// If it can't be written back, it stays in the cache, still dirty, and
+    /// the error is returned.
+    fn evict(&mut self) -> Result<(), BlockError> {
+        let victim = self
+            .lru
+            .values()
+            .copied()
+            .find(|blk_id| Arc::strong_count(&self.blocks[blk_id].0) == 1);
+        let blk_id = match victim {
+            Some(blk_id) => blk_id,
+            None => return Ok(()),
+        };
+
+        // Nobody holds a reference, so nobody is writing to it either.
+        let (block, _) = &self.blocks[&blk_id];
+        if let Err(e) = Self::write_back(&mut self.dev, &mut self.stats, block) {
+            warn!("failed to write back block {}: {:?}", blk_id, e);
+            return Err(e);
+        }
+        let (_, last_used) = self.blocks.remove(&blk_id).unwrap();
+        self.lru.remove(&last_used);
+        self.stats.evictions += 1;
+        Ok(())
+    }
+
+    /// Write `block` back if it's dirty and nobody is writing to it.
+    /// Returns whether it's clean now.
+    fn write_back(
+        dev: &mut B,
+        stats: &mut CacheStats,
+        block: &CachedBlock,
+    ) -> Result<bool, BlockError> {
+        if !block.dirty.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        let data = match block.data.try_read() {
+            Some(data) => data,
+            None => return Ok(false),
+        };
+        dev.write(block.blk_id, &data)?;
+        block.dirty.store(false, Ordering::Relaxed);
+        stats.writebacks += 1;
+        Ok(true)
+    }
+}
+
+impl<B> Drop for BufferCache<B>
+where
+    B: BlockDevice,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to write back the buffer cache: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::block::MemDevice;
+
+#[test_case]
+fn test_bcache_evicts_least_recently_used() {
+    let mut dev = MemDevice::new(4);
+    for (i, block) in dev.blocks.iter_mut().enumerate() {
+        block.fill(i as u8);
+    }
+    let mut cache = BufferCache::new(dev, 2);
+    assert_eq!(cache.get(0).unwrap().read()[0], 0);
+    assert_eq!(cache.get(1).unwrap().read()[0], 1);
+    cache.get(0).unwrap();
+    cache.get(2).unwrap(); // evicts 1
+    cache.get(0).unwrap();
+    cache.get(1).unwrap(); // evicts 2
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, 4);
+    assert_eq!(stats.evictions, 2);
+
+    // Referenced blocks stay.
+    let pinned = cache.get(3).unwrap(); // evicts 0
+    cache.get(0).unwrap(); // evicts 1, not 3
+    assert_eq!(pinned.read()[0], 3);
+    assert_eq!(cache.get(3).unwrap().read()[0], 3);
+    assert_eq!(cache.stats().hits, 3);
+}
+
+#[test_case]
+fn test_bcache_writes_back_dirty_blocks() {
+    let dev = MemDevice::new(4);
+    let mut cache = BufferCache::new(dev, 2);
+    cache.get(0).unwrap().write()[0] = 0xaa;
+    cache.get(1).unwrap();
+    assert_eq!(cache.device().writes, 0);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.device().writes, 1);
+    assert_eq!(cache.device().blocks[0][0], 0xaa);
+
+    // Clean blocks aren't written again, dirty ones are on eviction.
+    cache.flush().unwrap();
+    cache.get(1).unwrap().write()[1] = 0xbb;
+    cache.get(2).unwrap(); // evicts 0
+    cache.get(3).unwrap(); // evicts 1
+    assert_eq!(cache.device().writes, 2);
+    assert_eq!(cache.device().blocks[1][1], 0xbb);
+    assert_eq!(cache.stats().writebacks, 2);
+}
+
+#[test_case]
+fn test_bcache_flush_skips_blocks_being_written() {
+    let dev = MemDevice::new(4);
+    let mut cache = BufferCache::new(dev, 2);
+    let block0 = cache.get(0).unwrap();
+    let block1 = cache.get(1).unwrap();
+    block0.write()[0] = 0xaa;
+    let mut data = block1.write();
+    data[0] = 0xbb;
+
+    // Reading doesn't get in the way, writing does.
+    let read = block0.read();
+    assert_eq!(cache.flush(), Err(BlockError::Busy));
+    assert_eq!(read[0], 0xaa);
+    assert_eq!(cache.device().blocks[0][0], 0xaa);
+    assert_eq!(cache.device().blocks[1][0], 0);
+
+    drop(data);
+    cache.flush().unwrap();
+    assert_eq!(cache.device().blocks[1][0], 0xbb);
+    assert_eq!(cache.stats().writebacks, 2);
+}
+
+#[test_case]
+fn test_bcache_keeps_blocks_that_fail_to_write_back() {
+    let dev = MemDevice::new(4);
+    let mut cache = BufferCache::new(dev, 1);
+    cache.get(0).unwrap().write()[0] = 0xaa;
+
+    // Evicting block 0 to make room fails, so it stays dirty in the cache.
+    cache.dev.fail_writes = true;
+    assert_eq!(cache.get(1).err(), Some(BlockError::Io));
+    assert_eq!(cache.flush(), Err(BlockError::Io));
+    assert_eq!(cache.stats().evictions, 0);
+    assert_eq!(cache.get(0).unwrap().read()[0], 0xaa);
+
+    cache.dev.fail_writes = false;
+    cache.get(1).unwrap(); // evicts 0
+    assert_eq!(cache.device().blocks[0][0], 0xaa);
+    assert_eq!(cache.stats().evictions, 1);
+}
@@ -0,0 +1,197 @@
+//! The block device registry.
+//!
+//! Probing registers every block device it finds under a name (`vda`,
+//! `vdb`, ...), and drivers claim the ones they want through a
+//! `BlockHandle`. A device can only be claimed once at a time, so that,
+//! say, two filesystem drivers never run on the same disk. Dropping the
+//! handle gives the device back.
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::block::{BlockDevice, BlockError, BlockStats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// There's no device with that name.
+    NotFound,
+
+    /// Someone else claimed the device already.
+    Busy,
+}
+
+/// What we know about a registered device, claimed or not.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+
+    /// The device tree node the device was probed from.
+    pub node: String,
+
+    pub block_size: usize,
+    pub block_count: usize,
+    pub claimed: bool,
+}
+
+struct Registered {
+    info: DeviceInfo,
+
+    /// `None` while claimed.
+    dev: Option<Box<dyn BlockDevice + Send>>,
+}
+
+static DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// Register `dev`, probed from the device tree node `node`, naming it
+/// `prefix` followed by the next free letter.
+///
+/// Returns the name the device got.
+pub fn register(prefix: &str, node: &str, dev: Box<dyn BlockDevice + Send>) -> String {
+    let nth = DEVICES
+        .lock()
+        .iter()
+        .filter(|d| {
+            d.info.name.strip_prefix(prefix).map_or(false, |suffix| {
+                suffix.bytes().all(|c| c.is_ascii_lowercase())
+            })
+        })
+        .count();
+    let name = format!("{}{}", prefix, disk_suffix(nth));
+    register_as(name.clone(), node, dev);
+    name
+}
+
+/// Register `dev` under the given name.
+pub fn register_as(name: String, node: &str, dev: Box<dyn BlockDevice + Send>) {
+    let mut devices = DEVICES.lock();
+    assert!(
+        devices.iter().all(|d| d.info.name != name),
+        "{} registered twice",
+        name
+    );
+    devices.push(Registered {
+        info: DeviceInfo {
+            name,
+            node: node.to_string(),
+            block_size: dev.block_size(),
+            block_count: dev.block_count(),
+            claimed: false,
+        },
+        dev: Some(dev),
+    });
+}
+
+/// Every registered device, in the order they were registered.
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES.lock().iter().map(|d| d.info.clone()).collect()
+}
+
+/// Get exclusive access to the device called `name`.
+pub fn claim(name: &str) -> Result<BlockHandle, RegistryError> {
+    let mut devices = DEVICES.lock();
+    let registered = devices
+        .iter_mut()
+        .find(|d| d.info.name == name)
+        .ok_or(RegistryError::NotFound)?;
+    let dev = registered.dev.take().ok_or(RegistryError::Busy)?;
+    registered.info.claimed = true;
+    Ok(BlockHandle {
+        name: registered.info.name.clone(),
+        dev: Some(dev),
+    })
+}
+
+/// `a`, `b`, ..., `z`, `aa`, `ab`, ..., like Linux names its disks.
+fn disk_suffix(mut n: usize) -> String {
+    let mut suffix = Vec::new();
+    loop {
+        suffix.push(b'a' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    suffix.reverse();
+    String::from_utf8(suffix).unwrap()
+}
+
+/// Exclusive access to a registered block device.
+pub struct BlockHandle {
+    name: String,
+
+    /// Only `None` while being dropped.
+    dev: Option<Box<dyn BlockDevice + Send>>,
+}
+
+impl BlockHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dev(&self) -> &(dyn BlockDevice + Send) {
+        self.dev.as_deref().unwrap()
+    }
+
+    fn dev_mut(&mut self) -> &mut (dyn BlockDevice + Send) {
+        self.dev.as_deref_mut().unwrap()
+    }
+}
+
+impl BlockDevice for BlockHandle {
+    fn block_size(&self) -> usize {
+        self.dev().block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.dev().block_count()
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.dev_mut().read_blocks(blk_id, buf)
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.dev_mut().write_blocks(blk_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev_mut().flush()
+    }
+
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        self.dev_mut().discard(blk_id, count)
+    }
+
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        self.dev_mut().write_zeroes(blk_id, count)
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        self.dev().stats()
+    }
+}
+
+impl Drop for BlockHandle {
+    fn drop(&mut self) {
+        let mut devices = DEVICES.lock();
+        if let Some(registered) = devices.iter_mut().find(|d| d.info.name == self.name) {
+            registered.dev = self.dev.take();
+            registered.info.claimed = false;
+        }
+    }
+}
+
+#[test_case]
+fn test_disk_suffix() {
+    assert_eq!(disk_suffix(0), "a");
+    assert_eq!(disk_suffix(25), "z");
+    assert_eq!(disk_suffix(26), "aa");
+    assert_eq!(disk_suffix(27), "ab");
+    assert_eq!(disk_suffix(26 + 26 * 26), "aaa");
+}
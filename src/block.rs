@@ -1,48 +1,1134 @@
-use core::ops::Deref;
-
-use virtio_drivers::VirtIOBlk;
-
-// NOTE: Obivate this static variable, it's desired
-// for device probing module to put every probed
-// devices in a `Vec` or something and hand it to
-// the driver layer.
-//
-// My idea is that one driver holds exclusive access
-// to a device, for example, there can't be two filesystem
-// driver running simultaneously on the same device?
-//
-// Could be wrong.
-pub static mut BLK: Option<VirtioBlock> = None;
-
-// TODO: Implement a copyless BlockDevice.
-// Because we could repetitively read the same block, which can
-// result in wasted I/O or extra copies even with buffering.
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use log::info;
+use riscv::register::time;
+use spin::Mutex;
+use virtio_drivers::{BlkRange, BlkReq, BlkResp, Error as VirtioError, RespStatus, VirtIOBlk};
+
+use crate::{
+    addr::VirtAddr,
+    futex::{self, FutexError},
+    timer,
+    trap::without_interrupts,
+};
+
+/// Every virtio-blk device along with its PLIC interrupt source,
+/// so that `handle_interrupt` can find the device that raised it.
+static VIRTIO_BLKS: Mutex<Vec<(u32, Arc<Mutex<VirtioBlockState>>)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The device failed to carry out the request.
+    Io,
+
+    /// The request touches blocks past the end of the device.
+    OutOfRange,
+
+    /// The device can't be written to.
+    ReadOnly,
+
+    /// The device doesn't support the operation.
+    Unsupported,
+
+    /// The data read back doesn't match its checksum.
+    Checksum,
+
+    /// A cached block couldn't be written back because someone is in the
+    /// middle of writing to it. It's still dirty, try again later.
+    Busy,
+
+    /// There's no room left to write the data, like in a full overlay.
+    NoSpace,
+}
+
+// NOTE: Reading the same block over and over is what `bcache::BufferCache`
+// is for, put one on top instead of caching in here.
 pub trait BlockDevice {
-    const BSIZE: usize = 512;
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> usize;
+
+    /// Read contiguous blocks starting at `blk_id` into `buf`, whose length
+    /// is a multiple of the block size.
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf`, whose length is a multiple of the block size, to
+    /// contiguous blocks starting at `blk_id`.
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError>;
 
     /// Read a block into `buf`
-    fn read(&mut self, blk_id: usize, buf: &mut [u8]) -> Option<usize>;
+    fn read(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        debug_assert_eq!(buf.len(), self.block_size());
+        self.read_blocks(blk_id, buf)
+    }
 
     /// Write `buf` to a block
-    fn write(&mut self, blk_id: usize, buf: &[u8]) -> Option<usize>;
+    fn write(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        debug_assert_eq!(buf.len(), self.block_size());
+        self.write_blocks(blk_id, buf)
+    }
+
+    /// Make sure everything written so far reached stable storage.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Tell the device `count` blocks starting at `blk_id` aren't used
+    /// anymore, so their contents can be dropped.
+    fn discard(&mut self, _blk_id: usize, _count: usize) -> Result<(), BlockError> {
+        Err(BlockError::Unsupported)
+    }
+
+    /// Zero `count` blocks starting at `blk_id`.
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        zero_fill(self, blk_id, count)
+    }
+
+    /// I/O statistics, for devices that keep them.
+    fn stats(&self) -> Option<BlockStats> {
+        None
+    }
+}
+
+/// So something can use a device without taking it, like a volume that's
+/// mounted again afterwards.
+impl<B> BlockDevice for &mut B
+where
+    B: BlockDevice + ?Sized,
+{
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_blocks(blk_id, buf)
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        (**self).write_blocks(blk_id, buf)
+    }
+
+    fn read(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read(blk_id, buf)
+    }
+
+    fn write(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        (**self).write(blk_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        (**self).flush()
+    }
+
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        (**self).discard(blk_id, count)
+    }
+
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        (**self).write_zeroes(blk_id, count)
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        (**self).stats()
+    }
+}
+
+/// Check that `len` bytes starting at block `blk_id` are whole blocks
+/// within `dev`.
+pub fn check_range<B>(dev: &B, blk_id: usize, len: usize) -> Result<(), BlockError>
+where
+    B: BlockDevice + ?Sized,
+{
+    let block_size = dev.block_size();
+    if len % block_size != 0 {
+        return Err(BlockError::Unsupported);
+    }
+    match blk_id.checked_add(len / block_size) {
+        Some(end) if end <= dev.block_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Zero blocks by writing zeroes to them, for devices that can't do better.
+pub fn zero_fill<B>(dev: &mut B, blk_id: usize, count: usize) -> Result<(), BlockError>
+where
+    B: BlockDevice + ?Sized,
+{
+    const CHUNK: usize = 32;
+    let block_size = dev.block_size();
+    check_range(dev, blk_id, count * block_size)?;
+    let zeroes = vec![0; count.min(CHUNK) * block_size];
+    let mut done = 0;
+    while done < count {
+        let n = (count - done).min(CHUNK);
+        dev.write_blocks(blk_id + done, &zeroes[..n * block_size])?;
+        done += n;
+    }
+    Ok(())
+}
+
+/// Size of a virtio-blk sector, the unit of `blk_id`s.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Don't merge requests into device requests larger than this.
+const MAX_MERGED_BYTES: usize = 64 * 1024;
+
+/// Descriptors used by a request besides its data: header and status.
+const DESCS_PER_HEADER: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+}
+
+/// A request to read into, or write from, `buf`, starting at block `blk_id`.
+///
+/// `buf` is owned by the request, so that it can't be touched while the
+/// device is using it. It's handed back on completion.
+#[derive(Debug)]
+pub struct BlockRequest {
+    pub op: BlockOp,
+    pub blk_id: usize,
+
+    /// A non-zero multiple of `SECTOR_SIZE` bytes.
+    pub buf: Vec<u8>,
+}
+
+impl BlockRequest {
+    pub fn read(blk_id: usize, blocks: usize) -> Self {
+        BlockRequest {
+            op: BlockOp::Read,
+            blk_id,
+            buf: vec![0; blocks * SECTOR_SIZE],
+        }
+    }
+
+    pub fn write(blk_id: usize, buf: Vec<u8>) -> Self {
+        BlockRequest {
+            op: BlockOp::Write,
+            blk_id,
+            buf,
+        }
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.buf.len() / SECTOR_SIZE
+    }
+
+    /// The block right after the last one this request touches.
+    pub fn end(&self) -> usize {
+        self.blk_id + self.blocks()
+    }
+}
+
+/// Identifies a request submitted to a `VirtioBlock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+pub type RequestResult = Result<BlockRequest, BlockError>;
+
+/// A request waiting in an I/O scheduler.
+#[derive(Debug)]
+pub struct QueuedRequest {
+    pub id: RequestId,
+    pub req: BlockRequest,
+
+    /// When the request was submitted, in `time` CSR ticks.
+    pub submitted: u64,
+}
+
+/// Decides in which order queued requests go to the device.
+pub trait IoScheduler: Send {
+    fn name(&self) -> &'static str;
+
+    fn add(&mut self, rq: QueuedRequest);
+
+    /// Take the request to dispatch next.
+    fn next(&mut self) -> Option<QueuedRequest>;
+
+    /// Take a queued `op` request starting right at `blk_id` and no longer
+    /// than `max_bytes`, to be merged at the back of the request being
+    /// dispatched.
+    fn take_adjacent(
+        &mut self,
+        op: BlockOp,
+        blk_id: usize,
+        max_bytes: usize,
+    ) -> Option<QueuedRequest>;
+
+    fn is_empty(&self) -> bool;
+}
+
+/// Requests sorted by block, as kept by the sorting schedulers.
+type SortedRequests = BTreeMap<(usize, RequestId), QueuedRequest>;
+
+fn take_sorted(
+    sorted: &mut SortedRequests,
+    op: BlockOp,
+    blk_id: usize,
+    max_bytes: usize,
+) -> Option<QueuedRequest> {
+    let key = *sorted
+        .range((blk_id, RequestId(0))..=(blk_id, RequestId(u64::MAX)))
+        .find(|(_, rq)| rq.req.op == op && rq.req.buf.len() <= max_bytes)?
+        .0;
+    sorted.remove(&key)
+}
+
+/// First come, first served. Only merges with the request right behind.
+#[derive(Default)]
+pub struct Noop {
+    queue: VecDeque<QueuedRequest>,
+}
+
+impl IoScheduler for Noop {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    fn add(&mut self, rq: QueuedRequest) {
+        self.queue.push_back(rq);
+    }
+
+    fn next(&mut self) -> Option<QueuedRequest> {
+        self.queue.pop_front()
+    }
+
+    fn take_adjacent(
+        &mut self,
+        op: BlockOp,
+        blk_id: usize,
+        max_bytes: usize,
+    ) -> Option<QueuedRequest> {
+        let front = self.queue.front()?;
+        if front.req.op == op && front.req.blk_id == blk_id && front.req.buf.len() <= max_bytes {
+            self.queue.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// SCAN: sweep across the disk in one direction, serving requests on the
+/// way, then turn around.
+#[derive(Default)]
+pub struct Elevator {
+    queue: SortedRequests,
+
+    /// Where the last dispatched request ended.
+    head: usize,
+    descending: bool,
+}
+
+impl IoScheduler for Elevator {
+    fn name(&self) -> &'static str {
+        "elevator"
+    }
+
+    fn add(&mut self, rq: QueuedRequest) {
+        self.queue.insert((rq.req.blk_id, rq.id), rq);
+    }
+
+    fn next(&mut self) -> Option<QueuedRequest> {
+        let up = (self.head, RequestId(0))..;
+        let down = ..(self.head, RequestId(0));
+        let key = if self.descending {
+            self.queue.range(down).next_back().or_else(|| {
+                self.descending = false;
+                self.queue.range(up).next()
+            })
+        } else {
+            self.queue.range(up).next().or_else(|| {
+                self.descending = true;
+                self.queue.range(down).next_back()
+            })
+        }
+        .map(|(key, _)| *key)?;
+
+        let rq = self.queue.remove(&key)?;
+        self.head = rq.req.end();
+        Some(rq)
+    }
+
+    fn take_adjacent(
+        &mut self,
+        op: BlockOp,
+        blk_id: usize,
+        max_bytes: usize,
+    ) -> Option<QueuedRequest> {
+        let rq = take_sorted(&mut self.queue, op, blk_id, max_bytes)?;
+        self.head = rq.req.end();
+        Some(rq)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Like Linux's deadline scheduler: serve requests in ascending block order
+/// (wrapping around at the end), but serve a request first once it has
+/// waited past its deadline. Reads are preferred over writes, as someone
+/// is usually waiting for them, but writes don't get starved forever.
+#[derive(Default)]
+pub struct Deadline {
+    /// Indexed by `Deadline::dir`.
+    sorted: [SortedRequests; 2],
+
+    /// Keys and deadlines in submission order, indexed by `Deadline::dir`.
+    /// Entries of requests that were dispatched by block order are skipped
+    /// lazily.
+    fifo: [VecDeque<((usize, RequestId), u64)>; 2],
+
+    head: usize,
+
+    /// Reads dispatched while there were writes waiting.
+    writes_starved: usize,
+}
+
+impl Deadline {
+    const READ_EXPIRE: Duration = Duration::from_millis(500);
+    const WRITE_EXPIRE: Duration = Duration::from_secs(5);
+
+    /// Dispatch writes after this many reads, even if there are more reads.
+    const WRITES_STARVED: usize = 2;
+
+    fn dir(op: BlockOp) -> usize {
+        match op {
+            BlockOp::Read => 0,
+            BlockOp::Write => 1,
+        }
+    }
+
+    fn next_of(&mut self, op: BlockOp) -> Option<QueuedRequest> {
+        let dir = Deadline::dir(op);
+        let (sorted, fifo) = (&mut self.sorted[dir], &mut self.fifo[dir]);
+
+        while let Some((key, _)) = fifo.front() {
+            if sorted.contains_key(key) {
+                break;
+            }
+            fifo.pop_front();
+        }
+
+        let expired = match fifo.front() {
+            Some((key, deadline)) if *deadline <= time::read64() => Some(*key),
+            _ => None,
+        };
+        let key = expired.or_else(|| {
+            sorted
+                .range((self.head, RequestId(0))..)
+                .next()
+                .or_else(|| sorted.iter().next())
+                .map(|(key, _)| *key)
+        })?;
+
+        let rq = sorted.remove(&key)?;
+        self.head = rq.req.end();
+        Some(rq)
+    }
 }
 
-pub struct VirtioBlock<'a>(pub VirtIOBlk<'a>);
+impl IoScheduler for Deadline {
+    fn name(&self) -> &'static str {
+        "deadline"
+    }
+
+    fn add(&mut self, rq: QueuedRequest) {
+        let expire = match rq.req.op {
+            BlockOp::Read => Deadline::READ_EXPIRE,
+            BlockOp::Write => Deadline::WRITE_EXPIRE,
+        };
+        let dir = Deadline::dir(rq.req.op);
+        let key = (rq.req.blk_id, rq.id);
+        self.fifo[dir].push_back((key, rq.submitted + timer::duration_to_ticks(expire)));
+        self.sorted[dir].insert(key, rq);
+    }
+
+    fn next(&mut self) -> Option<QueuedRequest> {
+        let reads = !self.sorted[0].is_empty();
+        let writes = !self.sorted[1].is_empty();
 
-impl<'a> Deref for VirtioBlock<'a> {
-    type Target = VirtIOBlk<'a>;
+        if reads && (!writes || self.writes_starved < Deadline::WRITES_STARVED) {
+            if writes {
+                self.writes_starved += 1;
+            }
+            self.next_of(BlockOp::Read)
+        } else {
+            self.writes_starved = 0;
+            self.next_of(BlockOp::Write)
+        }
+    }
+
+    fn take_adjacent(
+        &mut self,
+        op: BlockOp,
+        blk_id: usize,
+        max_bytes: usize,
+    ) -> Option<QueuedRequest> {
+        let rq = take_sorted(&mut self.sorted[Deadline::dir(op)], op, blk_id, max_bytes)?;
+        self.head = rq.req.end();
+        Some(rq)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    fn is_empty(&self) -> bool {
+        self.sorted.iter().all(|sorted| sorted.is_empty())
     }
 }
 
-impl<'a> BlockDevice for VirtioBlock<'a> {
-    fn read(&mut self, blk_id: usize, buf: &mut [u8]) -> Option<usize> {
-        self.0.read_block(blk_id, buf).map(|_| buf.len()).ok()
+/// Number of buckets in `BlockStats::latency`.
+pub const LATENCY_BUCKETS: usize = 16;
+
+/// Per-device I/O statistics.
+#[derive(Debug, Clone, Default)]
+pub struct BlockStats {
+    /// Requests submitted.
+    pub requests: u64,
+
+    /// Requests sent to the device, after merging.
+    pub dispatched: u64,
+
+    /// Requests merged into another one.
+    pub merges: u64,
+
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub errors: u64,
+
+    /// Latency of requests from submission to completion. Bucket 0 counts
+    /// requests that took less than 64us, and each next bucket covers twice
+    /// as long as the previous one. The last bucket counts everything
+    /// slower.
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+impl BlockStats {
+    /// The (exclusive) upper bound of latency bucket `i`.
+    pub fn bucket_limit(i: usize) -> Duration {
+        Duration::from_micros(64 << i)
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = (64 - (us >> 6).leading_zeros()) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+}
+
+impl core::fmt::Display for BlockStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "requests: {}, dispatched: {}, merges: {}, errors: {}",
+            self.requests, self.dispatched, self.merges, self.errors
+        )?;
+        writeln!(
+            f,
+            "read: {} bytes, written: {} bytes",
+            self.read_bytes, self.write_bytes
+        )?;
+        for (i, count) in self.latency.iter().enumerate().filter(|(_, c)| **c != 0) {
+            if i == LATENCY_BUCKETS - 1 {
+                writeln!(f, "    >= {:?}: {}", BlockStats::bucket_limit(i - 1), count)?;
+            } else {
+                writeln!(f, "    < {:?}: {}", BlockStats::bucket_limit(i), count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An interrupt-driven virtio-blk device, able to keep many requests in
+/// flight.
+///
+/// Requests are submitted with `submit`/`submit_batch` and picked up with
+/// `poll` or `wait`. Until there's room for them in the virtqueue, they
+/// wait in an I/O scheduler, which decides their order and merges adjacent
+/// ones into multi-segment device requests.
+///
+/// Completions are handled by `handle_interrupt`, which wakes up anyone
+/// sleeping in `wait` through a futex.
+///
+/// NOTE: Requests always address the disk in `SECTOR_SIZE` units, like
+/// virtio does, while `BlockDevice` works in the logical block size the
+/// device reports. Submitted requests should be aligned to the latter.
+pub struct VirtioBlock {
+    state: Arc<Mutex<VirtioBlockState>>,
+    capacity: usize,
+    block_size: usize,
+    read_only: bool,
+    flush: bool,
+
+    /// Largest discard and write zeroes requests, in sectors. Zero if the
+    /// device can't do them.
+    max_discard: usize,
+    max_write_zeroes: usize,
+
+    /// Bumped on every completion. Waiters sleep on this.
+    completions: Arc<AtomicU32>,
+}
+
+struct VirtioBlockState {
+    dev: VirtIOBlk<'static>,
+    completions: Arc<AtomicU32>,
+    next_id: u64,
+
+    /// Requests waiting for room in the virtqueue.
+    scheduler: Box<dyn IoScheduler>,
+
+    /// Commands waiting for room in the virtqueue. They go before any
+    /// queued request.
+    commands: VecDeque<(RequestId, Command)>,
+
+    /// Requests on the device, by token.
+    in_flight: BTreeMap<u16, InFlight>,
+
+    /// Completed requests that haven't been picked up yet.
+    completed: BTreeMap<RequestId, RequestResult>,
+
+    /// Completed commands that haven't been picked up yet.
+    commands_done: BTreeMap<RequestId, Result<(), BlockError>>,
+
+    stats: BlockStats,
+}
+
+/// Requests without data. Block numbers and counts are in sectors.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    Flush,
+    Discard(usize, usize),
+    WriteZeroes(usize, usize),
+}
+
+/// A device request.
+struct InFlight {
+    // Boxed, because the device reads/writes them until completion. The
+    // header is never looked at again, it only has to stay alive.
+    _req: Box<BlkReq>,
+    resp: Box<BlkResp>,
+    work: Work,
+}
+
+enum Work {
+    /// One or more merged requests, each with its buffer as one segment.
+    Data(Vec<QueuedRequest>),
+
+    Command(RequestId, Box<BlkRange>),
+}
+
+impl VirtioBlock {
+    /// Wrap `dev`, whose interrupts arrive through the PLIC source `irq`.
+    pub fn new(dev: VirtIOBlk<'static>, irq: u32) -> Self {
+        let completions = Arc::new(AtomicU32::new(0));
+        let capacity = dev.capacity();
+        let block_size = dev.blk_size();
+        let read_only = dev.readonly();
+        let flush = dev.flush_supported();
+        let max_discard = dev.max_discard_sectors() as usize;
+        let max_write_zeroes = dev.max_write_zeroes_sectors() as usize;
+        let state = Arc::new(Mutex::new(VirtioBlockState {
+            dev,
+            completions: completions.clone(),
+            next_id: 0,
+            scheduler: Box::new(Noop::default()),
+            commands: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            commands_done: BTreeMap::new(),
+            stats: BlockStats::default(),
+        }));
+        without_interrupts(|| VIRTIO_BLKS.lock().push((irq, state.clone())));
+        VirtioBlock {
+            state,
+            capacity,
+            block_size,
+            read_only,
+            flush,
+            max_discard,
+            max_write_zeroes,
+            completions,
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Switch to another I/O scheduler, moving queued requests over.
+    pub fn set_scheduler(&mut self, mut scheduler: Box<dyn IoScheduler>) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            while let Some(rq) = state.scheduler.next() {
+                scheduler.add(rq);
+            }
+            info!("block: using the {} I/O scheduler", scheduler.name());
+            state.scheduler = scheduler;
+        });
+    }
+
+    /// Submit a request, returning immediately.
+    pub fn submit(&mut self, req: BlockRequest) -> RequestId {
+        self.submit_batch(vec![req])[0]
+    }
+
+    /// Submit several requests at once, returning immediately.
+    ///
+    /// This gives the I/O scheduler the chance to merge and sort them.
+    /// Writes to a read-only device fail right away.
+    pub fn submit_batch(&mut self, reqs: Vec<BlockRequest>) -> Vec<RequestId> {
+        let read_only = self.read_only;
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            let now = time::read64();
+            let mut refused = false;
+            let ids = reqs
+                .into_iter()
+                .map(|req| {
+                    assert!(!req.buf.is_empty() && req.buf.len() % SECTOR_SIZE == 0);
+                    let id = state.next_id();
+                    state.stats.requests += 1;
+                    if read_only && req.op == BlockOp::Write {
+                        state.stats.errors += 1;
+                        state.completed.insert(id, Err(BlockError::ReadOnly));
+                        refused = true;
+                    } else {
+                        state.scheduler.add(QueuedRequest {
+                            id,
+                            req,
+                            submitted: now,
+                        });
+                    }
+                    id
+                })
+                .collect();
+            if refused {
+                self.completions.fetch_add(1, Ordering::Release);
+            }
+            state.dispatch();
+            ids
+        })
+    }
+
+    /// Take the result of request `id` if it has completed.
+    pub fn poll(&self, id: RequestId) -> Option<RequestResult> {
+        without_interrupts(|| self.state.lock().completed.remove(&id))
+    }
+
+    /// Sleep until request `id` completes and return its result.
+    ///
+    /// Must not be called with interrupts disabled, otherwise we never get
+    /// to see the completion.
+    pub fn wait(&mut self, id: RequestId) -> RequestResult {
+        sleep_until(&self.completions, || self.poll(id))
+    }
+
+    /// Run `cmd` ahead of queued requests and wait for it.
+    fn command(&mut self, cmd: Command) -> Result<(), BlockError> {
+        let id = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let id = state.next_id();
+            state.commands.push_back((id, cmd));
+            state.dispatch();
+            id
+        });
+        let state = &self.state;
+        sleep_until(&self.completions, || {
+            without_interrupts(|| state.lock().commands_done.remove(&id))
+        })
+    }
+
+    /// Split `count` blocks starting at `blk_id` into commands of at most
+    /// `max` sectors, and run them one after the other.
+    fn range_command(
+        &mut self,
+        blk_id: usize,
+        count: usize,
+        max: usize,
+        cmd: fn(usize, usize) -> Command,
+    ) -> Result<(), BlockError> {
+        check_range(self, blk_id, count * self.block_size)?;
+        let per_block = self.block_size / SECTOR_SIZE;
+        let (mut sector, end) = (blk_id * per_block, (blk_id + count) * per_block);
+        // Keep whole blocks in each command.
+        let max = max / per_block * per_block;
+        while sector < end {
+            let n = (end - sector).min(max);
+            self.command(cmd(sector, n))?;
+            sector += n;
+        }
+        Ok(())
+    }
+}
+
+/// Sleep on `completions` until `ready` has something.
+fn sleep_until<T>(completions: &AtomicU32, mut ready: impl FnMut() -> Option<T>) -> T {
+    let uaddr = VirtAddr::new(completions as *const AtomicU32 as u64);
+    loop {
+        // Load the counter before polling, so that a completion in
+        // between makes the futex wait return right away.
+        let seen = completions.load(Ordering::Acquire);
+        if let Some(result) = ready() {
+            return result;
+        }
+        match futex::wait(uaddr, seen, None) {
+            Ok(()) | Err(FutexError::WouldBlock) => {}
+            Err(e) => panic!("failed to wait for block request: {:?}", e),
+        }
+    }
+}
+
+fn device_error(e: VirtioError) -> BlockError {
+    match e {
+        VirtioError::Unsupported => BlockError::Unsupported,
+        _ => BlockError::Io,
+    }
+}
+
+impl VirtioBlockState {
+    fn next_id(&mut self) -> RequestId {
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Move commands and queued requests onto the device, as long as
+    /// there's room.
+    fn dispatch(&mut self) {
+        // Room for the header, the status and at least one more.
+        while self.dev.available_desc() > DESCS_PER_HEADER {
+            if let Some((id, cmd)) = self.commands.pop_front() {
+                self.dispatch_command(id, cmd);
+                continue;
+            }
+
+            let mut parts = match self.scheduler.next() {
+                Some(first) => vec![first],
+                None => return,
+            };
+            let max_segments = self
+                .dev
+                .seg_max()
+                .min(self.dev.available_desc() - DESCS_PER_HEADER);
+            let (op, blk_id) = (parts[0].req.op, parts[0].req.blk_id);
+            let mut len = parts[0].req.buf.len();
+            let mut end = parts[0].req.end();
+            while parts.len() < max_segments {
+                let next = match self
+                    .scheduler
+                    .take_adjacent(op, end, MAX_MERGED_BYTES - len)
+                {
+                    Some(next) => next,
+                    None => break,
+                };
+                len += next.req.buf.len();
+                end = next.req.end();
+                parts.push(next);
+            }
+
+            self.stats.dispatched += 1;
+            self.stats.merges += parts.len() as u64 - 1;
+
+            let mut req = Box::new(BlkReq::default());
+            let mut resp = Box::new(BlkResp::default());
+
+            // SAFETY: The header, status and data buffers are owned by the
+            // `InFlight` below, which lives in `self.in_flight` until the
+            // request completes. Moving it around doesn't move the heap
+            // allocations.
+            let token = unsafe {
+                match op {
+                    BlockOp::Read => {
+                        let mut bufs: Vec<&mut [u8]> =
+                            parts.iter_mut().map(|p| &mut p.req.buf[..]).collect();
+                        self.dev
+                            .read_segments_nb(blk_id, &mut req, &mut bufs, &mut resp)
+                    }
+                    BlockOp::Write => {
+                        let bufs: Vec<&[u8]> = parts.iter().map(|p| &p.req.buf[..]).collect();
+                        self.dev
+                            .write_segments_nb(blk_id, &mut req, &bufs, &mut resp)
+                    }
+                }
+            };
+            match token {
+                Ok(token) => {
+                    let in_flight = InFlight {
+                        _req: req,
+                        resp,
+                        work: Work::Data(parts),
+                    };
+                    self.in_flight.insert(token, in_flight);
+                }
+                Err(e) => {
+                    // Fail every part.
+                    for part in parts.iter() {
+                        self.stats.errors += 1;
+                        self.completed.insert(part.id, Err(device_error(e)));
+                    }
+                    self.completions.fetch_add(1, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    fn dispatch_command(&mut self, id: RequestId, cmd: Command) {
+        let mut req = Box::new(BlkReq::default());
+        let mut resp = Box::new(BlkResp::default());
+        let mut range = Box::new(BlkRange::default());
+
+        // SAFETY: Same as for data requests in `dispatch`.
+        let token = unsafe {
+            match cmd {
+                Command::Flush => self.dev.flush_nb(&mut req, &mut resp),
+                Command::Discard(sector, count) => self
+                    .dev
+                    .discard_nb(sector, count, &mut req, &mut range, &mut resp),
+                Command::WriteZeroes(sector, count) => self
+                    .dev
+                    .write_zeroes_nb(sector, count, &mut req, &mut range, &mut resp),
+            }
+        };
+        match token {
+            Ok(token) => {
+                let in_flight = InFlight {
+                    _req: req,
+                    resp,
+                    work: Work::Command(id, range),
+                };
+                self.in_flight.insert(token, in_flight);
+            }
+            Err(e) => {
+                self.stats.errors += 1;
+                self.commands_done.insert(id, Err(device_error(e)));
+                self.completions.fetch_add(1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Handle a device request that's done.
+    fn complete(&mut self, token: u16) {
+        let InFlight { resp, work, .. } = match self.in_flight.remove(&token) {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+        let result = match resp.status() {
+            RespStatus::Ok => Ok(()),
+            RespStatus::Unsupported => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        };
+
+        let parts = match work {
+            Work::Data(parts) => parts,
+            Work::Command(id, _) => {
+                if result.is_err() {
+                    self.stats.errors += 1;
+                }
+                self.commands_done.insert(id, result);
+                return;
+            }
+        };
+
+        let now = time::read64();
+        for part in parts.iter() {
+            let latency = timer::ticks_to_duration(now.saturating_sub(part.submitted));
+            self.stats.record_latency(latency);
+        }
+
+        if let Err(e) = result {
+            for part in parts {
+                self.stats.errors += 1;
+                self.completed.insert(part.id, Err(e));
+            }
+            return;
+        }
+
+        let bytes: u64 = parts.iter().map(|p| p.req.buf.len() as u64).sum();
+        match parts[0].req.op {
+            BlockOp::Read => self.stats.read_bytes += bytes,
+            BlockOp::Write => self.stats.write_bytes += bytes,
+        }
+
+        // Every part got its own segment, so the data is already in place.
+        for QueuedRequest { id, req, .. } in parts {
+            self.completed.insert(id, Ok(req));
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> usize {
+        self.capacity / (self.block_size / SECTOR_SIZE)
+    }
+
+    /// Read contiguous blocks with a single request.
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let sector = blk_id * (self.block_size / SECTOR_SIZE);
+        let id = self.submit(BlockRequest::read(sector, buf.len() / SECTOR_SIZE));
+        let req = self.wait(id)?;
+        buf.copy_from_slice(&req.buf);
+        Ok(())
+    }
+
+    /// Write contiguous blocks with a single request.
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let sector = blk_id * (self.block_size / SECTOR_SIZE);
+        let id = self.submit(BlockRequest::write(sector, buf.to_vec()));
+        self.wait(id).map(|_| ())
+    }
+
+    /// Flush the write cache of the device, if it has one. Every write done
+    /// through here has completed already, so they're all covered.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if !self.flush || self.read_only {
+            return Ok(());
+        }
+        self.command(Command::Flush)
+    }
+
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        if self.max_discard < self.block_size / SECTOR_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        self.range_command(blk_id, count, self.max_discard, Command::Discard)
+    }
+
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        if self.max_write_zeroes < self.block_size / SECTOR_SIZE {
+            return zero_fill(self, blk_id, count);
+        }
+        self.range_command(blk_id, count, self.max_write_zeroes, Command::WriteZeroes)
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        Some(without_interrupts(|| self.state.lock().stats.clone()))
+    }
+}
+
+/// Complete the requests of the virtio-blk device behind PLIC source `irq`.
+///
+/// Called from the PLIC interrupt handler.
+pub fn handle_interrupt(irq: u32) {
+    let devices = VIRTIO_BLKS.lock();
+    for (_, state) in devices.iter().filter(|(i, _)| *i == irq) {
+        let mut state = state.lock();
+        state.dev.ack_interrupt();
+
+        let mut completed = false;
+        while let Ok(token) = state.dev.pop_used() {
+            state.complete(token);
+            completed = true;
+        }
+        if !completed {
+            continue;
+        }
+
+        // Descriptors were freed, so queued requests may fit now.
+        state.dispatch();
+
+        state.completions.fetch_add(1, Ordering::Release);
+        let uaddr = VirtAddr::new(&*state.completions as *const AtomicU32 as u64);
+        futex::wake(uaddr, usize::MAX).unwrap();
+    }
+}
+
+/// A block device in memory, for tests.
+#[cfg(test)]
+pub struct MemDevice {
+    pub blocks: Vec<[u8; SECTOR_SIZE]>,
+
+    /// Blocks written so far.
+    pub writes: usize,
+
+    /// Make writes fail with `BlockError::Io`.
+    pub fail_writes: bool,
+}
+
+#[cfg(test)]
+impl MemDevice {
+    pub fn new(count: usize) -> Self {
+        MemDevice {
+            blocks: vec![[0; SECTOR_SIZE]; count],
+            writes: 0,
+            fail_writes: false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for MemDevice {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        for (block, chunk) in self.blocks[blk_id..]
+            .iter()
+            .zip(buf.chunks_mut(SECTOR_SIZE))
+        {
+            chunk.copy_from_slice(block);
+        }
+        Ok(())
     }
 
-    fn write(&mut self, blk_id: usize, buf: &[u8]) -> Option<usize> {
-        self.0.write_block(blk_id, buf).map(|_| buf.len()).ok()
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        if self.fail_writes {
+            return Err(BlockError::Io);
+        }
+        for (block, chunk) in self.blocks[blk_id..]
+            .iter_mut()
+            .zip(buf.chunks(SECTOR_SIZE))
+        {
+            block.copy_from_slice(chunk);
+            self.writes += 1;
+        }
+        Ok(())
     }
 }
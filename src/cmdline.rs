@@ -0,0 +1,114 @@
+//! The kernel command line, from `/chosen/bootargs`.
+//!
+//! Options are whitespace separated `key=value` pairs (or bare `key`s).
+//! QEMU passes whatever follows `-append`.
+//!
+//! Secrets (just `cryptkey=` so far) are left out when the command line is
+//! logged, and whoever uses one should [`take`] it so that it doesn't stay
+//! around.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+static CMDLINE: Mutex<String> = Mutex::new(String::new());
+
+pub fn set(cmdline: &str) {
+    *CMDLINE.lock() = cmdline.to_string();
+}
+
+/// Options whose values don't get logged.
+const SECRETS: &[&str] = &["cryptkey"];
+
+/// The value of option `key`. The last one wins if it's given twice.
+pub fn get(key: &str) -> Option<String> {
+    find(&CMDLINE.lock(), key).map(|v| v.to_string())
+}
+
+/// Like `get`, but removes every `key` option from the command line, and
+/// zeroes the memory they were in.
+pub fn take(key: &str) -> Option<String> {
+    let mut cmdline = CMDLINE.lock();
+    let value = find(&cmdline, key)?.to_string();
+    let rest = remove(&cmdline, key);
+    // Zero bytes are still UTF-8.
+    unsafe { cmdline.as_bytes_mut().fill(0) };
+    *cmdline = rest;
+    Some(value)
+}
+
+/// `cmdline` with the values of secret options replaced, to log it.
+pub fn redact(cmdline: &str) -> String {
+    let opts = cmdline
+        .split_ascii_whitespace()
+        .map(|opt| match opt.split_once('=') {
+            Some((k, _)) if SECRETS.contains(&k) => format!("{}=<redacted>", k),
+            _ => opt.to_string(),
+        });
+    opts.collect::<Vec<_>>().join(" ")
+}
+
+/// `cmdline` without any `key` options.
+fn remove(cmdline: &str, key: &str) -> String {
+    let opts = cmdline
+        .split_ascii_whitespace()
+        .filter(|opt| opt.split_once('=').map_or(*opt, |(k, _)| k) != key);
+    opts.collect::<Vec<_>>().join(" ")
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .filter_map(|opt| match opt.split_once('=') {
+            Some((k, v)) => (k == key).then_some(v),
+            None => (opt == key).then_some(""),
+        })
+        .last()
+}
+
+/// A size in bytes, with an optional `K`, `M` or `G` suffix.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[test_case]
+fn test_cmdline_find() {
+    let cmdline = "root=vda1  quiet crypt=vda root=LABEL=ROOT";
+    assert_eq!(find(cmdline, "root"), Some("LABEL=ROOT"));
+    assert_eq!(find(cmdline, "quiet"), Some(""));
+    assert_eq!(find(cmdline, "crypt"), Some("vda"));
+    assert_eq!(find(cmdline, "cryptkey"), None);
+}
+
+#[test_case]
+fn test_cmdline_parse_size() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("16M"), Some(16 * 1024 * 1024));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size("off"), None);
+}
+
+#[test_case]
+fn test_cmdline_secrets() {
+    let cmdline = "crypt=vda cryptkey=00112233 root=crypt0 cryptkey2=x";
+    assert_eq!(
+        redact(cmdline),
+        "crypt=vda cryptkey=<redacted> root=crypt0 cryptkey2=x"
+    );
+    assert_eq!(
+        remove(cmdline, "cryptkey"),
+        "crypt=vda root=crypt0 cryptkey2=x"
+    );
+    assert_eq!(remove("cryptkey quiet", "cryptkey"), "quiet");
+}
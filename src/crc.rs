@@ -0,0 +1,57 @@
+//! Table-driven CRC32s.
+
+/// CRC-32 (IEEE 802.3), as used by GPT, zip and friends.
+pub fn crc32(data: &[u8]) -> u32 {
+    !update(!0, data, &CRC32_TABLE)
+}
+
+/// CRC-32C (Castagnoli), as used by iSCSI, ext4 and btrfs.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !update(!0, data, &CRC32C_TABLE)
+}
+
+/// Feed `data` into a running CRC. The caller takes care of the initial
+/// value and the final xor.
+fn update(mut crc: u32, data: &[u8], table: &[u32; 256]) -> u32 {
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Reversed polynomial of CRC-32.
+const CRC32_POLY: u32 = 0xedb88320;
+
+/// Reversed polynomial of CRC-32C.
+const CRC32C_POLY: u32 = 0x82f63b78;
+
+static CRC32_TABLE: [u32; 256] = make_table(CRC32_POLY);
+static CRC32C_TABLE: [u32; 256] = make_table(CRC32C_POLY);
+
+const fn make_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    assert_eq!(crc32c(b""), 0);
+    assert_eq!(crc32c(b"123456789"), 0xe3069283);
+}
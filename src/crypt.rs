@@ -0,0 +1,279 @@
+//! Encrypted block devices, in the spirit of dm-crypt.
+//!
+//! Every block is encrypted on its own with AES-XTS, using the block
+//! number as the tweak. With 512 byte blocks that's dm-crypt's
+//! `aes-xts-plain64`, so an image can be prepared on Linux with
+//!
+//! ```text
+//! cryptsetup open --type plain --cipher aes-xts-plain64 --key-size 512 \
+//!     --key-file key.bin fs.img crypt
+//! ```
+//!
+//! and then formatted and filled through `/dev/mapper/crypt`.
+//!
+//! The key comes from the command line: `crypt=vda` picks the device,
+//! `cryptkey=<hex>` gives the key directly, and `cryptkey=initrd` uses the
+//! initrd as the key file. The decrypted device is registered as `crypt0`.
+//! Once read, the key is zeroed where it came from, the command line or the
+//! initrd.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use log::info;
+
+use crate::{
+    aes::{self, Aes},
+    blkdev::{self, RegistryError},
+    block::{BlockDevice, BlockError, BlockStats},
+    cmdline, ramdisk,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptError {
+    /// The key is missing, or isn't 32 or 64 bytes (AES-128 or AES-256).
+    InvalidKey,
+
+    Registry(RegistryError),
+}
+
+/// AES-XTS (IEEE 1619) over whole data units.
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl Xts {
+    /// `key` holds the data key followed by the tweak key.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() != 32 && key.len() != 64 {
+            return None;
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Some(Xts {
+            data: Aes::new(data)?,
+            tweak: Aes::new(tweak)?,
+        })
+    }
+
+    pub fn encrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.process(unit, buf, |aes, block| aes.encrypt_block(block));
+    }
+
+    pub fn decrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.process(unit, buf, |aes, block| aes.decrypt_block(block));
+    }
+
+    // NOTE: Data units are always whole AES blocks here, so there's no
+    // ciphertext stealing.
+    fn process(&self, unit: u64, buf: &mut [u8], f: impl Fn(&Aes, &mut [u8; aes::BLOCK_SIZE])) {
+        assert_eq!(buf.len() % aes::BLOCK_SIZE, 0);
+        let mut tweak = [0; aes::BLOCK_SIZE];
+        tweak[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+
+        for chunk in buf.chunks_mut(aes::BLOCK_SIZE) {
+            let block: &mut [u8; aes::BLOCK_SIZE] = chunk.try_into().unwrap();
+            xor(block, &tweak);
+            f(&self.data, block);
+            xor(block, &tweak);
+            mul_alpha(&mut tweak);
+        }
+    }
+}
+
+fn xor(block: &mut [u8; aes::BLOCK_SIZE], tweak: &[u8; aes::BLOCK_SIZE]) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// Multiply the tweak by x in GF(2^128), little endian.
+fn mul_alpha(tweak: &mut [u8; aes::BLOCK_SIZE]) {
+    let mut carry = 0;
+    for b in tweak.iter_mut() {
+        let next = *b >> 7;
+        *b = (*b << 1) | carry;
+        carry = next;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+/// A block device whose contents are encrypted on `dev`.
+pub struct Crypt<B>
+where
+    B: BlockDevice,
+{
+    dev: B,
+    xts: Xts,
+}
+
+impl<B> Crypt<B>
+where
+    B: BlockDevice,
+{
+    pub fn new(dev: B, key: &[u8]) -> Result<Self, CryptError> {
+        let xts = Xts::new(key).ok_or(CryptError::InvalidKey)?;
+        Ok(Crypt { dev, xts })
+    }
+}
+
+impl<B> BlockDevice for Crypt<B>
+where
+    B: BlockDevice,
+{
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.dev.block_count()
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.dev.read_blocks(blk_id, buf)?;
+        let block_size = self.block_size();
+        for (i, block) in buf.chunks_mut(block_size).enumerate() {
+            self.xts.decrypt((blk_id + i) as u64, block);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        let mut encrypted = buf.to_vec();
+        let block_size = self.block_size();
+        for (i, block) in encrypted.chunks_mut(block_size).enumerate() {
+            self.xts.encrypt((blk_id + i) as u64, block);
+        }
+        self.dev.write_blocks(blk_id, &encrypted)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+
+    // NOTE: No `discard`, it'd tell whoever has the disk which blocks are
+    // unused.
+
+    fn stats(&self) -> Option<BlockStats> {
+        self.dev.stats()
+    }
+}
+
+/// Register `crypt0` if the command line asks for it.
+pub fn setup_from_cmdline() -> Result<(), CryptError> {
+    let name = match cmdline::get("crypt") {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let mut key = match cmdline::take("cryptkey") {
+        Some(arg) if arg == "initrd" => {
+            let initrd = ramdisk::take_initrd().ok_or(CryptError::InvalidKey)?;
+            let key = initrd.to_vec();
+            initrd.fill(0);
+            key
+        }
+        Some(mut hex) => {
+            let key = parse_hex(&hex);
+            // Zero bytes are still UTF-8.
+            unsafe { hex.as_bytes_mut().fill(0) };
+            key.ok_or(CryptError::InvalidKey)?
+        }
+        None => return Err(CryptError::InvalidKey),
+    };
+
+    let node = blkdev::devices()
+        .into_iter()
+        .find(|d| d.name == name)
+        .map(|d| d.node)
+        .unwrap_or_default();
+    let dev = blkdev::claim(&name).map_err(CryptError::Registry);
+    let crypt = dev.and_then(|dev| Crypt::new(dev, &key));
+    key.fill(0);
+    let crypt = crypt?;
+
+    info!("crypt0: {} decrypted with AES-{}-XTS", name, key.len() * 4);
+    blkdev::register_as(String::from("crypt0"), &node, Box::new(crypt));
+    Ok(())
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+fn from_hex<const N: usize>(hex: &str) -> [u8; N] {
+    parse_hex(hex).unwrap().try_into().unwrap()
+}
+
+#[test_case]
+fn test_xts_known_answers() {
+    // IEEE 1619, vectors 1 and 2.
+    let xts = Xts::new(&[0; 32]).unwrap();
+    let mut buf = [0; 32];
+    xts.encrypt(0, &mut buf);
+    assert_eq!(
+        buf,
+        from_hex::<32>("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e")
+    );
+    xts.decrypt(0, &mut buf);
+    assert_eq!(buf, [0; 32]);
+
+    let mut key = [0x11; 32];
+    key[16..].fill(0x22);
+    let xts = Xts::new(&key).unwrap();
+    let mut buf = [0x44; 32];
+    xts.encrypt(0x3333333333, &mut buf);
+    assert_eq!(
+        buf,
+        from_hex::<32>("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0")
+    );
+
+    // A whole sector, so that the tweak gets carried over, with AES-256.
+    let mut key = [0; 64];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let xts = Xts::new(&key).unwrap();
+    let mut sector = [0; 512];
+    for (i, b) in sector.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    xts.encrypt(5, &mut sector);
+    assert_eq!(
+        sector[..32],
+        from_hex::<32>("f87ca2f29b117c1b024a6ec8e8c5994e76f7d16b43eed21e6936126969e00dab")
+    );
+    assert_eq!(
+        sector[480..],
+        from_hex::<32>("eb6523fbfb5ca033725f703578b7dbb0e790ce5900c47286caaef5e457fecc4b")
+    );
+}
+
+#[test_case]
+fn test_fat32_on_crypt() {
+    use crate::{fat32::Fat32, ramdisk::RamDisk};
+
+    let mut plain = ramdisk::fat32_test_image();
+    let key = [0x5a; 64];
+    let mut crypt = Crypt::new(RamDisk::new(plain.block_count() * 512), &key).unwrap();
+    // Only the boot sector and the start of the FAT are needed to mount.
+    let mut buf = [0; 64 * 512];
+    plain.read_blocks(0, &mut buf).unwrap();
+    crypt.write_blocks(0, &buf).unwrap();
+
+    // What ends up on the disk is nothing like the plain text.
+    let mut on_disk = [0; 512];
+    crypt.dev.read(0, &mut on_disk).unwrap();
+    assert_ne!(on_disk, buf[..512]);
+
+    let fat32 = Fat32::new(crypt).unwrap();
+    assert_eq!(fat32.label(), "TRANSPARENT");
+}
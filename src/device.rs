@@ -1,8 +1,19 @@
+use alloc::{boxed::Box, format, string::String};
+
 use device_tree::{util::SliceRead, DeviceTree, Node};
-use log::trace;
-use virtio_drivers::{DeviceType, VirtIOBlk, VirtIOHeader};
+use log::{info, trace, warn};
+use virtio_drivers::{
+    DeviceType, PciCapabilityType, PciTransport, Transport, VirtIOBlk, VirtIOHeader,
+};
 
-use crate::block::{VirtioBlock, BLK};
+use crate::{
+    blkdev,
+    block::{BlockDevice, Deadline, VirtioBlock},
+    cmdline,
+    partition::{self, PartitionError},
+    pci::{self, PciDevice},
+    ramdisk::{self, RamDisk},
+};
 
 pub fn init(device_tree_addr: usize) {
     init_device_tree(device_tree_addr);
@@ -29,6 +40,8 @@ fn walk_dt_node(dt: &Node) {
     if let Ok(compatible) = dt.prop_str("compatible") {
         if compatible == "virtio,mmio" {
             virtio_probe(dt);
+        } else if compatible == "pci-host-ecam-generic" {
+            pci_probe(dt);
         }
     }
     if let Ok(device_type) = dt.prop_str("device_type") {
@@ -36,6 +49,9 @@ fn walk_dt_node(dt: &Node) {
             memory_probe(dt)
         }
     }
+    if dt.name == "chosen" {
+        chosen_probe(dt);
+    }
     for child in dt.children.iter() {
         walk_dt_node(child);
     }
@@ -51,6 +67,28 @@ fn memory_probe(db: &Node) {
     }
 }
 
+fn chosen_probe(node: &Node) {
+    if let Ok(bootargs) = node.prop_str("bootargs") {
+        info!("command line: {}", cmdline::redact(bootargs));
+        cmdline::set(bootargs);
+    }
+    if let Some((start, end)) = ramdisk::initrd_region(node) {
+        info!("initrd: {:#x} ~ {:#x}", start, end);
+        // The heap is in the kernel image, so nothing is going to
+        // allocate over the initrd.
+        unsafe { ramdisk::set_initrd(start, end) };
+        // Otherwise it's left for `crypt` to take, and not for anyone who
+        // can open a block device to read.
+        if cmdline::get("cryptkey").as_deref() == Some("initrd") {
+            info!("initrd: holds the crypt key");
+        } else if let Some(initrd) = ramdisk::take_initrd() {
+            let disk = RamDisk::from_region(initrd);
+            blkdev::register_as(String::from("ram0"), &node.name, Box::new(disk));
+            scan_partitions("ram0");
+        }
+    }
+}
+
 fn virtio_probe(node: &Node) {
     if let Some(reg) = node.prop_raw("reg") {
         let paddr = reg.as_slice().read_be_u64(0).unwrap();
@@ -63,15 +101,107 @@ fn virtio_probe(node: &Node) {
             header.vendor_id()
         );
         trace!("Device tree node {:?}", node);
+        // QEMU's virt machine wires each virtio-mmio slot to its own
+        // PLIC source.
+        let irq = node.prop_u32("interrupts").unwrap_or(0);
+        // Which interface QEMU offers depends on
+        // `-global virtio-mmio.force-legacy=`. The header drives either.
+        match header.mmio_version() {
+            Some(version) => trace!("virtio-mmio {:?} interface", version),
+            None => {
+                warn!("{}: unknown virtio-mmio version, skipping", node.name);
+                return;
+            }
+        }
         match header.device_type() {
-            DeviceType::Block => virtio_blk(header),
+            DeviceType::Block => virtio_blk(&node.name, header, irq),
             t => trace!("Unrecognized virtio device: {:?}", t),
         }
     }
 }
 
-fn virtio_blk(header: &'static mut VirtIOHeader) {
-    let blk = VirtIOBlk::new(header).expect("failed to create blk driver");
+fn pci_probe(node: &Node) {
+    for dev in pci::enumerate(node) {
+        if dev.vendor_id == VIRTIO_PCI_VENDOR {
+            virtio_pci_probe(node, &dev);
+        }
+    }
+}
+
+const VIRTIO_PCI_VENDOR: u16 = 0x1af4;
+
+/// Capability ID of the virtio structures.
+const PCI_CAP_VENDOR: u8 = 0x09;
+
+fn virtio_pci_probe(node: &Node, dev: &PciDevice) {
+    // Transitional devices tell the type in the subsystem ID, modern ones
+    // in the device ID.
+    let device_type = match dev.device_id {
+        0x1000..=0x103f => dev.subsystem_id,
+        0x1040..=0x107f => dev.device_id - 0x1040,
+        _ => return,
+    };
+
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_off_multiplier = 0;
+    for cap in dev.capabilities().filter(|cap| cap.id == PCI_CAP_VENDOR) {
+        let cfg_type = PciCapabilityType::from_u8(dev.read8(cap.offset + 3));
+        let bar = dev.read8(cap.offset + 4) as usize;
+        let offset = dev.read32(cap.offset + 8) as usize;
+        // The first capability of each type is the preferred one.
+        let addr = match dev.bars.get(bar).copied().flatten() {
+            Some(bar) => bar.addr + offset,
+            None => continue,
+        };
+        match cfg_type {
+            Some(PciCapabilityType::Common) => common = common.or(Some(addr)),
+            Some(PciCapabilityType::Notify) if notify.is_none() => {
+                notify = Some(addr);
+                notify_off_multiplier = dev.read32(cap.offset + 16);
+            }
+            Some(PciCapabilityType::Isr) => isr = isr.or(Some(addr)),
+            Some(PciCapabilityType::Device) => device = device.or(Some(addr)),
+            _ => {}
+        }
+    }
+    let name = format!("{}/{}", node.name, dev.address());
+    let (common, notify, isr, device) = match (common, notify, isr, device) {
+        (Some(common), Some(notify), Some(isr), Some(device)) => (common, notify, isr, device),
+        // TODO: Legacy only devices, through the I/O BAR.
+        _ => {
+            warn!("{}: virtio-pci device without modern interface", name);
+            return;
+        }
+    };
+    let irq = match dev.irq {
+        Some(irq) => irq,
+        None => {
+            warn!("{}: no interrupt, skipping", name);
+            return;
+        }
+    };
+
+    match device_type {
+        2 => {
+            // SAFETY: The BARs were just assigned, and stay where they are.
+            let transport = unsafe {
+                PciTransport::new(
+                    DeviceType::Block,
+                    common,
+                    notify,
+                    notify_off_multiplier,
+                    isr,
+                    device,
+                )
+            };
+            virtio_blk(&name, Box::leak(Box::new(transport)), irq);
+        }
+        t => trace!("Unrecognized virtio-pci device type: {}", t),
+    }
+}
+
+fn virtio_blk(node_name: &str, transport: &'static mut dyn Transport, irq: u32) {
+    let blk = VirtIOBlk::new(transport).expect("failed to create blk driver");
     /*
     let mut input = [0xffu8; 512];
     let mut output = [0u8; 512];
@@ -85,7 +215,23 @@ fn virtio_blk(header: &'static mut VirtIOHeader) {
     }
     info!("virtio-blk test finished");
     */
-    unsafe {
-        BLK = Some(VirtioBlock(blk));
+    let mut blk = VirtioBlock::new(blk, irq);
+    blk.set_scheduler(Box::new(Deadline::default()));
+    let (block_size, read_only) = (blk.block_size(), blk.read_only());
+    let name = blkdev::register("vd", node_name, Box::new(blk));
+    info!(
+        "{}: virtio-blk at {}, {} byte blocks{}",
+        name,
+        node_name,
+        block_size,
+        if read_only { ", read-only" } else { "" }
+    );
+    scan_partitions(&name);
+}
+
+fn scan_partitions(name: &str) {
+    match partition::register_partitions(name) {
+        Ok(_) | Err(PartitionError::NoTable) => {}
+        Err(e) => warn!("{}: failed to read the partition table: {:?}", name, e),
     }
 }
@@ -0,0 +1,1267 @@
+//! Read-only exFAT, with the same kind of API as [`crate::fat32`].
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{info, warn};
+
+use crate::{
+    bcache::BufferCache,
+    block::{BlockDevice, BlockError},
+    fat32::{Attributes, DateTime, SeekFrom},
+    println,
+};
+
+/// How many blocks the buffer cache of a volume holds.
+const CACHED_BLOCKS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExFatError {
+    /// The underlying block device failed.
+    Block(BlockError),
+
+    /// Sector 0 doesn't hold an exFAT boot sector.
+    InvalidBootSector,
+
+    /// Neither the main nor the backup boot region matches its checksum.
+    BootChecksum,
+
+    /// The boot sector describes a volume we can't make sense of.
+    InvalidVolume(VolumeError),
+
+    /// The root directory has no allocation bitmap.
+    NoBitmap,
+
+    /// The root directory has no up-case table.
+    NoUpcaseTable,
+
+    /// The up-case table doesn't match its checksum.
+    UpcaseChecksum,
+
+    /// There's no such file or directory.
+    NoSuchFile,
+
+    /// A path goes through something that isn't a directory.
+    NotADirectory,
+
+    /// Tried to open a directory as a file.
+    IsADirectory,
+
+    /// Tried to seek before the start of a file.
+    InvalidSeek,
+
+    /// A cluster chain ends before the data it should hold does, or goes
+    /// somewhere outside the cluster heap.
+    BrokenChain,
+}
+
+impl From<BlockError> for ExFatError {
+    fn from(e: BlockError) -> Self {
+        ExFatError::Block(e)
+    }
+}
+
+/// What's wrong with the geometry in a boot sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeError {
+    /// Sectors are 512 to 4096 bytes.
+    BytesPerSectorShift(u8),
+
+    /// Clusters are at most 32 MiB.
+    SectorsPerClusterShift(u8),
+
+    /// There are one or two FATs.
+    Fats(u8),
+
+    /// We only know version 1.
+    Revision(u16),
+
+    /// The volume is bigger than the device, or smaller than exFAT allows.
+    VolumeLength(u64),
+
+    /// The FATs overlap the boot regions.
+    FatOffset(u32),
+
+    /// The FAT can't hold an entry for every cluster.
+    FatTooSmall,
+
+    /// The cluster heap overlaps the FATs, or runs past the end of the
+    /// volume.
+    ClusterHeap(u32),
+
+    RootCluster(u32),
+
+    /// `volume_flags` makes the second FAT the active one, and there's only
+    /// one.
+    ActiveFat,
+}
+
+/// A read-only exFAT volume.
+pub struct ExFat<B>
+where
+    B: BlockDevice,
+{
+    cache: BufferCache<B>,
+    boot: BootSector,
+    /// The upper case of every UTF-16 code unit, for comparing names. Code
+    /// units past the end map to themselves.
+    upcase: Vec<u16>,
+    label: String,
+    free_clusters: u32,
+    /// The root directory has no entry of its own to say how big it is, so
+    /// that's worked out when mounting.
+    root_size: u64,
+}
+
+impl<B> ExFat<B>
+where
+    B: BlockDevice,
+{
+    pub fn new(block: B) -> Result<Self, ExFatError> {
+        let device_size = block.block_count() as u64 * block.block_size() as u64;
+        let mut cache = BufferCache::new(block, CACHED_BLOCKS);
+
+        // Only use the backup boot region if the main one is corrupt.
+        let boot = match BootSector::read_region(&mut cache, 0) {
+            Ok(boot) => boot,
+            Err(
+                e @ (ExFatError::InvalidBootSector
+                | ExFatError::BootChecksum
+                | ExFatError::InvalidVolume(VolumeError::BytesPerSectorShift(_))),
+            ) => {
+                warn!("exFAT main boot region is corrupt, trying the backup");
+                BootSector::read_backup(&mut cache).ok_or(e)?
+            }
+            Err(e) => return Err(e),
+        };
+        boot.validate(device_size)
+            .map_err(ExFatError::InvalidVolume)?;
+
+        let mut exfat = ExFat {
+            cache,
+            boot,
+            upcase: Vec::new(),
+            label: String::new(),
+            free_clusters: 0,
+            root_size: 0,
+        };
+        exfat.root_size = exfat.chain_len(boot.root_cluster)? * exfat.bytes_per_cluster() as u64;
+        exfat.read_root_entries()?;
+        Ok(exfat)
+    }
+
+    /// The underlying block device.
+    pub fn device(&self) -> &B {
+        self.cache.device()
+    }
+
+    /// The volume serial number, formatted like `blkid` does (`1234-ABCD`).
+    pub fn uuid(&self) -> String {
+        let id = self.boot.serial;
+        format!("{:04X}-{:04X}", id >> 16, id & 0xffff)
+    }
+
+    /// The volume label from the root directory, if there is one.
+    pub fn label(&self) -> String {
+        self.label.clone()
+    }
+
+    /// Check the geometry in the boot sector makes sense. `new` already
+    /// refuses volumes that don't pass, this logs what the volume looks like.
+    pub fn check_fs(&self) -> Result<(), VolumeError> {
+        info!("Checking exFAT filesystem");
+        info!("{:?}", self.boot);
+
+        let device = self.device();
+        self.boot
+            .validate(device.block_count() as u64 * device.block_size() as u64)?;
+
+        info!(
+            "{} clusters of {} bytes, {} free, data from sector 0x{:x}, FAT {} active",
+            self.cluster_count(),
+            self.bytes_per_cluster(),
+            self.free_clusters,
+            self.boot.cluster_heap_offset,
+            self.boot.active_fat(),
+        );
+        info!("Check passed");
+        Ok(())
+    }
+
+    /// How many clusters the allocation bitmap says are free.
+    pub fn free_clusters(&self) -> u32 {
+        self.free_clusters
+    }
+
+    /// The number of clusters in the cluster heap, numbered from 2.
+    pub fn cluster_count(&self) -> u32 {
+        self.boot.cluster_count
+    }
+
+    /// The root directory.
+    pub fn root_dir(&self) -> DirEntry {
+        DirEntry {
+            name: String::new(),
+            name_hash: 0,
+            attributes: Attributes::DIRECTORY,
+            first_cluster: self.boot.root_cluster,
+            contiguous: false,
+            size: self.root_size,
+            valid_size: self.root_size,
+            created: DateTime::FAT_EPOCH,
+            modified: DateTime::FAT_EPOCH,
+            accessed: DateTime::FAT_EPOCH,
+        }
+    }
+
+    /// Iterate over the entries of the directory `dir`.
+    pub fn read_dir(&mut self, dir: &DirEntry) -> DirEntries<'_, B> {
+        DirEntries {
+            fat: self,
+            dir: dir.clone(),
+            pos: 0,
+            cursor: None,
+            done: false,
+        }
+    }
+
+    /// Find the file or directory at `path`, like `/TEST_DIR/POEM.TXT`.
+    ///
+    /// Paths start at the root directory whether or not they begin with a
+    /// `/`. Names are compared with the up-case table of the volume, so
+    /// case doesn't matter.
+    pub fn lookup(&mut self, path: &str) -> Result<DirEntry, ExFatError> {
+        // exFAT directories have no `.` or `..` entries, so remember the way
+        // back up.
+        let mut parents = Vec::new();
+        let mut entry = self.root_dir();
+        for name in path.split('/') {
+            match name {
+                "" | "." if entry.is_dir() => continue,
+                ".." if entry.is_dir() => {
+                    // The root directory is its own parent.
+                    if let Some(parent) = parents.pop() {
+                        entry = parent;
+                    }
+                }
+                _ if !entry.is_dir() => return Err(ExFatError::NotADirectory),
+                _ => {
+                    let child = self.find(&entry, name)?;
+                    parents.push(core::mem::replace(&mut entry, child));
+                }
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Like [`ExFat::lookup`], but `path` has to be a directory.
+    pub fn open_dir(&mut self, path: &str) -> Result<DirEntry, ExFatError> {
+        let dir = self.lookup(path)?;
+        if !dir.is_dir() {
+            return Err(ExFatError::NotADirectory);
+        }
+        Ok(dir)
+    }
+
+    /// Open the file at `path`.
+    pub fn open(&mut self, path: &str) -> Result<File<'_, B>, ExFatError> {
+        let entry = self.lookup(path)?;
+        if entry.is_dir() {
+            return Err(ExFatError::IsADirectory);
+        }
+        Ok(File {
+            fat: self,
+            entry,
+            pos: 0,
+            cursor: None,
+        })
+    }
+
+    /// List the directory at `path`.
+    pub fn ls(&mut self, path: &str) -> Result<(), ExFatError> {
+        let dir = self.open_dir(path)?;
+        for entry in self.read_dir(&dir) {
+            let entry = entry?;
+            let size = if entry.is_dir() {
+                "<DIR>".to_string()
+            } else {
+                entry.size().to_string()
+            };
+            println!("{}  {:>10}  {}", entry.modified(), size, entry.name());
+        }
+        Ok(())
+    }
+
+    /// Look for `name` in `dir`. The name hash in the stream extension
+    /// entry rules out most entries without comparing names.
+    fn find(&mut self, dir: &DirEntry, name: &str) -> Result<DirEntry, ExFatError> {
+        let wanted = self.upcase_name(name);
+        let hash = name_hash(&wanted);
+        let mut entries = self.read_dir(dir);
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            if entry.name_hash == hash && entries.fat.upcase_name(entry.name()) == wanted {
+                return Ok(entry);
+            }
+        }
+        Err(ExFatError::NoSuchFile)
+    }
+
+    fn upcase_name(&self, name: &str) -> Vec<u16> {
+        name.encode_utf16()
+            .map(|c| self.upcase.get(c as usize).copied().unwrap_or(c))
+            .collect()
+    }
+
+    /// Find the allocation bitmap, the up-case table and the volume label
+    /// in the root directory, and load the first two.
+    fn read_root_entries(&mut self) -> Result<(), ExFatError> {
+        let root = self.root_dir();
+        let mut cursor = None;
+        let mut bitmap = None;
+        let mut upcase = None;
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        let mut pos = 0;
+        while pos < root.size {
+            self.read_at(&root, &mut cursor, pos, &mut slot)?;
+            pos += DIR_ENTRY_SIZE as u64;
+            match slot[0] {
+                ENTRY_END => break,
+                // With two FATs there are two bitmaps, one for each.
+                ENTRY_BITMAP if (slot[1] & 1) as u32 == self.boot.active_fat() => {
+                    bitmap = Some(DirEntry::data_of(&slot))
+                }
+                ENTRY_UPCASE => upcase = Some((le32(&slot, 4), DirEntry::data_of(&slot))),
+                ENTRY_LABEL => {
+                    let len = (slot[1] as usize).min(11);
+                    let chars: Vec<u16> = (0..len).map(|i| le16(&slot, 2 + 2 * i)).collect();
+                    self.label = String::from_utf16_lossy(&chars);
+                }
+                _ => {}
+            }
+        }
+
+        let (checksum, upcase) = upcase.ok_or(ExFatError::NoUpcaseTable)?;
+        let mut table = alloc::vec![0; upcase.size as usize];
+        self.read_at(&upcase, &mut None, 0, &mut table)?;
+        if table.iter().fold(0, |sum, &b| checksum32(sum, b)) != checksum {
+            return Err(ExFatError::UpcaseChecksum);
+        }
+        self.upcase = decompress_upcase(&table);
+
+        let bitmap = bitmap.ok_or(ExFatError::NoBitmap)?;
+        let mut used = 0;
+        let mut cursor = None;
+        let mut chunk = alloc::vec![0; self.bytes_per_cluster() as usize];
+        let bits = self.cluster_count() as u64;
+        let mut pos = 0;
+        while pos * 8 < bits {
+            let n = self.read_at(&bitmap, &mut cursor, pos, &mut chunk)?;
+            if n == 0 {
+                // The bitmap is shorter than it has to be.
+                return Err(ExFatError::BrokenChain);
+            }
+            for (i, byte) in chunk[..n].iter().enumerate() {
+                // Bits past the last cluster don't count.
+                let left = bits - (pos + i as u64) * 8;
+                let byte = if left < 8 {
+                    byte & ((1 << left) - 1)
+                } else {
+                    *byte
+                };
+                used += byte.count_ones();
+            }
+            pos += n as u64;
+        }
+        self.free_clusters = self.cluster_count().saturating_sub(used);
+        Ok(())
+    }
+
+    /// Read from `entry` at `pos` into `buf`, returning how many bytes were
+    /// read. That's only less than `buf.len()` at the end of the entry.
+    ///
+    /// `cursor` remembers the last cluster read from and its index, so
+    /// reading on from there doesn't walk the chain from the start again.
+    fn read_at(
+        &mut self,
+        entry: &DirEntry,
+        cursor: &mut Option<(u32, u32)>,
+        pos: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, ExFatError> {
+        let bytes_per_cluster = self.bytes_per_cluster() as u64;
+        let len = (buf.len() as u64).min(entry.size.saturating_sub(pos)) as usize;
+        let mut done = 0;
+        while done < len {
+            let pos = pos + done as u64;
+            let offset = pos % bytes_per_cluster;
+            let n = (len - done).min((bytes_per_cluster - offset) as usize);
+            let buf = &mut buf[done..done + n];
+            // Past the valid data length there's nothing on disk worth
+            // reading, it's zeros.
+            let valid = entry.valid_size.saturating_sub(pos).min(n as u64) as usize;
+            if valid > 0 {
+                let cluster = self.cluster_at(entry, cursor, (pos / bytes_per_cluster) as u32)?;
+                let sector = self.cluster_to_sector(cluster);
+                self.read_bytes(sector, offset, &mut buf[..valid])?;
+            }
+            buf[valid..].fill(0);
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// The cluster that holds the `index`th cluster worth of `entry`.
+    fn cluster_at(
+        &mut self,
+        entry: &DirEntry,
+        cursor: &mut Option<(u32, u32)>,
+        index: u32,
+    ) -> Result<u32, ExFatError> {
+        let cluster = if entry.contiguous {
+            // NoFatChain: the clusters follow each other, and the FAT says
+            // nothing about them.
+            entry.first_cluster.checked_add(index).unwrap_or(0)
+        } else {
+            let (mut cluster, mut at) = match *cursor {
+                Some((cluster, at)) if at <= index => (cluster, at),
+                _ => (entry.first_cluster, 0),
+            };
+            while at < index {
+                cluster = self.next_cluster(cluster)?.ok_or(ExFatError::BrokenChain)?;
+                at += 1;
+            }
+            cluster
+        };
+        if !self.is_data_cluster(cluster) {
+            return Err(ExFatError::BrokenChain);
+        }
+        *cursor = Some((cluster, index));
+        Ok(cluster)
+    }
+
+    /// The cluster after `cluster` in its chain, from the active FAT. `None`
+    /// at the end of the chain, and at anything else that can't be followed.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, ExFatError> {
+        if !self.is_data_cluster(cluster) {
+            return Ok(None);
+        }
+        let fat = self.boot.fat_offset as u64
+            + self.boot.active_fat() as u64 * self.boot.fat_length as u64;
+        let mut bytes = [0; 4];
+        self.read_bytes(fat, cluster as u64 * 4, &mut bytes)?;
+        let next = u32::from_le_bytes(bytes);
+        Ok(self.is_data_cluster(next).then_some(next))
+    }
+
+    /// How many clusters there are in the chain starting at `cluster`.
+    fn chain_len(&mut self, cluster: u32) -> Result<u64, ExFatError> {
+        if !self.is_data_cluster(cluster) {
+            return Err(ExFatError::BrokenChain);
+        }
+        let mut len = 1;
+        let mut cluster = cluster;
+        while let Some(next) = self.next_cluster(cluster)? {
+            len += 1;
+            // A chain longer than the volume has to be going round in
+            // circles.
+            if len > self.cluster_count() as u64 {
+                return Err(ExFatError::BrokenChain);
+            }
+            cluster = next;
+        }
+        Ok(len)
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count() as u64 + 2).contains(&(cluster as u64))
+    }
+
+    fn bytes_per_cluster(&self) -> u32 {
+        1 << (self.boot.bytes_per_sector_shift + self.boot.sectors_per_cluster_shift)
+    }
+
+    /// Convert a cluster number to a sector number.
+    ///
+    /// Sectors are counted in `u64`s, like `volume_length`: `validate`
+    /// makes sure the cluster heap fits in the volume, but a volume can have
+    /// more than 2^32 sectors.
+    fn cluster_to_sector(&self, cluster: u32) -> u64 {
+        self.boot.cluster_heap_offset as u64
+            + ((cluster as u64 - 2) << self.boot.sectors_per_cluster_shift)
+    }
+
+    /// Read `buf.len()` bytes from `offset` bytes into `sector`, whatever
+    /// the block size of the device.
+    fn read_bytes(&mut self, sector: u64, offset: u64, buf: &mut [u8]) -> Result<(), ExFatError> {
+        let pos = (sector << self.boot.bytes_per_sector_shift) + offset;
+        read_raw(&mut self.cache, pos, buf)
+    }
+}
+
+/// Read `buf.len()` bytes from `pos` bytes into the device.
+fn read_raw<B: BlockDevice>(
+    cache: &mut BufferCache<B>,
+    mut pos: u64,
+    buf: &mut [u8],
+) -> Result<(), ExFatError> {
+    let block_size = cache.device().block_size();
+    let mut done = 0;
+    while done < buf.len() {
+        let off = (pos % block_size as u64) as usize;
+        let n = (buf.len() - done).min(block_size - off);
+        let block = cache.get((pos / block_size as u64) as usize)?;
+        buf[done..done + n].copy_from_slice(&block.read()[off..off + n]);
+        done += n;
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// One step of the checksum of the boot region and the up-case table.
+fn checksum32(sum: u32, byte: u8) -> u32 {
+    sum.rotate_right(1).wrapping_add(byte as u32)
+}
+
+/// One step of the checksum of entry sets and names.
+fn checksum16(sum: u16, byte: u8) -> u16 {
+    sum.rotate_right(1).wrapping_add(byte as u16)
+}
+
+/// The checksum of the boot region, leaving out the fields that change
+/// while the volume is in use.
+fn boot_checksum(region: &[u8]) -> u32 {
+    region
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 106 && i != 107 && i != 112)
+        .fold(0, |sum, (_, &b)| checksum32(sum, b))
+}
+
+/// The hash of an up-cased name that's kept in its stream extension entry.
+fn name_hash(upcased: &[u16]) -> u16 {
+    upcased
+        .iter()
+        .flat_map(|c| c.to_le_bytes())
+        .fold(0, checksum16)
+}
+
+/// Expand an up-case table. 0xffff followed by a count means that many
+/// characters map to themselves.
+fn decompress_upcase(table: &[u8]) -> Vec<u16> {
+    let mut upcase = Vec::new();
+    let mut values = table.chunks_exact(2).map(|c| le16(c, 0));
+    while let Some(value) = values.next() {
+        match value {
+            0xffff => {
+                let count = values.next().unwrap_or(0);
+                for _ in 0..count {
+                    upcase.push(upcase.len() as u16);
+                }
+            }
+            _ => upcase.push(value),
+        }
+        // Anything past 0xffff would be nonsense.
+        if upcase.len() > 0x10000 {
+            upcase.truncate(0x10000);
+            break;
+        }
+    }
+    upcase
+}
+
+/// The main and the backup boot regions are 12 sectors each: the boot
+/// sector, 8 extended boot sectors, the OEM parameters, a reserved sector
+/// and the checksum sector.
+const BOOT_REGION_SECTORS: u32 = 12;
+
+/// `volume_flags` bit saying which FAT and bitmap are in use.
+const VOLUME_ACTIVE_FAT: u16 = 0x0001;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+// Directory entry types. The top bit says the entry is in use, deleted ones
+// have it cleared.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xc0;
+const ENTRY_NAME: u8 = 0xc1;
+
+/// The characters of a name in each file name entry.
+const NAME_CHARS: usize = 15;
+
+/// `general_secondary_flags` bit saying a stream has no FAT chain.
+const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+/// The fields of the boot sector we use.
+#[derive(Debug, Clone, Copy)]
+struct BootSector {
+    volume_length: u64,
+    fat_offset: u32,
+    fat_length: u32,
+    cluster_heap_offset: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    serial: u32,
+    revision: u16,
+    volume_flags: u16,
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: u8,
+    fats: u8,
+}
+
+impl BootSector {
+    /// Read the boot sector `pos` bytes into the device.
+    fn read<B: BlockDevice>(cache: &mut BufferCache<B>, pos: u64) -> Result<Self, ExFatError> {
+        let mut buf = [0; 512];
+        read_raw(cache, pos, &mut buf)?;
+        if buf[..3] != [0xeb, 0x76, 0x90]
+            || &buf[3..11] != b"EXFAT   "
+            // Where the FAT12/16/32 BPB would be.
+            || buf[11..64].iter().any(|&b| b != 0)
+            || buf[510..] != [0x55, 0xaa]
+        {
+            return Err(ExFatError::InvalidBootSector);
+        }
+        let boot = BootSector {
+            volume_length: le64(&buf, 72),
+            fat_offset: le32(&buf, 80),
+            fat_length: le32(&buf, 84),
+            cluster_heap_offset: le32(&buf, 88),
+            cluster_count: le32(&buf, 92),
+            root_cluster: le32(&buf, 96),
+            serial: le32(&buf, 100),
+            revision: le16(&buf, 104),
+            volume_flags: le16(&buf, 106),
+            bytes_per_sector_shift: buf[108],
+            sectors_per_cluster_shift: buf[109],
+            fats: buf[110],
+        };
+        // Needed to find the rest of the boot region.
+        if !(9..=12).contains(&boot.bytes_per_sector_shift) {
+            return Err(ExFatError::InvalidVolume(VolumeError::BytesPerSectorShift(
+                boot.bytes_per_sector_shift,
+            )));
+        }
+        Ok(boot)
+    }
+
+    /// Read the boot sector `pos` bytes into the device, and check the boot
+    /// region it starts.
+    fn read_region<B: BlockDevice>(
+        cache: &mut BufferCache<B>,
+        pos: u64,
+    ) -> Result<Self, ExFatError> {
+        let boot = Self::read(cache, pos)?;
+        if !boot.checksum_ok(cache, pos)? {
+            return Err(ExFatError::BootChecksum);
+        }
+        Ok(boot)
+    }
+
+    /// The backup boot region, which is right after the main one. Where
+    /// that is depends on the sector size, and the main boot sector can't
+    /// be trusted about it, so try each one.
+    fn read_backup<B: BlockDevice>(cache: &mut BufferCache<B>) -> Option<Self> {
+        (9..=12).find_map(|shift| {
+            let boot = Self::read_region(cache, (BOOT_REGION_SECTORS as u64) << shift).ok()?;
+            (boot.bytes_per_sector_shift == shift).then_some(boot)
+        })
+    }
+
+    /// Whether the boot region this boot sector came from, `pos` bytes into
+    /// the device, matches the checksum at its end.
+    fn checksum_ok<B: BlockDevice>(
+        &self,
+        cache: &mut BufferCache<B>,
+        pos: u64,
+    ) -> Result<bool, ExFatError> {
+        let sector_size = 1 << self.bytes_per_sector_shift;
+        let mut region = alloc::vec![0; BOOT_REGION_SECTORS as usize * sector_size];
+        read_raw(cache, pos, &mut region)?;
+        let (region, checksums) = region.split_at((BOOT_REGION_SECTORS as usize - 1) * sector_size);
+        let checksum = boot_checksum(region);
+        Ok(checksums.chunks_exact(4).all(|c| le32(c, 0) == checksum))
+    }
+
+    fn validate(&self, device_size: u64) -> Result<(), VolumeError> {
+        let sector_shift = self.bytes_per_sector_shift;
+        if !(9..=12).contains(&sector_shift) {
+            return Err(VolumeError::BytesPerSectorShift(sector_shift));
+        }
+        if sector_shift + self.sectors_per_cluster_shift > 25 {
+            return Err(VolumeError::SectorsPerClusterShift(
+                self.sectors_per_cluster_shift,
+            ));
+        }
+        if self.fats != 1 && self.fats != 2 {
+            return Err(VolumeError::Fats(self.fats));
+        }
+        if self.revision >> 8 != 1 {
+            return Err(VolumeError::Revision(self.revision));
+        }
+
+        let length = self.volume_length;
+        if length < (1 << 20) >> sector_shift || length > device_size >> sector_shift {
+            return Err(VolumeError::VolumeLength(length));
+        }
+        if self.fat_offset < 2 * BOOT_REGION_SECTORS {
+            return Err(VolumeError::FatOffset(self.fat_offset));
+        }
+        if ((self.fat_length as u64) << sector_shift) < (self.cluster_count as u64 + 2) * 4 {
+            return Err(VolumeError::FatTooSmall);
+        }
+        let heap = self.cluster_heap_offset as u64;
+        if heap < self.fat_offset as u64 + self.fat_length as u64 * self.fats as u64
+            || heap + ((self.cluster_count as u64) << self.sectors_per_cluster_shift) > length
+        {
+            return Err(VolumeError::ClusterHeap(self.cluster_heap_offset));
+        }
+
+        let root = self.root_cluster;
+        if root < 2 || root as u64 >= self.cluster_count as u64 + 2 {
+            return Err(VolumeError::RootCluster(root));
+        }
+        if self.active_fat() >= self.fats as u32 {
+            return Err(VolumeError::ActiveFat);
+        }
+        Ok(())
+    }
+
+    /// The FAT and allocation bitmap in use.
+    fn active_fat(&self) -> u32 {
+        (self.volume_flags & VOLUME_ACTIVE_FAT) as u32
+    }
+}
+
+/// A file or directory, as described by its directory entry set.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DirEntry {
+    name: String,
+    /// The hash of the up-cased name, from the stream extension entry.
+    name_hash: u16,
+    attributes: Attributes,
+    first_cluster: u32,
+    /// The clusters follow each other from `first_cluster`, and aren't in
+    /// the FAT (NoFatChain).
+    contiguous: bool,
+    size: u64,
+    /// How much of `size` has been written, the rest reads as zeros.
+    valid_size: u64,
+    created: DateTime,
+    modified: DateTime,
+    accessed: DateTime,
+}
+
+impl DirEntry {
+    /// Decode an entry set: a file entry, then a stream extension entry and
+    /// file name entries. `None` if the set doesn't hold together.
+    fn from_set(set: &[[u8; DIR_ENTRY_SIZE]]) -> Option<Self> {
+        let (file, stream) = (set.first()?, set.get(1)?);
+        if file[0] != ENTRY_FILE || set.len() != file[1] as usize + 1 || stream[0] != ENTRY_STREAM {
+            return None;
+        }
+        let checksum = set
+            .iter()
+            .flatten()
+            .enumerate()
+            // The checksum itself.
+            .filter(|&(i, _)| i != 2 && i != 3)
+            .fold(0, |sum, (_, &b)| checksum16(sum, b));
+        if checksum != le16(file, 2) {
+            return None;
+        }
+
+        // The name entries have to come right after the stream extension,
+        // anything else after them is for someone else.
+        let name_len = stream[3] as usize;
+        let names = &set[2..];
+        if name_len == 0
+            || names.len() * NAME_CHARS < name_len
+            || names[..div_ceil(name_len, NAME_CHARS)]
+                .iter()
+                .any(|e| e[0] != ENTRY_NAME)
+        {
+            return None;
+        }
+        let chars: Vec<u16> = names
+            .iter()
+            .flat_map(|e| (0..NAME_CHARS).map(move |i| le16(e, 2 + 2 * i)))
+            .take(name_len)
+            .collect();
+
+        // NOTE: Timestamps also have a UTC offset, FAT ones don't, and we
+        // don't know our own time zone anyway.
+        let time = |at: usize, tens: Option<usize>| {
+            let stamp = le32(file, at);
+            let tens = tens.map_or(0, |at| file[at]);
+            DateTime::from_fat((stamp >> 16) as u16, stamp as u16, tens)
+        };
+        Some(DirEntry {
+            name: String::from_utf16_lossy(&chars),
+            name_hash: le16(stream, 4),
+            attributes: Attributes::from_bits_truncate(le16(file, 4) as u8) - Attributes::VOLUME_ID,
+            first_cluster: le32(stream, 20),
+            contiguous: stream[1] & STREAM_NO_FAT_CHAIN != 0,
+            size: le64(stream, 24),
+            valid_size: le64(stream, 8).min(le64(stream, 24)),
+            created: time(8, Some(20)),
+            modified: time(12, Some(21)),
+            accessed: time(16, None),
+        })
+    }
+
+    /// The data of a bitmap or up-case table entry, which has no name and
+    /// always a FAT chain.
+    fn data_of(slot: &[u8; DIR_ENTRY_SIZE]) -> Self {
+        let size = le64(slot, 24);
+        DirEntry {
+            name: String::new(),
+            name_hash: 0,
+            attributes: Attributes::SYSTEM,
+            first_cluster: le32(slot, 20),
+            contiguous: false,
+            size,
+            valid_size: size,
+            created: DateTime::FAT_EPOCH,
+            modified: DateTime::FAT_EPOCH,
+            accessed: DateTime::FAT_EPOCH,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// The size in bytes. Directories have one too.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 0 for an empty file.
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    pub fn created(&self) -> DateTime {
+        self.created
+    }
+
+    pub fn modified(&self) -> DateTime {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> DateTime {
+        self.accessed
+    }
+}
+
+fn div_ceil(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+pub struct DirEntries<'f, B>
+where
+    B: BlockDevice,
+{
+    fat: &'f mut ExFat<B>,
+    dir: DirEntry,
+    /// Where the next entry is, in bytes from the start of the directory.
+    pos: u64,
+    cursor: Option<(u32, u32)>,
+    done: bool,
+}
+
+impl<B> DirEntries<'_, B>
+where
+    B: BlockDevice,
+{
+    /// The next 32 byte entry, `None` at the end of the directory.
+    fn next_slot(&mut self) -> Result<Option<[u8; DIR_ENTRY_SIZE]>, ExFatError> {
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        if self
+            .fat
+            .read_at(&self.dir, &mut self.cursor, self.pos, &mut slot)?
+            < slot.len()
+        {
+            return Ok(None);
+        }
+        self.pos += DIR_ENTRY_SIZE as u64;
+        Ok(Some(slot))
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, ExFatError> {
+        while !self.done {
+            let slot = match self.next_slot()? {
+                Some(slot) => slot,
+                None => break,
+            };
+            match slot[0] {
+                ENTRY_END => self.done = true,
+                ENTRY_FILE => {
+                    let start = self.pos;
+                    let mut set = alloc::vec![slot];
+                    while set.len() <= slot[1] as usize {
+                        match self.next_slot()? {
+                            Some(slot) => set.push(slot),
+                            None => break,
+                        }
+                    }
+                    match DirEntry::from_set(&set) {
+                        Some(entry) => return Ok(Some(entry)),
+                        None => {
+                            // Whatever came after the file entry might
+                            // still make sense on its own.
+                            warn!(
+                                "exFAT: skipping a broken entry set in {:?}",
+                                self.dir.name()
+                            );
+                            self.pos = start;
+                        }
+                    }
+                }
+                // The bitmap, the up-case table, the label, deleted entries
+                // and whatever else we don't know about.
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<B> Iterator for DirEntries<'_, B>
+where
+    B: BlockDevice,
+{
+    type Item = Result<DirEntry, ExFatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Don't keep going after an error.
+            self.done = true;
+        }
+        entry.transpose()
+    }
+}
+
+/// An open file, made by [`ExFat::open`].
+pub struct File<'f, B>
+where
+    B: BlockDevice,
+{
+    fat: &'f mut ExFat<B>,
+    entry: DirEntry,
+    pos: u64,
+    /// The last cluster we read from, and its index in the chain.
+    cursor: Option<(u32, u32)>,
+}
+
+impl<B> File<'_, B>
+where
+    B: BlockDevice,
+{
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    /// The size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.entry.size()
+    }
+
+    /// Where the next read starts.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Move the position. It's fine to go past the end, reads there just
+    /// return nothing.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, ExFatError> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i128,
+            SeekFrom::Current(off) => self.pos as i128 + off as i128,
+            SeekFrom::End(off) => self.size() as i128 + off as i128,
+        };
+        self.pos = u64::try_from(pos).map_err(|_| ExFatError::InvalidSeek)?;
+        Ok(self.pos)
+    }
+
+    /// Read from the current position into `buf`, returning how many bytes
+    /// were read. That's only less than `buf.len()` at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ExFatError> {
+        let n = self
+            .fat
+            .read_at(&self.entry, &mut self.cursor, self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[test_case]
+fn test_exfat_mounts_test_image() {
+    let exfat = ExFat::new(crate::ramdisk::exfat_test_image()).unwrap();
+    assert_eq!(exfat.check_fs(), Ok(()));
+    assert_eq!(exfat.uuid(), "1234-ABCD");
+    assert_eq!(exfat.label(), "TRANSPARENT");
+    assert_eq!(exfat.cluster_count(), 1000);
+    // Two bitmaps, the up-case table, 2 clusters of root directory, 4 of
+    // README.TXT, TEST_DIR and its 4 of POEM.TXT, 3 more files in 4
+    // clusters, and FILE1.TXT to FILE40.TXT.
+    assert_eq!(exfat.free_clusters(), 1000 - (3 + 2 + 4 + 1 + 4 + 4 + 40));
+    assert_eq!(exfat.root_dir().size(), 2 * 4096);
+}
+
+#[test_case]
+fn test_exfat_read_root_dir() {
+    let mut exfat = ExFat::new(crate::ramdisk::exfat_test_image()).unwrap();
+    let root = exfat.root_dir();
+    let entries: Vec<_> = exfat.read_dir(&root).collect::<Result<_, _>>().unwrap();
+
+    // No bitmap, up-case table, label or deleted file, and the entry sets
+    // go on into the second cluster.
+    assert_eq!(entries.len(), 6 + 40);
+    let names: Vec<_> = entries[..6].iter().map(|e| e.name()).collect();
+    assert_eq!(
+        names,
+        [
+            "README.TXT",
+            "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt",
+            "TEST_DIR",
+            "Ünïcödé.txt",
+            "SPARSE.BIN",
+            "EMPTY.TXT",
+        ]
+    );
+    assert_eq!(entries[45].name(), "FILE40.TXT");
+
+    assert_eq!(entries[0].size(), 16000);
+    assert_eq!(entries[0].first_cluster(), 8);
+    assert_eq!(entries[0].attributes(), Attributes::ARCHIVE);
+    assert!(entries[2].is_dir());
+    let stamp = DateTime {
+        year: 2023,
+        month: 3,
+        day: 14,
+        hour: 12,
+        minute: 34,
+        second: 56,
+        millis: 0,
+    };
+    assert_eq!(entries[0].modified(), stamp);
+    assert_eq!(entries[0].accessed(), stamp);
+    assert_eq!(
+        entries[0].created(),
+        DateTime {
+            second: 57,
+            ..stamp
+        }
+    );
+}
+
+#[test_case]
+fn test_exfat_lookup() {
+    let mut exfat = ExFat::new(crate::ramdisk::exfat_test_image()).unwrap();
+    assert_eq!(exfat.lookup("/").unwrap(), exfat.root_dir());
+    assert_eq!(exfat.lookup("/readme.TXT").unwrap().first_cluster(), 8);
+    assert_eq!(
+        exfat.lookup("TEST_DIR/POEM.TXT").unwrap().first_cluster(),
+        11
+    );
+    assert_eq!(
+        exfat
+            .lookup("/test_dir/../TEST_DIR/./poem.txt")
+            .unwrap()
+            .size(),
+        14000
+    );
+    assert_eq!(exfat.lookup("/../..").unwrap(), exfat.root_dir());
+    // Names that aren't ASCII go through the up-case table too.
+    assert_eq!(exfat.lookup("/ÜNÏCÖDÉ.TXT").unwrap().first_cluster(), 16);
+    assert_eq!(exfat.lookup("/FILE40.txt").unwrap().first_cluster(), 59);
+
+    assert_eq!(exfat.lookup("/NOPE.TXT"), Err(ExFatError::NoSuchFile));
+    assert_eq!(
+        exfat.lookup("/README.TXT/POEM.TXT"),
+        Err(ExFatError::NotADirectory)
+    );
+    assert_eq!(
+        exfat.open_dir("/README.TXT"),
+        Err(ExFatError::NotADirectory)
+    );
+    assert_eq!(
+        exfat.open("/TEST_DIR").err(),
+        Some(ExFatError::IsADirectory)
+    );
+    assert_eq!(exfat.ls("/TEST_DIR"), Ok(()));
+}
+
+#[test_case]
+fn test_exfat_read_file() {
+    let mut exfat = ExFat::new(crate::ramdisk::exfat_test_image()).unwrap();
+    let lines = |name: &str| -> Vec<u8> {
+        (0..1000)
+            .flat_map(|i| format!("{} line {:03}\n", name, i).into_bytes())
+            .collect()
+    };
+    let mut data = alloc::vec![0; 20000];
+
+    // A FAT chain that goes backwards.
+    let readme = lines("README");
+    let mut file = exfat.open("/README.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(readme.len()));
+    assert_eq!(&data[..readme.len()], &readme[..]);
+    assert_eq!(file.read(&mut data), Ok(0));
+    assert_eq!(file.seek(SeekFrom::End(-16)), Ok(15984));
+    assert_eq!(file.read(&mut data[..100]), Ok(16));
+    assert_eq!(&data[..16], b"README line 999\n");
+    assert_eq!(file.position(), 16000);
+    assert_eq!(file.entry().first_cluster(), 8);
+    assert_eq!(
+        file.seek(SeekFrom::Current(-20000)),
+        Err(ExFatError::InvalidSeek)
+    );
+
+    // NoFatChain, with nothing in the FAT to follow.
+    let poem = lines("POEM");
+    let mut file = exfat.open("/TEST_DIR/POEM.TXT").unwrap();
+    assert_eq!(file.seek(SeekFrom::Start(4090)), Ok(4090));
+    assert_eq!(file.read(&mut data), Ok(poem.len() - 4090));
+    assert_eq!(&data[..poem.len() - 4090], &poem[4090..]);
+
+    let mut file = exfat.open("/ünïcödé.txt").unwrap();
+    assert_eq!(file.read(&mut data), Ok(10));
+    assert_eq!(&data[..10], "ünicöde\n".as_bytes());
+    let mut file = exfat.open("/FILE40.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(24));
+    assert_eq!(&data[..24], b"hey, my name is file 40\n");
+    let mut file = exfat.open("/EMPTY.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(0));
+
+    // Only the first 100 bytes have been written, the rest reads as zeros
+    // whatever is on disk.
+    let mut file = exfat.open("/SPARSE.BIN").unwrap();
+    assert_eq!(file.read(&mut data), Ok(8192));
+    assert!(data[..100].iter().all(|&b| b == b'x'));
+    assert!(data[100..8192].iter().all(|&b| b == 0));
+}
+
+#[test_case]
+fn test_exfat_rejects_bad_volumes() {
+    let mount = |corrupt: &dyn Fn(&mut [u8])| {
+        let mut disk = crate::ramdisk::exfat_test_image();
+        let mut region = alloc::vec![0; 24 * 512];
+        disk.read_blocks(0, &mut region).unwrap();
+        corrupt(&mut region);
+        disk.write_blocks(0, &region).unwrap();
+        ExFat::new(disk)
+    };
+    // Change a field and fix up the checksum of a boot region.
+    let patch = |region: &mut [u8], at: usize, bytes: &[u8]| {
+        region[at..at + bytes.len()].copy_from_slice(bytes);
+        let checksum = boot_checksum(&region[..11 * 512]);
+        for c in region[11 * 512..12 * 512].chunks_exact_mut(4) {
+            c.copy_from_slice(&checksum.to_le_bytes());
+        }
+    };
+
+    // The fields left out of the checksum can change.
+    assert!(mount(&|r| r[112] = 42).is_ok());
+    // A corrupt main boot region, but the backup is fine.
+    let exfat = mount(&|r| r[100] = 0).unwrap();
+    assert_eq!(exfat.uuid(), "1234-ABCD");
+    assert_eq!(
+        mount(&|r| {
+            r[100] = 0;
+            r[12 * 512 + 100] = 0;
+        })
+        .err(),
+        Some(ExFatError::BootChecksum)
+    );
+    // A main boot sector that isn't one at all, or has a sector size that
+    // would put the backup somewhere else.
+    let exfat = mount(&|r| r[510] = 0).unwrap();
+    assert_eq!(exfat.uuid(), "1234-ABCD");
+    assert!(mount(&|r| r[3] = b'N').is_ok());
+    assert!(mount(&|r| patch(r, 108, &[12])).is_ok());
+    assert!(mount(&|r| patch(r, 108, &[13])).is_ok());
+    assert_eq!(
+        mount(&|r| {
+            r[3] = b'N';
+            r[12 * 512 + 3] = b'N';
+        })
+        .err(),
+        Some(ExFatError::InvalidBootSector)
+    );
+
+    // Both boot regions, so that the backup doesn't save the day.
+    let invalid = |at: usize, bytes: &[u8]| {
+        mount(&|r| {
+            patch(&mut r[..12 * 512], at, bytes);
+            patch(&mut r[12 * 512..], at, bytes);
+        })
+        .err()
+    };
+    let volume = |e| Some(ExFatError::InvalidVolume(e));
+    assert_eq!(
+        invalid(108, &[13]),
+        volume(VolumeError::BytesPerSectorShift(13))
+    );
+    assert_eq!(
+        invalid(109, &[17]),
+        volume(VolumeError::SectorsPerClusterShift(17))
+    );
+    assert_eq!(invalid(110, &[3]), volume(VolumeError::Fats(3)));
+    assert_eq!(invalid(104, &[0, 2]), volume(VolumeError::Revision(0x200)));
+    assert_eq!(
+        invalid(96, &[1, 0, 0, 0]),
+        volume(VolumeError::RootCluster(1))
+    );
+    assert_eq!(invalid(84, &[1, 0, 0, 0]), volume(VolumeError::FatTooSmall));
+    assert_eq!(
+        invalid(88, &[40, 0, 0, 0]),
+        volume(VolumeError::ClusterHeap(40))
+    );
+    assert_eq!(
+        invalid(72, &u64::MAX.to_le_bytes()),
+        volume(VolumeError::VolumeLength(u64::MAX))
+    );
+    assert_eq!(invalid(110, &[1]), volume(VolumeError::ActiveFat));
+}
+
+#[test_case]
+fn test_exfat_checks_upcase_table() {
+    let exfat = ExFat::new(crate::ramdisk::exfat_test_image()).unwrap();
+    // The up-case table is in cluster 3.
+    let sector = exfat.cluster_to_sector(3) as usize;
+    let mut disk = crate::ramdisk::exfat_test_image();
+    let mut buf = [0; 512];
+    disk.read(sector, &mut buf).unwrap();
+    buf[2 * b'a' as usize] = b'a';
+    disk.write(sector, &buf).unwrap();
+    assert_eq!(ExFat::new(disk).err(), Some(ExFatError::UpcaseChecksum));
+}
@@ -1,174 +1,2435 @@
-#![allow(unused)]
-use alloc::vec::Vec;
-use log::{debug, info, warn};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use bitflags::bitflags;
+use log::{info, warn};
 
-use crate::{block::BlockDevice, println};
+use crate::{
+    bcache::{BufferCache, CacheStats},
+    block::{BlockDevice, BlockError},
+    println,
+};
 
+/// How many blocks the buffer cache of a volume holds.
+const CACHED_BLOCKS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// The underlying block device failed.
+    Block(BlockError),
+
+    /// Sector 0 doesn't hold a FAT boot sector.
+    InvalidBootSector,
+
+    /// The boot sector describes a volume we can't make sense of.
+    InvalidVolume(VolumeError),
+
+    /// There's no such volume.
+    NotFound,
+
+    /// There's no such file or directory.
+    NoSuchFile,
+
+    /// A path goes through something that isn't a directory.
+    NotADirectory,
+
+    /// Tried to open a directory as a file.
+    IsADirectory,
+
+    /// Tried to seek before the start of a file, or too far past it.
+    InvalidSeek,
+
+    /// A cluster chain ends before the data it should hold does.
+    BrokenChain,
+
+    /// There are no free clusters left.
+    NoSpace,
+
+    /// There's already a file or directory by that name.
+    AlreadyExists,
+
+    /// Tried to remove a directory that still has something in it.
+    DirectoryNotEmpty,
+
+    /// The name can't be stored in a FAT directory, or can't be used for
+    /// what was asked, like moving a directory into itself.
+    InvalidName,
+
+    /// The volume wasn't unmounted cleanly and the check at mount time found
+    /// problems. It can't be written to until `fsck` repairs them.
+    NeedsRepair,
+}
+
+impl From<BlockError> for FatError {
+    fn from(e: BlockError) -> Self {
+        FatError::Block(e)
+    }
+}
+
+/// Which kind of FAT a volume has. It only depends on the number of
+/// clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn from_cluster_count(clusters: u32) -> Self {
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+}
+
+/// What's wrong with the geometry in a boot sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeError {
+    /// The boot sector doesn't start with a jump instruction.
+    JumpBoot([u8; 3]),
+
+    /// Sectors are 512, 1024, 2048 or 4096 bytes.
+    BytesPerSector(u16),
+
+    /// Clusters are a power of two from 1 to 128 sectors.
+    SectorsPerCluster(u8),
+
+    /// The boot sector itself is reserved, so there's at least one.
+    NoReservedSectors,
+
+    NoFats,
+
+    Media(u8),
+
+    /// The BPB fields don't fit the FAT type the cluster count makes the
+    /// volume, like a FAT32 volume with a fixed root directory.
+    FatType(FatType),
+
+    /// We only know version 0.0.
+    Version(u16),
+
+    /// The volume is bigger than the device, or has no room for data.
+    TotalSectors(u32),
+
+    /// The FAT can't hold an entry for every cluster.
+    FatTooSmall,
+
+    RootCluster(u32),
+
+    /// The FSInfo sector isn't in the reserved region.
+    FsInfoSector(u16),
+
+    /// `extended_flags` makes a FAT that doesn't exist the active one.
+    ActiveFat(u8),
+}
+
+/// A FAT volume. Despite the name, FAT12 and FAT16 work too.
 pub struct Fat32<B>
 where
     B: BlockDevice,
 {
-    block: B,
-    bpb: BiosParameterBlockPacked,
+    cache: BufferCache<B>,
+    bpb: BiosParameterBlockPacked,
+    fat_type: FatType,
+    volume_id: u32,
+    volume_label: [u8; 11],
+    fsinfo: FsInfo,
+    /// Where modification times come from.
+    clock: fn() -> DateTime,
+    /// Whether FAT[1] on disk says the volume is in use. It's set before
+    /// the first write and cleared again by `unmount`.
+    dirty: bool,
+    /// The volume was dirty when mounted and isn't consistent, so writes are
+    /// refused until it's repaired.
+    needs_repair: bool,
+}
+
+impl<B> Fat32<B>
+where
+    B: BlockDevice,
+{
+    pub fn new(mut block: B) -> Result<Self, FatError> {
+        let mut buf = alloc::vec![0; block.block_size()];
+        block.read(0, &mut buf)?;
+        if buf.len() < 512 || buf[510] != 0x55 || buf[511] != 0xaa {
+            return Err(FatError::InvalidBootSector);
+        }
+        let superblock =
+            BiosParameterBlockPacked::from_bytes(&buf).map_err(|_| FatError::InvalidBootSector)?;
+        let device_size = block.block_count() as u64 * block.block_size() as u64;
+        superblock
+            .validate(device_size)
+            .map_err(FatError::InvalidVolume)?;
+        let fat_type = superblock.fat_type();
+        // The extended boot record comes after the FAT32 fields, if there
+        // are any.
+        let ebr = if fat_type == FatType::Fat32 { 64 } else { 36 };
+        let mut volume_label = [0; 11];
+        volume_label.copy_from_slice(&buf[ebr + 7..ebr + 18]);
+        let mut fat32 = Fat32 {
+            cache: BufferCache::new(block, CACHED_BLOCKS),
+            bpb: superblock,
+            fat_type,
+            volume_id: u32::from_le_bytes(buf[ebr + 3..ebr + 7].try_into().unwrap()),
+            volume_label,
+            fsinfo: FsInfo::UNKNOWN,
+            clock: || DateTime::FAT_EPOCH,
+            dirty: false,
+            needs_repair: false,
+        };
+        fat32.fsinfo = fat32.read_fsinfo()?;
+
+        // A crash in the middle of an update can leave clusters nothing uses,
+        // so don't write anything to the volume before it's been checked.
+        if !fat32.is_clean()? {
+            warn!("fat32: volume wasn't unmounted cleanly, checking it");
+            fat32.dirty = true;
+            let report = fat32.fsck(false)?;
+            info!("{}", report);
+            if !report.is_clean() {
+                warn!("fat32: volume needs repair, not writing to it until then");
+                fat32.needs_repair = true;
+            }
+        }
+        Ok(fat32)
+    }
+
+    /// Set where the times recorded in directory entries come from.
+    ///
+    /// NOTE: We have no RTC driver yet, so by default everything is written
+    /// at 1980-01-01 00:00:00.
+    pub fn set_clock(&mut self, clock: fn() -> DateTime) {
+        self.clock = clock;
+    }
+
+    /// The underlying block device.
+    pub fn device(&self) -> &B {
+        self.cache.device()
+    }
+
+    /// The volume serial number, formatted like `blkid` does (`1234-ABCD`).
+    pub fn uuid(&self) -> String {
+        let id = self.volume_id;
+        format!("{:04X}-{:04X}", id >> 16, id & 0xffff)
+    }
+
+    /// The volume label from the boot sector, without the padding.
+    pub fn label(&self) -> String {
+        let label = self.volume_label;
+        String::from_utf8_lossy(&label).trim_end().to_string()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Check the geometry in the boot sector makes sense. `new` already
+    /// refuses volumes that don't pass, this logs what the volume looks like.
+    pub fn check_fs(&self) -> Result<(), VolumeError> {
+        info!("Checking {:?} filesystem", self.fat_type);
+        info!("{:?}", self.bpb);
+
+        let device = self.device();
+        self.bpb
+            .validate(device.block_count() as u64 * device.block_size() as u64)?;
+
+        info!(
+            "{} clusters of {} bytes, data from sector 0x{:x}, FAT {} active",
+            self.cluster_count(),
+            self.bytes_per_cluster(),
+            self.first_data_sector(),
+            self.bpb
+                .active_fat()
+                .map_or("all".to_string(), |fat| fat.to_string()),
+        );
+        info!("Check passed");
+        Ok(())
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// The root directory.
+    pub fn root_dir(&self) -> DirEntry {
+        match self.fat_type {
+            FatType::Fat32 => DirEntry::root(self.bpb.root_cluster()),
+            _ => DirEntry::root(FIXED_ROOT),
+        }
+    }
+
+    fn first_data_sector(&self) -> u32 {
+        self.bpb.first_data_sector()
+    }
+
+    /// Convert a cluster number to a sector number.
+    fn cluster_to_sector(&self, cluster_no: u32) -> u32 {
+        if cluster_no == FIXED_ROOT {
+            return self.first_data_sector() - self.bpb.root_dir_sectors();
+        }
+        self.first_data_sector() + (cluster_no - 2) * self.bpb.sectors_per_cluster() as u32
+    }
+
+    /// Where FAT number `fat` starts.
+    fn fat_start(&self, fat: u32) -> u32 {
+        self.bpb.reserved_sectors() as u32 + fat * self.bpb.sectors_per_fat()
+    }
+
+    /// Read `buf.len()` bytes, starting `offset` bytes into sector `sector`.
+    ///
+    /// Sectors don't have to be the size of the device's blocks, so this
+    /// works out which blocks the bytes are in.
+    fn read_bytes(&mut self, sector: u32, offset: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        let block_size = self.device().block_size();
+        let mut pos = sector as u64 * self.bpb.bytes_per_sector() as u64 + offset as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let off = (pos % block_size as u64) as usize;
+            let n = (buf.len() - done).min(block_size - off);
+            let block = self.cache.get((pos / block_size as u64) as usize)?;
+            buf[done..done + n].copy_from_slice(&block.read()[off..off + n]);
+            done += n;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Like `read_bytes`, but the other way around.
+    ///
+    /// The first write marks the volume as in use.
+    fn write_bytes(&mut self, sector: u32, offset: u32, buf: &[u8]) -> Result<(), FatError> {
+        if self.needs_repair {
+            return Err(FatError::NeedsRepair);
+        }
+        if !self.dirty {
+            self.mark_dirty()?;
+        }
+        let block_size = self.device().block_size();
+        let mut pos = sector as u64 * self.bpb.bytes_per_sector() as u64 + offset as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let off = (pos % block_size as u64) as usize;
+            let n = (buf.len() - done).min(block_size - off);
+            let block = self.cache.get((pos / block_size as u64) as usize)?;
+            block.write()[off..off + n].copy_from_slice(&buf[done..done + n]);
+            done += n;
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Write back everything cached and flush the device.
+    pub fn sync(&mut self) -> Result<(), FatError> {
+        // The FSInfo sector can't have changed if nothing was written.
+        if self.dirty && !self.needs_repair {
+            self.write_fsinfo()?;
+        }
+        Ok(self.cache.sync()?)
+    }
+
+    /// Write everything back and mark the volume as cleanly unmounted.
+    ///
+    /// Dropping the volume writes back what's cached as well, but leaves it
+    /// marked as in use, so the next mount checks it.
+    pub fn unmount(mut self) -> Result<(), FatError> {
+        self.sync()?;
+        if self.dirty && !self.needs_repair {
+            self.set_clean(true)?;
+            self.cache.sync()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Whether writes are refused until `fsck` repairs the volume.
+    pub fn needs_repair(&self) -> bool {
+        self.needs_repair
+    }
+
+    /// Make sure everything written so far is on disk before anything that
+    /// comes after it.
+    ///
+    /// Updates are ordered so that a crash in the middle of one can only
+    /// leave clusters that nothing uses, never an entry pointing at clusters
+    /// that aren't its own:
+    ///
+    /// - clusters are allocated in the FAT before data goes in them, and the
+    ///   data is written before the directory entry that points at it,
+    /// - going the other way, an entry lets go of clusters before they're
+    ///   freed.
+    fn barrier(&mut self) -> Result<(), FatError> {
+        Ok(self.cache.sync()?)
+    }
+
+    /// Mark the volume as in use in FAT[1], before anything else is written.
+    fn mark_dirty(&mut self) -> Result<(), FatError> {
+        self.dirty = true;
+        self.set_clean(false)?;
+        self.barrier()
+    }
+
+    /// The clean bit of FAT[1], for the FAT types that have one.
+    fn clean_bit(&self) -> Option<u32> {
+        match self.fat_type {
+            FatType::Fat12 => None,
+            FatType::Fat16 => Some(FAT16_CLEAN),
+            FatType::Fat32 => Some(FAT32_CLEAN),
+        }
+    }
+
+    /// Whether FAT[1] says the volume was unmounted cleanly. FAT12 volumes
+    /// always were, as far as we can tell.
+    fn is_clean(&mut self) -> Result<bool, FatError> {
+        match self.clean_bit() {
+            Some(bit) => Ok(self.read_fat_entry(1)? & bit != 0),
+            None => Ok(true),
+        }
+    }
+
+    fn set_clean(&mut self, clean: bool) -> Result<(), FatError> {
+        let bit = match self.clean_bit() {
+            Some(bit) => bit,
+            None => return Ok(()),
+        };
+        let entry = self.read_fat_entry(1)?;
+        let entry = if clean { entry | bit } else { entry & !bit };
+        self.set_fat_entry(1, entry)
+    }
+
+    /// How many free clusters there are, if the FSInfo sector knows.
+    pub fn free_clusters(&self) -> Option<u32> {
+        (self.fsinfo.free_count != FsInfo::UNKNOWN.free_count).then_some(self.fsinfo.free_count)
+    }
+
+    /// The number of data clusters, numbered from 2.
+    fn cluster_count(&self) -> u32 {
+        self.bpb.cluster_count()
+    }
+
+    /// Read the FSInfo sector, forgetting whatever it says if it doesn't
+    /// look right.
+    fn read_fsinfo(&mut self) -> Result<FsInfo, FatError> {
+        // Only FAT32 has one.
+        if self.fat_type != FatType::Fat32 {
+            return Ok(FsInfo::UNKNOWN);
+        }
+        let sector = match self.bpb.fsinfo_sector() {
+            0 | 0xffff => return Ok(FsInfo::UNKNOWN),
+            sector => sector as u32,
+        };
+        let mut block = [0; 512];
+        self.read_bytes(sector, 0, &mut block)?;
+        let u32_at =
+            |i: usize| u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+        if u32_at(0) != FSINFO_LEAD_SIG
+            || u32_at(484) != FSINFO_STRUCT_SIG
+            || u32_at(508) != FSINFO_TRAIL_SIG
+        {
+            warn!("fat32: bad FSInfo sector, ignoring it");
+            return Ok(FsInfo::UNKNOWN);
+        }
+        let mut fsinfo = FsInfo {
+            free_count: u32_at(488),
+            next_free: u32_at(492),
+        };
+        if fsinfo.free_count > self.cluster_count() {
+            fsinfo.free_count = FsInfo::UNKNOWN.free_count;
+        }
+        Ok(fsinfo)
+    }
+
+    fn write_fsinfo(&mut self) -> Result<(), FatError> {
+        if self.fat_type != FatType::Fat32 {
+            return Ok(());
+        }
+        let sector = match self.bpb.fsinfo_sector() {
+            0 | 0xffff => return Ok(()),
+            sector => sector as u32,
+        };
+        let mut block = [0; 512];
+        self.read_bytes(sector, 0, &mut block)?;
+        block[0..4].copy_from_slice(&FSINFO_LEAD_SIG.to_le_bytes());
+        block[484..488].copy_from_slice(&FSINFO_STRUCT_SIG.to_le_bytes());
+        block[488..492].copy_from_slice(&self.fsinfo.free_count.to_le_bytes());
+        block[492..496].copy_from_slice(&self.fsinfo.next_free.to_le_bytes());
+        block[508..512].copy_from_slice(&FSINFO_TRAIL_SIG.to_le_bytes());
+        self.write_bytes(sector, 0, &block)
+    }
+
+    /// Find a free cluster, starting where the last search left off, and
+    /// mark it as the end of a chain. It's linked after `prev` if given.
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, FatError> {
+        let count = self.cluster_count();
+        let hint = match self.fsinfo.next_free {
+            hint if (2..count + 2).contains(&hint) => hint,
+            _ => 2,
+        };
+        for i in 0..count {
+            let cluster = 2 + (hint - 2 + i) % count;
+            if self.get_fat_entry(cluster)?.is_free() {
+                self.set_fat_entry(cluster, FAT_EOC)?;
+                if let Some(prev) = prev {
+                    self.set_fat_entry(prev, cluster)?;
+                }
+                if let Some(free) = self.free_clusters() {
+                    self.fsinfo.free_count = free.saturating_sub(1);
+                }
+                self.fsinfo.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+        }
+        Err(FatError::NoSpace)
+    }
+
+    /// Free the chain starting at `cluster`.
+    fn free_chain(&mut self, cluster: u32) -> Result<(), FatError> {
+        let chain: Vec<_> = FatEntries {
+            fat: self,
+            curr_clus: cluster,
+        }
+        .map(|e| e.map(|e| e.cluster))
+        .collect::<Result<_, _>>()?;
+        for &cluster in &chain {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        if let Some(free) = self.free_clusters() {
+            self.fsinfo.free_count = free + chain.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Write the parts of `entry` that can change back to its slot.
+    fn update_entry(&mut self, entry: &DirEntry) -> Result<(), FatError> {
+        // The root directory has no entry to update.
+        if entry.is_rootdir() {
+            return Ok(());
+        }
+        let mut slot = self.read_dir_slot(entry.loc)?;
+        entry.encode(&mut slot);
+        self.write_dir_slot(entry.loc, &slot)
+    }
+
+    /// Iterate over the entries of the directory `dir`.
+    pub fn read_dir(&mut self, dir: &DirEntry) -> DirEntries<'_, B> {
+        let cluster = dir.first_data_clus().cluster;
+        DirEntries {
+            fat: self,
+            loc: ClusterLoc { cluster, offset: 0 },
+            lfn: None,
+            // An empty directory has no cluster to read.
+            done: cluster == 0,
+        }
+    }
+
+    /// Find the file or directory at `path`, like `/TEST_DIR/POEM.TXT`.
+    ///
+    /// Paths start at the root directory whether or not they begin with a
+    /// `/`. Names match either the long or the short name of an entry,
+    /// ignoring case.
+    pub fn lookup(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = self.root_dir();
+        for name in path.split('/') {
+            match name {
+                "" | "." if entry.is_dir() => continue,
+                // The root directory has no `..` entry, it's its own parent.
+                ".." if entry.is_rootdir() => continue,
+                _ if !entry.is_dir() => return Err(FatError::NotADirectory),
+                _ => entry = self.find(&entry, name)?,
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Like [`Fat32::lookup`], but `path` has to be a directory.
+    pub fn open_dir(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        let dir = self.lookup(path)?;
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        Ok(dir)
+    }
+
+    /// Open the file at `path`.
+    pub fn open(&mut self, path: &str) -> Result<File<'_, B>, FatError> {
+        let entry = self.lookup(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        Ok(File {
+            fat: self,
+            entry,
+            pos: 0,
+            cursor: None,
+        })
+    }
+
+    /// Create an empty file at `path`, and open it.
+    pub fn create(&mut self, path: &str) -> Result<File<'_, B>, FatError> {
+        let (parent, name) = self.parent_of(path)?;
+        let entry = self.new_entry(Attributes::ARCHIVE, 0);
+        let entry = self.add_entry(&parent, name, entry, None)?;
+        Ok(File {
+            fat: self,
+            entry,
+            pos: 0,
+            cursor: None,
+        })
+    }
+
+    /// Create an empty directory at `path`.
+    pub fn mkdir(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        let (parent, name) = self.parent_of(path)?;
+        // Before the cluster, so a bad or taken name doesn't touch the FAT.
+        let name = self.new_name(&parent, name, None)?;
+        let cluster = self.alloc_cluster(None)?;
+        self.zero_cluster(cluster)?;
+
+        let dir = self.new_entry(Attributes::DIRECTORY, cluster);
+        let mut dot = dir.clone();
+        dot.short_name = *b".          ";
+        let mut dotdot = self.new_entry(Attributes::DIRECTORY, parent.dotdot_cluster());
+        dotdot.short_name = *b"..         ";
+        for (i, entry) in [dot, dotdot].iter().enumerate() {
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            entry.encode(&mut slot);
+            let loc = ClusterLoc {
+                cluster,
+                offset: (i * DIR_ENTRY_SIZE) as u32,
+            };
+            self.write_dir_slot(loc, &slot)?;
+        }
+
+        // The directory is complete before its parent points at it.
+        self.barrier()?;
+        match self.insert_entry(&parent, name, dir) {
+            Ok(dir) => Ok(dir),
+            Err(e) => {
+                self.free_chain(cluster)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Delete the file at `path`.
+    pub fn remove(&mut self, path: &str) -> Result<(), FatError> {
+        let entry = self.lookup(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        self.delete_entry(&entry)?;
+        if entry.first_cluster != 0 {
+            self.barrier()?;
+            self.free_chain(entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the directory at `path`, which has to be empty.
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FatError> {
+        let dir = self.open_dir(path)?;
+        if dir.is_rootdir() || matches!(dir.name(), "." | "..") {
+            return Err(FatError::InvalidName);
+        }
+        for entry in self.read_dir(&dir) {
+            if !matches!(entry?.name(), "." | "..") {
+                return Err(FatError::DirectoryNotEmpty);
+            }
+        }
+        self.delete_entry(&dir)?;
+        self.barrier()?;
+        self.free_chain(dir.first_cluster)
+    }
+
+    /// Move the file or directory at `from` to `to`, which must not exist
+    /// yet. The two can be in different directories.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FatError> {
+        let entry = self.lookup(from)?;
+        if entry.is_rootdir() || matches!(entry.name(), "." | "..") {
+            return Err(FatError::InvalidName);
+        }
+        let (parent, name) = self.parent_of(to)?;
+
+        if entry.is_dir() {
+            // Moving a directory below itself would cut it off from the tree.
+            let mut ancestor = parent.clone();
+            while !ancestor.is_rootdir() {
+                if ancestor.first_cluster == entry.first_cluster {
+                    return Err(FatError::InvalidName);
+                }
+                ancestor = self.find(&ancestor, "..")?;
+            }
+        }
+
+        // NOTE: A crash between these leaves both names pointing at the same
+        // clusters, which `fsck` finds as a cross-link. The other way around
+        // would lose the file.
+        let moved = self.add_entry(&parent, name, entry.clone(), Some(&entry))?;
+        self.barrier()?;
+        self.delete_entry(&entry)?;
+
+        // A directory's `..` has to follow it to its new parent.
+        if moved.is_dir() {
+            let loc = ClusterLoc {
+                cluster: moved.first_cluster,
+                offset: DIR_ENTRY_SIZE as u32,
+            };
+            let mut slot = self.read_dir_slot(loc)?;
+            if slot[..11] == *b"..         " {
+                let mut dotdot = DirEntry::from_slot(loc, &slot, None);
+                dotdot.first_cluster = parent.dotdot_cluster();
+                dotdot.encode(&mut slot);
+                self.write_dir_slot(loc, &slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Split `path` into the directory it's in and its name.
+    fn parent_of<'p>(&mut self, path: &'p str) -> Result<(DirEntry, &'p str), FatError> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if matches!(name, "" | "." | "..") {
+            return Err(FatError::InvalidName);
+        }
+        Ok((self.open_dir(dir)?, name))
+    }
+
+    /// A directory entry for something new, stamped with the current time.
+    fn new_entry(&self, attributes: Attributes, first_cluster: u32) -> DirEntry {
+        let now = (self.clock)();
+        DirEntry {
+            attributes,
+            first_cluster,
+            created: now,
+            modified: now,
+            accessed: now,
+            ..DirEntry::root(0)
+        }
+    }
+
+    /// Add `entry` to the directory `dir` under `name`, with a long name and
+    /// a generated short name if `name` doesn't fit in 8.3. `replacing` is an
+    /// entry that is about to go away, whose names don't count as taken.
+    fn add_entry(
+        &mut self,
+        dir: &DirEntry,
+        name: &str,
+        entry: DirEntry,
+        replacing: Option<&DirEntry>,
+    ) -> Result<DirEntry, FatError> {
+        let name = self.new_name(dir, name, replacing)?;
+        self.insert_entry(dir, name, entry)
+    }
+
+    /// Check that `name` is valid and not taken in `dir`, and pick its short
+    /// name. Nothing is written yet.
+    fn new_name(
+        &mut self,
+        dir: &DirEntry,
+        name: &str,
+        replacing: Option<&DirEntry>,
+    ) -> Result<NewName, FatError> {
+        let long_name = encode_long_name(name)?;
+        let mut short_names = Vec::new();
+        for existing in self.read_dir(dir) {
+            let existing = existing?;
+            if replacing.map_or(false, |r| r.loc == existing.loc) {
+                continue;
+            }
+            if names_match(existing.name(), name) || names_match(&existing.short_name(), name) {
+                return Err(FatError::AlreadyExists);
+            }
+            short_names.push(existing.short_name);
+        }
+
+        let (short_name, nt_flags, lfn) = match to_short_name(name) {
+            Some((short_name, nt_flags)) => (short_name, nt_flags, Vec::new()),
+            None => {
+                let short_name = make_alias(name, &short_names)?;
+                (short_name, 0, lfn_slots(&long_name, &short_name))
+            }
+        };
+        Ok(NewName {
+            name: name.to_string(),
+            short_name,
+            nt_flags,
+            lfn,
+        })
+    }
+
+    /// Write `entry` to `dir` under the name `new_name` picked.
+    fn insert_entry(
+        &mut self,
+        dir: &DirEntry,
+        name: NewName,
+        mut entry: DirEntry,
+    ) -> Result<DirEntry, FatError> {
+        entry.name = name.name;
+        entry.short_name = name.short_name;
+
+        let mut loc = self.find_free_slots(dir, name.lfn.len() + 1)?;
+        entry.lfn_loc = (!name.lfn.is_empty()).then_some(loc);
+        for slot in &name.lfn {
+            self.write_dir_slot(loc, slot)?;
+            // `find_free_slots` made sure there's room.
+            loc = self.next_slot(loc)?.unwrap();
+        }
+        entry.loc = loc;
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        entry.encode(&mut slot);
+        slot[12] = name.nt_flags;
+        self.write_dir_slot(loc, &slot)?;
+        Ok(entry)
+    }
+
+    /// Mark the slots of `entry` deleted.
+    fn delete_entry(&mut self, entry: &DirEntry) -> Result<(), FatError> {
+        let mut loc = entry.lfn_loc.unwrap_or(entry.loc);
+        loop {
+            let mut slot = self.read_dir_slot(loc)?;
+            slot[0] = DIR_DELETED;
+            self.write_dir_slot(loc, &slot)?;
+            if loc == entry.loc {
+                return Ok(());
+            }
+            loc = self.next_slot(loc)?.ok_or(FatError::BrokenChain)?;
+        }
+    }
+
+    /// Find `n` free slots in a row in the directory `dir`, growing it if
+    /// there aren't any.
+    fn find_free_slots(&mut self, dir: &DirEntry, n: usize) -> Result<ClusterLoc, FatError> {
+        let mut loc = dir.first_data_clus();
+        let mut run = 0;
+        let mut run_start = loc;
+        loop {
+            let slot = self.read_dir_slot(loc)?;
+            if slot[0] == DIR_END || slot[0] == DIR_DELETED {
+                if run == 0 {
+                    run_start = loc;
+                }
+                run += 1;
+                if run == n {
+                    return Ok(run_start);
+                }
+            } else {
+                run = 0;
+            }
+            loc = match self.next_slot(loc)? {
+                Some(next) => next,
+                // The FAT12/16 root directory can't grow.
+                None if loc.cluster == FIXED_ROOT => return Err(FatError::NoSpace),
+                None => {
+                    // Zeroed before it's linked in, so a crash can't leave
+                    // garbage entries in the directory.
+                    let cluster = self.alloc_cluster(None)?;
+                    self.zero_cluster(cluster)?;
+                    self.barrier()?;
+                    self.set_fat_entry(loc.cluster, cluster)?;
+                    ClusterLoc { cluster, offset: 0 }
+                }
+            };
+        }
+    }
+
+    /// Find the entry called `name` in the directory `dir`.
+    fn find(&mut self, dir: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        for entry in self.read_dir(dir) {
+            let entry = entry?;
+            if names_match(entry.name(), name) || names_match(&entry.short_name(), name) {
+                // A `..` pointing at cluster 0 means the parent is the root.
+                // Anything else with cluster 0 is just empty.
+                let root = self.root_dir().first_cluster;
+                let dotdot = entry.is_dir() && entry.short_name == *b"..         ";
+                if dotdot && (entry.first_cluster() == 0 || entry.first_cluster() == root) {
+                    return Ok(self.root_dir());
+                }
+                return Ok(entry);
+            }
+        }
+        Err(FatError::NoSuchFile)
+    }
+
+    /// List the directory at `path`.
+    pub fn ls(&mut self, path: &str) -> Result<(), FatError> {
+        let dir = self.open_dir(path)?;
+        for entry in self.read_dir(&dir) {
+            let entry = entry?;
+            let size = if entry.is_dir() {
+                "<DIR>".to_string()
+            } else {
+                entry.size().to_string()
+            };
+            println!("{}  {:>10}  {}", entry.modified(), size, entry.name());
+        }
+        Ok(())
+    }
+
+    fn bytes_per_cluster(&self) -> u32 {
+        self.bpb.bytes_per_sector() as u32 * self.bpb.sectors_per_cluster() as u32
+    }
+
+    /// Read the 32-byte directory slot at `loc`.
+    fn read_dir_slot(&mut self, loc: ClusterLoc) -> Result<[u8; DIR_ENTRY_SIZE], FatError> {
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        self.read_bytes(self.cluster_to_sector(loc.cluster), loc.offset, &mut slot)?;
+        Ok(slot)
+    }
+
+    fn write_dir_slot(
+        &mut self,
+        loc: ClusterLoc,
+        slot: &[u8; DIR_ENTRY_SIZE],
+    ) -> Result<(), FatError> {
+        self.write_bytes(self.cluster_to_sector(loc.cluster), loc.offset, slot)
+    }
+
+    /// The directory slot after `loc`, following the cluster chain. `None`
+    /// at the end of the chain.
+    fn next_slot(&mut self, loc: ClusterLoc) -> Result<Option<ClusterLoc>, FatError> {
+        let offset = loc.offset + DIR_ENTRY_SIZE as u32;
+        if loc.cluster == FIXED_ROOT {
+            let size = self.bpb.max_root_dir_entries() as u32 * DIR_ENTRY_SIZE as u32;
+            return Ok((offset < size).then_some(ClusterLoc {
+                cluster: FIXED_ROOT,
+                offset,
+            }));
+        }
+        if offset < self.bytes_per_cluster() {
+            return Ok(Some(ClusterLoc {
+                cluster: loc.cluster,
+                offset,
+            }));
+        }
+        let entry = self.get_fat_entry(loc.cluster)?;
+        Ok(entry.has_next().then_some(ClusterLoc {
+            cluster: entry.entry,
+            offset: 0,
+        }))
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FatError> {
+        let zeros = alloc::vec![0; self.bytes_per_cluster() as usize];
+        self.write_bytes(self.cluster_to_sector(cluster), 0, &zeros)
+    }
+
+    /// Where the FAT entry of `cluster` is in a FAT, in bytes, and how many
+    /// bytes to read to get all of it.
+    fn fat_entry_offset(&self, cluster: u32) -> (u32, usize) {
+        match self.fat_type {
+            // Two entries share three bytes.
+            FatType::Fat12 => (cluster + cluster / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    /// Read the FAT entry of `cluster` from the active FAT, as it is.
+    fn read_fat_entry(&mut self, cluster: u32) -> Result<u32, FatError> {
+        let fat = self.bpb.active_fat().unwrap_or(0) as u32;
+        let (offset, len) = self.fat_entry_offset(cluster);
+        let mut bytes = [0; 4];
+        self.read_bytes(self.fat_start(fat), offset, &mut bytes[..len])?;
+        let raw = u32::from_le_bytes(bytes);
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => raw >> 4,
+            FatType::Fat12 => raw & 0xfff,
+            _ => raw,
+        })
+    }
+
+    /// Read the FAT entry of `cluster`, from the active FAT.
+    ///
+    /// FAT12 and FAT16 entries are widened so that their special values look
+    /// like the FAT32 ones: 0xff7 (bad) becomes 0x0ffffff7, and so on.
+    fn get_fat_entry(&mut self, cluster_no: u32) -> Result<FatEntry, FatError> {
+        let raw = self.read_fat_entry(cluster_no)?;
+        let (entry, special) = match self.fat_type {
+            FatType::Fat12 => (raw, 0xff7),
+            FatType::Fat16 => (raw, 0xfff7),
+            // Only 28 bits of an entry are used, the rest are reserved.
+            FatType::Fat32 => (raw & FAT_ENTRY_MASK, FAT_BAD),
+        };
+        let entry = if entry >= special {
+            entry | FAT_ENTRY_MASK & !(special | 0xf)
+        } else {
+            entry
+        };
+        Ok(FatEntry {
+            cluster: cluster_no,
+            entry,
+        })
+    }
+
+    /// Set the FAT entry of `cluster` to `value`, in every copy of the FAT,
+    /// or only in the active one if mirroring is off.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let fats = match self.bpb.active_fat() {
+            Some(fat) => fat as u32..fat as u32 + 1,
+            None => 0..self.bpb.fats() as u32,
+        };
+        let (offset, len) = self.fat_entry_offset(cluster);
+        // The bits of what's there that belong to the entry.
+        let mask = match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => 0xfff0,
+            FatType::Fat12 => 0x0fff,
+            FatType::Fat16 => 0xffff,
+            // Keep the reserved top bits as they are.
+            FatType::Fat32 => FAT_ENTRY_MASK,
+        };
+        let value = match self.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => value << 4,
+            _ => value,
+        } & mask;
+        for fat in fats {
+            let mut bytes = [0; 4];
+            self.read_bytes(self.fat_start(fat), offset, &mut bytes[..len])?;
+            let new = u32::from_le_bytes(bytes) & !mask | value;
+            self.write_bytes(self.fat_start(fat), offset, &new.to_le_bytes()[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Check the whole volume: every cluster chain, the FAT copies, the long
+    /// names, and what the FSInfo sector says is free. With `repair`, fix
+    /// what's found as well:
+    ///
+    /// - broken chains are cut short where they go wrong, and so are files
+    ///   that are bigger than what's left of their chain,
+    /// - chains longer than their file are cut to its size,
+    /// - clusters nothing uses are freed,
+    /// - long name entries that don't belong to anything are deleted,
+    /// - the other FATs are made the same as the first one,
+    /// - the FSInfo free count is corrected.
+    ///
+    /// A volume that needs repair can be written to again once this has
+    /// repaired it, or found nothing wrong.
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport, FatError> {
+        if repair {
+            self.needs_repair = false;
+        }
+        let mut check = Check {
+            report: FsckReport::default(),
+            used: ClusterSet::new(self.cluster_count() + 2),
+            chain: ClusterSet::new(self.cluster_count() + 2),
+            repair,
+        };
+        self.check_fats(&mut check)?;
+
+        let mut root = self.root_dir();
+        let clusters = if root.first_cluster == FIXED_ROOT {
+            Vec::new()
+        } else {
+            self.check_chain(&mut check, "/", &mut root)?
+        };
+        let mut dirs = alloc::vec![(String::from("/"), root, clusters)];
+        while let Some((path, dir, clusters)) = dirs.pop() {
+            check.report.dirs += 1;
+            for (name, mut entry) in self.check_dir_slots(&mut check, &path, &dir, &clusters)? {
+                let path = if path == "/" {
+                    format!("/{}", name)
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                let clusters = self.check_chain(&mut check, &path, &mut entry)?;
+                if !entry.is_dir() {
+                    check.report.files += 1;
+                } else if !clusters.is_empty() {
+                    dirs.push((path, entry, clusters));
+                }
+            }
+        }
+
+        self.check_lost_clusters(&mut check)?;
+        if check.repair {
+            self.sync()?;
+        }
+        check.report.repaired = repair && !check.report.problems.is_empty();
+        if check.report.is_clean() {
+            self.needs_repair = false;
+        }
+        Ok(check.report)
+    }
+
+    /// Compare every FAT with the first one, if they're supposed to be the
+    /// same.
+    fn check_fats(&mut self, check: &mut Check) -> Result<(), FatError> {
+        if self.bpb.active_fat().is_some() {
+            return Ok(());
+        }
+        let sector_size = self.bpb.bytes_per_sector() as usize;
+        let mut first = alloc::vec![0; sector_size];
+        let mut other = alloc::vec![0; sector_size];
+        for fat in 1..self.bpb.fats() as u32 {
+            let mut sectors = 0;
+            for sector in 0..self.bpb.sectors_per_fat() {
+                self.read_bytes(self.fat_start(0) + sector, 0, &mut first)?;
+                self.read_bytes(self.fat_start(fat) + sector, 0, &mut other)?;
+                if first != other {
+                    sectors += 1;
+                    if check.repair {
+                        self.write_bytes(self.fat_start(fat) + sector, 0, &first)?;
+                    }
+                }
+            }
+            if sectors != 0 {
+                check.problem(Problem::FatsDiffer { fat, sectors });
+            }
+        }
+        Ok(())
+    }
+
+    /// Go through the slots of the directory at `path`, checking the long
+    /// names, and return the entries in it other than `.` and `..`.
+    fn check_dir_slots(
+        &mut self,
+        check: &mut Check,
+        path: &str,
+        dir: &DirEntry,
+        clusters: &[u32],
+    ) -> Result<Vec<(String, DirEntry)>, FatError> {
+        let locs: Vec<_> = if dir.first_cluster == FIXED_ROOT {
+            let size = self.bpb.max_root_dir_entries() as u32 * DIR_ENTRY_SIZE as u32;
+            (0..size)
+                .step_by(DIR_ENTRY_SIZE)
+                .map(|offset| ClusterLoc {
+                    cluster: FIXED_ROOT,
+                    offset,
+                })
+                .collect()
+        } else {
+            // Only the clusters that made it through `check_chain`, the FAT
+            // might go round in circles.
+            let bytes_per_cluster = self.bytes_per_cluster();
+            clusters
+                .iter()
+                .flat_map(|&cluster| {
+                    (0..bytes_per_cluster)
+                        .step_by(DIR_ENTRY_SIZE)
+                        .map(move |offset| ClusterLoc { cluster, offset })
+                })
+                .collect()
+        };
+
+        let mut entries = Vec::new();
+        let mut lfn: Option<LongName> = None;
+        // The long name entries seen since the last short entry, and the
+        // ones that turned out not to belong to anything.
+        let mut pending = Vec::new();
+        let mut orphans = Vec::new();
+        for loc in locs {
+            let slot = self.read_dir_slot(loc)?;
+            let is_long_name =
+                slot[0] != DIR_END && slot[0] != DIR_DELETED && slot[11] & 0x3f == ATTR_LONG_NAME;
+            if is_long_name {
+                if slot[0] & LFN_LAST != 0 {
+                    orphans.append(&mut pending);
+                    let entries = slot[0] & !LFN_LAST;
+                    lfn = Some(LongName {
+                        chars: alloc::vec![0xffff; entries as usize * LFN_CHARS],
+                        checksum: slot[13],
+                        next: entries,
+                        loc,
+                    });
+                }
+                pending.push(loc);
+                if !lfn.as_mut().map_or(false, |lfn| lfn.push(&slot)) {
+                    orphans.append(&mut pending);
+                    lfn = None;
+                }
+                continue;
+            }
+
+            let is_entry = slot[0] != DIR_END
+                && slot[0] != DIR_DELETED
+                && slot[11] & Attributes::VOLUME_ID.bits() == 0;
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&slot[..11]);
+            let long_name = match lfn.take() {
+                Some(lfn) if is_entry => lfn.finish(&short_name),
+                _ => None,
+            };
+            if long_name.is_some() {
+                pending.clear();
+            }
+            orphans.append(&mut pending);
+            if !orphans.is_empty() {
+                check.problem(Problem::BadLongName {
+                    dir: path.to_string(),
+                    slots: orphans.len() as u32,
+                });
+                if check.repair {
+                    for &loc in &orphans {
+                        let mut slot = self.read_dir_slot(loc)?;
+                        slot[0] = DIR_DELETED;
+                        self.write_dir_slot(loc, &slot)?;
+                    }
+                }
+                orphans.clear();
+            }
+
+            if slot[0] == DIR_END {
+                break;
+            }
+            if is_entry && short_name[0] != b'.' {
+                let entry = DirEntry::from_slot(loc, &slot, long_name);
+                entries.push((entry.name().to_string(), entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Follow the chain of `entry`, the file or directory at `path`, and
+    /// check it against what's been seen so far and against the size of the
+    /// file. Returns the clusters of the chain, as far as it could be
+    /// followed.
+    fn check_chain(
+        &mut self,
+        check: &mut Check,
+        path: &str,
+        entry: &mut DirEntry,
+    ) -> Result<Vec<u32>, FatError> {
+        let count = self.cluster_count();
+        let mut chain = Vec::new();
+        let mut cluster = entry.first_cluster;
+        // An empty file has no chain at all.
+        let problem = if cluster == 0 && !entry.is_dir() {
+            None
+        } else {
+            loop {
+                if !(2..count + 2).contains(&cluster) {
+                    break Some(Problem::InvalidCluster {
+                        path: path.to_string(),
+                        cluster,
+                    });
+                }
+                if check.used.contains(cluster) {
+                    let path = path.to_string();
+                    break Some(if check.chain.contains(cluster) {
+                        Problem::Loop { path, cluster }
+                    } else {
+                        Problem::CrossLinked { path, cluster }
+                    });
+                }
+                check.used.insert(cluster);
+                check.chain.insert(cluster);
+                chain.push(cluster);
+
+                let next = self.get_fat_entry(cluster)?;
+                if next.is_eoc() {
+                    break None;
+                } else if next.is_free() {
+                    break Some(Problem::EndsInFree {
+                        path: path.to_string(),
+                        cluster,
+                    });
+                } else if next.is_bad() {
+                    // The cluster itself is bad, whatever was in it is gone.
+                    chain.pop();
+                    check.used.remove(cluster);
+                    break Some(Problem::EndsInBad {
+                        path: path.to_string(),
+                        cluster,
+                    });
+                }
+                cluster = next.entry;
+            }
+        };
+        for &cluster in &chain {
+            check.chain.remove(cluster);
+        }
+
+        let bytes_per_cluster = self.bytes_per_cluster();
+        let needed = div_ceil(entry.size, bytes_per_cluster) as usize;
+        let cut = match problem {
+            Some(problem) => {
+                check.problem(problem);
+                true
+            }
+            None if entry.is_dir() || chain.len() == needed => false,
+            None => {
+                check.problem(if chain.len() > needed {
+                    Problem::ChainTooLong {
+                        path: path.to_string(),
+                        clusters: chain.len() as u32,
+                        size: entry.size,
+                    }
+                } else {
+                    Problem::ChainTooShort {
+                        path: path.to_string(),
+                        clusters: chain.len() as u32,
+                        size: entry.size,
+                    }
+                });
+                true
+            }
+        };
+        if !entry.is_dir() && chain.len() > needed {
+            // What's past the end of the file isn't part of it, and should
+            // turn up as lost if nothing else has it.
+            for &cluster in &chain[needed..] {
+                check.used.remove(cluster);
+            }
+            chain.truncate(needed);
+        }
+        if cut && check.repair {
+            self.cut_chain(entry, &chain)?;
+        }
+        Ok(chain)
+    }
+
+    /// Make `chain` the whole chain of `entry`, and the file no bigger than
+    /// that. A directory with no chain left is removed.
+    fn cut_chain(&mut self, entry: &mut DirEntry, chain: &[u32]) -> Result<(), FatError> {
+        match chain.last() {
+            Some(&last) => self.set_fat_entry(last, FAT_EOC)?,
+            None if entry.is_dir() && !entry.is_rootdir() => return self.delete_entry(entry),
+            None => entry.first_cluster = 0,
+        }
+        if !entry.is_dir() {
+            let bytes = chain.len() as u64 * self.bytes_per_cluster() as u64;
+            entry.size = entry.size.min(bytes.min(u32::MAX as u64) as u32);
+        }
+        self.update_entry(entry)
+    }
+
+    /// Find the clusters in use that no file or directory has, and count
+    /// the free ones.
+    fn check_lost_clusters(&mut self, check: &mut Check) -> Result<(), FatError> {
+        let count = self.cluster_count();
+        let mut free = 0;
+        // Lost clusters and their FAT entries, in order.
+        let mut lost = Vec::new();
+        for cluster in 2..count + 2 {
+            let entry = self.get_fat_entry(cluster)?;
+            if entry.is_free() {
+                free += 1;
+            } else if !entry.is_bad() && !check.used.contains(cluster) {
+                lost.push((cluster, entry.entry));
+            }
+        }
+        check.report.used_clusters = count - free - lost.len() as u32;
+
+        // Lost chains start with a cluster no other lost cluster points to,
+        // unless they go round in circles.
+        let next_of = |cluster: u32| {
+            lost.binary_search_by_key(&cluster, |&(c, _)| c)
+                .ok()
+                .map(|i| lost[i].1)
+        };
+        let mut pointed_to = ClusterSet::new(count + 2);
+        for &(_, next) in &lost {
+            if next_of(next).is_some() {
+                pointed_to.insert(next);
+            }
+        }
+        let heads = lost.iter().filter(|&&(c, _)| !pointed_to.contains(c));
+        let mut seen = ClusterSet::new(count + 2);
+        let mut chains = Vec::new();
+        for &(first, _) in heads.chain(lost.iter()) {
+            let mut clusters = 0;
+            let mut cluster = first;
+            while !seen.contains(cluster) {
+                seen.insert(cluster);
+                clusters += 1;
+                match next_of(cluster) {
+                    Some(next) if next_of(next).is_some() => cluster = next,
+                    _ => break,
+                }
+            }
+            if clusters != 0 {
+                chains.push(Problem::LostChain { first, clusters });
+            }
+        }
+        for chain in chains {
+            check.problem(chain);
+        }
+        if check.repair {
+            for &(cluster, _) in &lost {
+                self.set_fat_entry(cluster, 0)?;
+            }
+            free += lost.len() as u32;
+        }
+
+        check.report.free_clusters = free;
+        if let Some(recorded) = self.free_clusters() {
+            if recorded != free {
+                check.problem(Problem::FreeCount {
+                    recorded,
+                    actual: free,
+                });
+            }
+        }
+        if check.repair && self.fat_type == FatType::Fat32 {
+            self.fsinfo.free_count = free;
+        }
+        Ok(())
+    }
+
+    // @TODO
+    // This design goes against the zero-copy objective, because
+    // we're always returning an owned buffer. Use `self.cache` directly
+    // where possible.
+    pub fn read_block(&mut self, sector_no: u32) -> Result<Vec<u8>, FatError> {
+        let mut buf = alloc::vec![0; self.bpb.bytes_per_sector() as usize];
+        self.read_bytes(sector_no, 0, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// The bits of a FAT32 entry that are actually used.
+const FAT_ENTRY_MASK: u32 = 0x0fffffff;
+
+/// A cluster that's gone bad.
+const FAT_BAD: u32 = 0x0ffffff7;
+
+/// Stands in for the cluster of the FAT12/16 root directory, which has a
+/// region of its own instead. Cluster 1 doesn't exist otherwise.
+const FIXED_ROOT: u32 = 1;
+
+/// What we mark the end of a cluster chain with.
+const FAT_EOC: u32 = 0x0fffffff;
+
+/// The bit of FAT[1] that's set when the volume was unmounted cleanly, and
+/// cleared while it's in use. FAT12 doesn't have one.
+const FAT16_CLEAN: u32 = 0x8000;
+const FAT32_CLEAN: u32 = 0x08000000;
+
+const FSINFO_LEAD_SIG: u32 = 0x41615252;
+const FSINFO_STRUCT_SIG: u32 = 0x61417272;
+const FSINFO_TRAIL_SIG: u32 = 0xaa550000;
+
+/// The hints kept in the FSInfo sector, so we don't have to scan the whole
+/// FAT to know how much space is left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FsInfo {
+    free_count: u32,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+}
+
+impl FsInfo {
+    const UNKNOWN: FsInfo = FsInfo {
+        free_count: 0xffffffff,
+        next_free: 0xffffffff,
+    };
+}
+
+/// Represents a location in a FAT32 filesystem.
+/// Identified by a cluster number and the offset within that cluster.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct ClusterLoc {
+    cluster: u32,
+    offset: u32,
+}
+
+/// Size of a directory entry on disk, short or long.
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// The first name byte of the entry that ends a directory.
+const DIR_END: u8 = 0x00;
+
+/// The first name byte of a deleted entry.
+const DIR_DELETED: u8 = 0xe5;
+
+/// Stands in for a real 0xe5 as the first name byte, so the entry doesn't
+/// look deleted.
+const DIR_KANJI_E5: u8 = 0x05;
+
+/// The attributes of a long file name entry, which no real file can have.
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Set in the ordinal of the last long file name entry, which comes first on
+/// disk.
+const LFN_LAST: u8 = 0x40;
+
+/// How many UCS-2 characters one long file name entry holds.
+const LFN_CHARS: usize = 13;
+
+/// Byte offsets of the name characters in a long file name entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Bits in `DIR_NTRes`, set by Windows NT when the base name or extension of
+/// a short name is all lower case, instead of adding a long name.
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+bitflags! {
+    /// Attributes of a directory entry.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Attributes: u8 {
+        const READ_ONLY = 0x01;
+        const HIDDEN = 0x02;
+        const SYSTEM = 0x04;
+        const VOLUME_ID = 0x08;
+        const DIRECTORY = 0x10;
+        const ARCHIVE = 0x20;
+    }
+}
+
+/// A timestamp from a directory entry, in local time, whatever that was on
+/// the machine that wrote it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl DateTime {
+    /// The earliest time FAT can record.
+    pub const FAT_EPOCH: DateTime = DateTime {
+        year: 1980,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+        millis: 0,
+    };
+
+    /// Decode a FAT date and time. Times only have a 2 second resolution,
+    /// `centis` adds the hundredths of a second on top (creation time only).
+    pub(crate) fn from_fat(date: u16, time: u16, centis: u8) -> Self {
+        DateTime {
+            year: 1980 + (date >> 9),
+            month: (date >> 5 & 0xf) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: (time >> 5 & 0x3f) as u8,
+            second: (time & 0x1f) as u8 * 2 + centis / 100,
+            millis: (centis % 100) as u16 * 10,
+        }
+    }
+
+    /// Encode as a FAT date, time and hundredths of a second.
+    fn to_fat(self) -> (u16, u16, u8) {
+        let date =
+            (self.year.saturating_sub(1980)) << 9 | (self.month as u16) << 5 | self.day as u16;
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second / 2) as u16;
+        let centis = self.second % 2 * 100 + (self.millis / 10) as u8;
+        (date, time, centis)
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// A file or directory, as described by its directory entry.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DirEntry {
+    /// Where the short entry is. The long name entries, if any, come right
+    /// before it.
+    loc: ClusterLoc,
+    /// Where the first long name entry is.
+    lfn_loc: Option<ClusterLoc>,
+    name: String,
+    short_name: [u8; 11],
+    attributes: Attributes,
+    size: u32,
+    first_cluster: u32,
+    created: DateTime,
+    modified: DateTime,
+    accessed: DateTime,
+}
+
+//type DataClusterIter<'b, B> = core::iter::Map<FatEntries<'b, 'b, B>>;
+
+impl DirEntry {
+    /// Decode the short entry in `slot`, found at `loc`. `long_name` is the
+    /// long name that came before it and where it started, if it belongs to
+    /// this entry.
+    fn from_slot(
+        loc: ClusterLoc,
+        slot: &[u8; DIR_ENTRY_SIZE],
+        long_name: Option<(String, ClusterLoc)>,
+    ) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([slot[i], slot[i + 1]]);
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&slot[..11]);
+        let (name, lfn_loc) = match long_name {
+            Some((name, lfn_loc)) => (name, Some(lfn_loc)),
+            None => (short_name_to_string(&short_name, slot[12]), None),
+        };
+        Self {
+            loc,
+            lfn_loc,
+            name,
+            short_name,
+            attributes: Attributes::from_bits_truncate(slot[11]),
+            size: u32::from_le_bytes([slot[28], slot[29], slot[30], slot[31]]),
+            first_cluster: (u16_at(20) as u32) << 16 | u16_at(26) as u32,
+            created: DateTime::from_fat(u16_at(16), u16_at(14), slot[13]),
+            modified: DateTime::from_fat(u16_at(24), u16_at(22), 0),
+            accessed: DateTime::from_fat(u16_at(18), 0, 0),
+        }
+    }
+
+    /// Write everything but the NT case flags back into `slot`.
+    fn encode(&self, slot: &mut [u8; DIR_ENTRY_SIZE]) {
+        let (cdate, ctime, centis) = self.created.to_fat();
+        let (mdate, mtime, _) = self.modified.to_fat();
+        let (adate, _, _) = self.accessed.to_fat();
+        slot[..11].copy_from_slice(&self.short_name);
+        slot[11] = self.attributes.bits();
+        slot[13] = centis;
+        slot[14..16].copy_from_slice(&ctime.to_le_bytes());
+        slot[16..18].copy_from_slice(&cdate.to_le_bytes());
+        slot[18..20].copy_from_slice(&adate.to_le_bytes());
+        slot[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        slot[22..24].copy_from_slice(&mtime.to_le_bytes());
+        slot[24..26].copy_from_slice(&mdate.to_le_bytes());
+        slot[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+
+    /// Returns the "directory entry" of the root directory, whose data starts
+    /// at `cluster`.
+    /// This is a lie, because root directory doesn't have a real directory entry.
+    /// So we set cluster to the invalid 0, representing the root directory.
+    fn root(cluster: u32) -> Self {
+        Self {
+            loc: ClusterLoc {
+                cluster: 0,
+                offset: 0,
+            },
+            lfn_loc: None,
+            name: String::from("/"),
+            short_name: [b' '; 11],
+            attributes: Attributes::DIRECTORY,
+            size: 0,
+            first_cluster: cluster,
+            created: DateTime::default(),
+            modified: DateTime::default(),
+            accessed: DateTime::default(),
+        }
+    }
+
+    fn is_rootdir(&self) -> bool {
+        self.loc.cluster == 0
+    }
+
+    /// What the `..` entries of subdirectories of this directory point to.
+    /// That's 0 for the root directory, wherever it actually is.
+    fn dotdot_cluster(&self) -> u32 {
+        if self.is_rootdir() {
+            0
+        } else {
+            self.first_cluster
+        }
+    }
+
+    /// The long name if there is one, the short name otherwise.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 8.3 name, like `THIS_I~1.TXT`.
+    pub fn short_name(&self) -> String {
+        short_name_to_string(&self.short_name, 0)
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// The size in bytes, always 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The first cluster of the data, 0 if there's none.
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    pub fn created(&self) -> DateTime {
+        self.created
+    }
+
+    pub fn modified(&self) -> DateTime {
+        self.modified
+    }
+
+    /// Only the date is recorded for the last access.
+    pub fn accessed(&self) -> DateTime {
+        self.accessed
+    }
+
+    fn fat_entries<'f, B: BlockDevice>(
+        &self,
+        fs: &'f mut Fat32<B>,
+    ) -> impl Iterator<Item = Result<FatEntry, FatError>> + 'f {
+        FatEntries {
+            fat: fs,
+            curr_clus: self.first_data_clus().cluster,
+        }
+    }
+
+    fn data_clusters<'f, B: BlockDevice>(
+        &self,
+        fs: &'f mut Fat32<B>,
+    ) -> impl Iterator<Item = Result<u32, FatError>> + 'f {
+        self.fat_entries(fs).map(|e| e.map(|e| e.cluster))
+    }
+
+    fn first_data_clus(&self) -> ClusterLoc {
+        ClusterLoc {
+            cluster: self.first_cluster,
+            offset: 0,
+        }
+    }
+}
+
+/// Characters allowed in short names, besides upper case letters and digits.
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// Characters allowed in long names, but not in short names.
+const LONG_NAME_SPECIAL: &[u8] = b"+,;=[] .";
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase()
+        || c.is_ascii_digit()
+        || c.is_ascii() && SHORT_NAME_SPECIAL.contains(&(c as u8))
+}
+
+/// The short name `name` is already, if it is one, and the NT flags that
+/// give back its case.
+fn to_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    let mut nt_flags = 0;
+    for (part, range, flag) in [(base, 0..8, NT_LOWER_BASE), (ext, 8..11, NT_LOWER_EXT)] {
+        let upper = part.chars().any(|c| c.is_ascii_uppercase());
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        // Mixed case needs a long name to keep.
+        if upper && lower {
+            return None;
+        }
+        if lower {
+            nt_flags |= flag;
+        }
+        for (c, byte) in part.chars().zip(&mut short_name[range]) {
+            let c = c.to_ascii_uppercase();
+            if !is_short_name_char(c) {
+                return None;
+            }
+            *byte = c as u8;
+        }
+    }
+    Some((short_name, nt_flags))
+}
+
+/// Make up a short name for `name` that isn't in `taken`, like Windows does:
+/// `this_is_a_file.txt` becomes `THIS_I~1.TXT`.
+fn make_alias(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FatError> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    let clean = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if is_short_name_char(c) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+    let base = clean(base, 8);
+    let ext = clean(ext, 3);
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let mut alias = [b' '; 11];
+        let keep = base.len().min(8 - tail.len());
+        alias[..keep].copy_from_slice(&base[..keep]);
+        alias[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        alias[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken.contains(&alias) {
+            return Ok(alias);
+        }
+    }
+    Err(FatError::AlreadyExists)
+}
+
+/// Check `name` can be a long name, and encode it in UCS-2.
+fn encode_long_name(name: &str) -> Result<Vec<u16>, FatError> {
+    let valid = |c: char| {
+        c.is_alphanumeric()
+            || !c.is_ascii()
+            || is_short_name_char(c)
+            || LONG_NAME_SPECIAL.contains(&(c as u8))
+    };
+    if !name.chars().all(valid) || name.ends_with('.') || name.ends_with(' ') {
+        return Err(FatError::InvalidName);
+    }
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.is_empty() || chars.len() > 255 {
+        return Err(FatError::InvalidName);
+    }
+    Ok(chars)
+}
+
+/// The long name entries for `long_name`, in the order they go on disk.
+fn lfn_slots(long_name: &[u16], short_name: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let checksum = lfn_checksum(short_name);
+    let count = div_ceil(long_name.len() as u32, LFN_CHARS as u32) as usize;
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            slot[0] = i as u8 + 1;
+            if i == count - 1 {
+                slot[0] |= LFN_LAST;
+            }
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = checksum;
+            for (j, &off) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                // Terminated by a 0, then padded with 0xffff.
+                let c = match (i * LFN_CHARS + j).cmp(&long_name.len()) {
+                    core::cmp::Ordering::Less => long_name[i * LFN_CHARS + j],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                slot[off..off + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+/// Compare two file names the way FAT does, ignoring case.
+fn names_match(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// Turn a padded 8.3 name into `BASE.EXT`, lowering the case of the parts
+/// `nt_flags` says to.
+fn short_name_to_string(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        // NOTE: Anything above 0x7f is in some OEM code page we don't know,
+        // Latin-1 is as good a guess as any.
+        let s: String = bytes.iter().map(|&b| b as char).collect();
+        let s = s.trim_end();
+        if lower {
+            s.to_ascii_lowercase()
+        } else {
+            s.to_string()
+        }
+    };
+    let mut base = short_name[..8].to_vec();
+    if base[0] == DIR_KANJI_E5 {
+        base[0] = DIR_DELETED;
+    }
+    let mut name = part(&base, nt_flags & NT_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], nt_flags & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// The checksum of a short name, which every long file name entry that
+/// belongs to it carries.
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The names of an entry about to be added, from [`Fat32::new_name`].
+struct NewName {
+    name: String,
+    short_name: [u8; 11],
+    nt_flags: u8,
+    /// The long file name entries, if the name isn't a valid short name.
+    lfn: Vec<[u8; DIR_ENTRY_SIZE]>,
+}
+
+/// A long file name being put together from its entries.
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// The ordinal of the entry we expect next, 0 once we've got them all.
+    next: u8,
+    /// Where the first entry is.
+    loc: ClusterLoc,
+}
+
+impl LongName {
+    /// Add the long file name entry in `slot`. Returns false if it doesn't
+    /// continue the name, in which case the name should be thrown away.
+    fn push(&mut self, slot: &[u8; DIR_ENTRY_SIZE]) -> bool {
+        let ord = slot[0] & !LFN_LAST;
+        if ord == 0 || ord != self.next || slot[13] != self.checksum {
+            return false;
+        }
+        let start = (ord as usize - 1) * LFN_CHARS;
+        for (i, &off) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16::from_le_bytes([slot[off], slot[off + 1]]);
+        }
+        self.next -= 1;
+        true
+    }
+
+    /// The name and where it started, if it's complete and belongs to
+    /// `short_name`.
+    fn finish(self, short_name: &[u8; 11]) -> Option<(String, ClusterLoc)> {
+        if self.next != 0 || self.checksum != lfn_checksum(short_name) {
+            return None;
+        }
+        // The name is terminated by a 0 if it doesn't fill the last entry,
+        // and padded with 0xffff after that.
+        let len = self
+            .chars
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.chars.len());
+        let name = char::decode_utf16(self.chars[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some((name, self.loc))
+    }
+}
+
+/// An iterator over the entries of a directory, made by [`Fat32::read_dir`].
+///
+/// Deleted entries and the volume label are skipped, `.` and `..` aren't.
+pub struct DirEntries<'f, B>
+where
+    B: BlockDevice,
+{
+    fat: &'f mut Fat32<B>,
+    /// The next slot to look at.
+    loc: ClusterLoc,
+    lfn: Option<LongName>,
+    done: bool,
 }
 
-impl<B> Fat32<B>
+impl<B> DirEntries<'_, B>
 where
     B: BlockDevice,
 {
-    pub fn new(mut block: B) -> Self {
-        let mut buf = [0; 512];
-        block.read(0, &mut buf).unwrap();
-        assert_eq!(buf[510], 0x55);
-        assert_eq!(buf[511], 0xaa);
-        let superblock = BiosParameterBlockPacked::from_bytes(&buf).unwrap();
-        Fat32 {
-            block,
-            bpb: superblock,
+    /// Move `self.loc` to the next slot, following the cluster chain.
+    fn advance(&mut self) -> Result<(), FatError> {
+        match self.fat.next_slot(self.loc)? {
+            Some(loc) => self.loc = loc,
+            None => self.done = true,
         }
+        Ok(())
     }
 
-    pub fn check_fs(&self) {
-        info!("Checking FAT32 filesystem");
-        info!("{:?}", self.bpb);
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FatError> {
+        while !self.done {
+            let loc = self.loc;
+            let slot = self.fat.read_dir_slot(loc)?;
+            self.advance()?;
+
+            match slot[0] {
+                DIR_END => self.done = true,
+                DIR_DELETED => self.lfn = None,
+                _ if slot[11] & 0x3f == ATTR_LONG_NAME => {
+                    if slot[0] & LFN_LAST != 0 {
+                        let entries = slot[0] & !LFN_LAST;
+                        self.lfn = Some(LongName {
+                            chars: alloc::vec![0xffff; entries as usize * LFN_CHARS],
+                            checksum: slot[13],
+                            next: entries,
+                            loc,
+                        });
+                    }
+                    // An orphaned piece of some other long name, ignore it
+                    // and whatever we had so far.
+                    if !self.lfn.as_mut().map_or(false, |lfn| lfn.push(&slot)) {
+                        self.lfn = None;
+                    }
+                }
+                _ if slot[11] & Attributes::VOLUME_ID.bits() != 0 => self.lfn = None,
+                _ => {
+                    let mut short_name = [0; 11];
+                    short_name.copy_from_slice(&slot[..11]);
+                    let long_name = self.lfn.take().and_then(|lfn| lfn.finish(&short_name));
+                    return Ok(Some(DirEntry::from_slot(loc, &slot, long_name)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
 
-        // TODO: Support more valid FAT32 volumes.
-        assert_eq!(self.bpb.jmp_boot()[0], 0xeb);
-        assert_eq!(self.bpb.bytes_per_sector(), 512);
-        assert_eq!(self.bpb.fats(), 2);
-        assert_eq!(self.bpb.max_root_dir_entries(), 0);
-        assert_eq!(self.bpb.total_sectors_16(), 0);
-        assert_eq!(self.bpb.media(), 0xf8);
-        assert_eq!(self.bpb.sectors_per_fat_16(), 0);
-        assert_eq!(self.bpb.fat_version(), 0);
-        assert_eq!(self.bpb.root_cluster(), 2);
-        assert_eq!(self.bpb.fsinfo_sector(), 1);
-        assert_eq!(self.bpb.backup_boot_sector(), 6);
-        assert_eq!(self.bpb.sectors_per_cluster(), 1); // TODO: Support volumns whose sectors per
-                                                       // cluster is not 1.
-
-        info!("rootdir_base_sec: 0x{:x}", self.rootdir_base_sec());
+impl<B> Iterator for DirEntries<'_, B>
+where
+    B: BlockDevice,
+{
+    type Item = Result<DirEntry, FatError>;
 
-        info!("Check passed");
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Don't keep going after an error.
+            self.done = true;
+        }
+        entry.transpose()
     }
+}
+
+/// Where to [`File::seek`] to, relative to the start, the current position
+/// or the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u32),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file, made by [`Fat32::open`].
+pub struct File<'f, B>
+where
+    B: BlockDevice,
+{
+    fat: &'f mut Fat32<B>,
+    entry: DirEntry,
+    pos: u32,
+    /// The last cluster we read from, and its index in the chain, so reading
+    /// on from there doesn't walk the chain from the start again.
+    cursor: Option<(u32, u32)>,
+}
 
-    fn rootdir_base_sec(&self) -> u32 {
-        self.bpb.reserved_sectors() as u32
-            + self.bpb.sectors_per_fat_32() as u32 * self.bpb.fats() as u32
+impl<B> File<'_, B>
+where
+    B: BlockDevice,
+{
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
     }
 
-    /// Convert a cluster number to a sector number.
-    fn cluster_to_sector(&self, cluster_no: u32) -> u32 {
-        self.rootdir_base_sec() + (cluster_no - 2) * self.bpb.sectors_per_cluster() as u32
+    /// The size of the file in bytes.
+    pub fn size(&self) -> u32 {
+        self.entry.size()
     }
 
-    pub fn ls_rootdir(&mut self) {
-        let rootdir = DirEntry::root();
-        let clusters: Vec<_> = rootdir.data_clusters(self).collect();
-        for data_cluster in clusters {
-            warn!("{:?}", data_cluster);
-            let buf = {
-                let sec = self.cluster_to_sector(data_cluster);
-                self.read_block(sec)
-            };
+    /// Where the next read starts.
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+
+    /// Move the position. It's fine to go past the end, reads there just
+    /// return nothing.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u32, FatError> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::Current(off) => self.pos as i64 + off,
+            SeekFrom::End(off) => self.size() as i64 + off,
+        };
+        self.pos = u32::try_from(pos).map_err(|_| FatError::InvalidSeek)?;
+        Ok(self.pos)
+    }
+
+    /// Read from the current position into `buf`, returning how many bytes
+    /// were read. That's only less than `buf.len()` at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FatError> {
+        let bytes_per_cluster = self.fat.bytes_per_cluster();
+        let len = buf.len().min(self.size().saturating_sub(self.pos) as usize);
+        let mut done = 0;
+        while done < len {
+            let cluster = self.cluster_at(self.pos / bytes_per_cluster)?;
+            let offset = self.pos % bytes_per_cluster;
+            let n = (len - done).min((bytes_per_cluster - offset) as usize);
+            let sector = self.fat.cluster_to_sector(cluster);
+            self.fat
+                .read_bytes(sector, offset, &mut buf[done..done + n])?;
+            done += n;
+            self.pos += n as u32;
         }
+        Ok(done)
     }
 
-    // @TODO: This is not very Rusty.
-    fn get_fat_entry(&mut self, cluster_no: u32) -> FatEntry {
-        let fat_offset = cluster_no * 4;
-        let fat_off_sec = self.bpb.reserved_sectors() as u32 + (fat_offset / 512);
-        let fat_entry_off = fat_offset as usize % 512;
+    /// Write `buf` at the current position, growing the file if needed.
+    ///
+    /// Writing past the end of the file fills the gap with zeros. If the
+    /// volume fills up along the way, what was written until then is kept
+    /// and its length returned.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FatError> {
+        let old = (self.entry.first_cluster, self.entry.size);
+        let pos = self.pos;
+        let result = self
+            .reserve(pos.saturating_add(buf.len() as u32))
+            .and_then(|end| {
+                self.write_zeros_to(end.min(pos))?;
+                // There has to be room for all of the gap, and some of `buf`.
+                if end < pos || end == pos && !buf.is_empty() {
+                    return Err(FatError::NoSpace);
+                }
+                let n = ((end - pos) as usize).min(buf.len());
+                self.write_at_pos(&buf[..n])
+            });
+        self.touch();
+        self.commit(old)?;
+        result
+    }
 
-        let block = self.read_block(fat_off_sec);
-        let fat_entry = block[fat_entry_off] as u32
-            | (block[fat_entry_off + 1] as u32) << 8
-            | (block[fat_entry_off + 2] as u32) << 16
-            | (block[fat_entry_off + 3] as u32) << 24;
+    /// Truncate or extend the file to `len` bytes. Extending fills the new
+    /// part with zeros. The position doesn't move.
+    pub fn set_len(&mut self, len: u32) -> Result<(), FatError> {
+        if len >= self.size() {
+            let old = (self.entry.first_cluster, self.entry.size);
+            let pos = self.pos;
+            let result = self.reserve(len).and_then(|end| {
+                self.write_zeros_to(end)?;
+                if end < len {
+                    return Err(FatError::NoSpace);
+                }
+                Ok(())
+            });
+            self.pos = pos;
+            self.touch();
+            self.commit(old)?;
+            return result;
+        }
 
-        FatEntry {
-            cluster: cluster_no,
-            entry: fat_entry,
+        let keep = div_ceil(len, self.fat.bytes_per_cluster());
+        let (last, rest) = if keep == 0 {
+            (None, self.entry.first_cluster)
+        } else {
+            let last = self.cluster_at(keep - 1)?;
+            let next = self.fat.get_fat_entry(last)?;
+            (Some(last), if next.has_next() { next.entry } else { 0 })
+        };
+        if keep == 0 {
+            self.entry.first_cluster = 0;
+        }
+        self.cursor = None;
+        self.entry.size = len;
+        self.touch();
+
+        // The entry lets go of the clusters before they're freed.
+        self.fat.update_entry(&self.entry)?;
+        if rest != 0 {
+            self.fat.barrier()?;
+            if let Some(last) = last {
+                self.fat.set_fat_entry(last, FAT_EOC)?;
+            }
+            self.fat.free_chain(rest)?;
         }
+        Ok(())
     }
 
-    // @TODO
-    // This design goes against the zero-copy objective, because
-    // we're always returning an owned buffer.
-    pub fn read_block(&mut self, sector_no: u32) -> [u8; 512] {
-        let mut buf = [0; 512]; // TODO: MaybeUninit?
-        self.block.read(sector_no as usize, &mut buf).unwrap();
-        buf
+    /// Write the entry back, after the data it points at if it changed.
+    fn commit(&mut self, old: (u32, u32)) -> Result<(), FatError> {
+        if (self.entry.first_cluster, self.entry.size) != old {
+            self.fat.barrier()?;
+        }
+        self.fat.update_entry(&self.entry)
+    }
+
+    /// Record a modification now.
+    fn touch(&mut self) {
+        let now = (self.fat.clock)();
+        self.entry.modified = now;
+        self.entry.accessed = now;
+    }
+
+    /// Grow the file with zeros until it's `len` bytes long, leaving the
+    /// position at the end.
+    fn write_zeros_to(&mut self, len: u32) -> Result<(), FatError> {
+        if len <= self.size() {
+            return Ok(());
+        }
+        let zeros = [0; 512];
+        self.pos = self.size();
+        while self.pos < len {
+            let n = (len - self.pos).min(zeros.len() as u32) as usize;
+            self.write_at_pos(&zeros[..n])?;
+        }
+        Ok(())
+    }
+
+    fn write_at_pos(&mut self, buf: &[u8]) -> Result<usize, FatError> {
+        let bytes_per_cluster = self.fat.bytes_per_cluster();
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster = self.cluster_at(self.pos / bytes_per_cluster)?;
+            let offset = self.pos % bytes_per_cluster;
+            let n = (buf.len() - done).min((bytes_per_cluster - offset) as usize);
+            let sector = self.fat.cluster_to_sector(cluster);
+            self.fat.write_bytes(sector, offset, &buf[done..done + n])?;
+            done += n;
+            self.pos += n as u32;
+            self.entry.size = self.entry.size.max(self.pos);
+        }
+        Ok(done)
     }
 
-    pub fn read_cluster(&mut self, cluster_no: u32) -> [u8; 512] {
-        self.read_block(self.cluster_to_sector(cluster_no))
+    /// Make the chain long enough for the file to be `len` bytes, and return
+    /// how far it reaches, which is short of `len` if the volume fills up.
+    ///
+    /// The new clusters are allocated on disk before this returns, so data
+    /// can go in them right away.
+    fn reserve(&mut self, len: u32) -> Result<u32, FatError> {
+        let bytes_per_cluster = self.fat.bytes_per_cluster();
+        // A chain is always just long enough for the data, except that an
+        // empty file can hold on to a cluster.
+        let allocated = match self.entry.first_cluster {
+            0 => 0,
+            _ => div_ceil(self.size(), bytes_per_cluster).max(1),
+        };
+        let mut prev = match allocated {
+            0 => None,
+            n => Some(self.cluster_at(n - 1)?),
+        };
+        let mut count = allocated;
+        while count < div_ceil(len, bytes_per_cluster) {
+            let cluster = match self.fat.alloc_cluster(prev) {
+                Ok(cluster) => cluster,
+                Err(FatError::NoSpace) => break,
+                Err(e) => return Err(e),
+            };
+            if prev.is_none() {
+                self.entry.first_cluster = cluster;
+            }
+            self.cursor = Some((cluster, count));
+            prev = Some(cluster);
+            count += 1;
+        }
+        if count > allocated {
+            self.fat.barrier()?;
+        }
+        Ok(len.min(count.saturating_mul(bytes_per_cluster)))
+    }
+
+    /// Find the `index`th cluster of the file.
+    fn cluster_at(&mut self, index: u32) -> Result<u32, FatError> {
+        let (start, start_index) = match self.cursor {
+            Some((cluster, i)) if i <= index => (cluster, i),
+            _ => (self.entry.first_cluster(), 0),
+        };
+        let cluster = if index == start_index {
+            start
+        } else {
+            let mut entries = FatEntries {
+                fat: self.fat,
+                curr_clus: start,
+            };
+            match entries.nth((index - start_index) as usize) {
+                Some(entry) => entry?.cluster,
+                // The file is bigger than its chain.
+                None => return Err(FatError::BrokenChain),
+            }
+        };
+        if cluster < 2 {
+            return Err(FatError::BrokenChain);
+        }
+        self.cursor = Some((cluster, index));
+        Ok(cluster)
     }
 }
 
-/// Represents a location in a FAT32 filesystem.
-/// Identified by a cluster number and the offset within that cluster.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-struct ClusterLoc {
-    cluster: u32,
-    offset: u32,
+/// Something [`Fat32::fsck`] found wrong with a volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// FAT number `fat` isn't the same as the first one, in `sectors`
+    /// sectors.
+    FatsDiffer { fat: u32, sectors: u32 },
+
+    /// The chain of `path` goes to a cluster that doesn't exist.
+    InvalidCluster { path: String, cluster: u32 },
+
+    /// The chain of `path` comes back to `cluster`.
+    Loop { path: String, cluster: u32 },
+
+    /// The chain of `path` runs into `cluster`, which something else has
+    /// already.
+    CrossLinked { path: String, cluster: u32 },
+
+    /// The chain of `path` ends with `cluster` marked as free.
+    EndsInFree { path: String, cluster: u32 },
+
+    /// The chain of `path` runs into `cluster`, which is marked bad.
+    EndsInBad { path: String, cluster: u32 },
+
+    /// The file at `path` has more clusters than its size needs.
+    ChainTooLong {
+        path: String,
+        clusters: u32,
+        size: u32,
+    },
+
+    /// The file at `path` has fewer clusters than its size needs.
+    ChainTooShort {
+        path: String,
+        clusters: u32,
+        size: u32,
+    },
+
+    /// Long name entries in `dir` that don't belong to the entry after them.
+    BadLongName { dir: String, slots: u32 },
+
+    /// A chain of clusters in use that no file or directory has.
+    LostChain { first: u32, clusters: u32 },
+
+    /// The FSInfo sector is wrong about how many clusters are free.
+    FreeCount { recorded: u32, actual: u32 },
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-struct DirEntry {
-    loc: ClusterLoc,
+impl core::fmt::Display for Problem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Problem::FatsDiffer { fat, sectors } => {
+                write!(f, "FAT {} differs from FAT 0 in {} sectors", fat, sectors)
+            }
+            Problem::InvalidCluster { path, cluster } => {
+                write!(f, "{}: invalid cluster {}", path, cluster)
+            }
+            Problem::Loop { path, cluster } => {
+                write!(f, "{}: chain loops back to cluster {}", path, cluster)
+            }
+            Problem::CrossLinked { path, cluster } => {
+                write!(f, "{}: cross-linked at cluster {}", path, cluster)
+            }
+            Problem::EndsInFree { path, cluster } => {
+                write!(f, "{}: chain ends in free cluster {}", path, cluster)
+            }
+            Problem::EndsInBad { path, cluster } => {
+                write!(f, "{}: chain runs into bad cluster {}", path, cluster)
+            }
+            Problem::ChainTooLong {
+                path,
+                clusters,
+                size,
+            } => write!(f, "{}: {} clusters for {} bytes", path, clusters, size),
+            Problem::ChainTooShort {
+                path,
+                clusters,
+                size,
+            } => write!(f, "{}: only {} clusters for {} bytes", path, clusters, size),
+            Problem::BadLongName { dir, slots } => {
+                write!(f, "{}: {} orphaned long name entries", dir, slots)
+            }
+            Problem::LostChain { first, clusters } => {
+                write!(f, "lost chain of {} clusters at {}", clusters, first)
+            }
+            Problem::FreeCount { recorded, actual } => write!(
+                f,
+                "FSInfo says {} clusters are free, but {} are",
+                recorded, actual
+            ),
+        }
+    }
 }
 
-//type DataClusterIter<'b, B> = core::iter::Map<FatEntries<'b, 'b, B>>;
+/// What [`Fat32::fsck`] found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub files: u32,
+    /// Including the root directory.
+    pub dirs: u32,
+    pub used_clusters: u32,
+    pub free_clusters: u32,
+    pub problems: Vec<Problem>,
+    /// Whether the problems have been fixed.
+    pub repaired: bool,
+}
 
-impl DirEntry {
-    fn new(loc: ClusterLoc) -> Self {
-        Self { loc }
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
     }
+}
 
-    /// Returns the "directory entry" of the root directory.
-    /// This is a lie, because root directory doesn't have a real directory entry.
-    /// So we set cluster to the invalid 0, representing the root directory.
-    fn root() -> Self {
-        Self::new(ClusterLoc {
-            cluster: 0,
-            offset: 0,
-        })
+impl core::fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(
+            f,
+            "{} files, {} directories, {} clusters used, {} free, {} problems{}",
+            self.files,
+            self.dirs,
+            self.used_clusters,
+            self.free_clusters,
+            self.problems.len(),
+            if self.repaired { " (repaired)" } else { "" }
+        )
     }
+}
 
-    fn is_rootdir(&self) -> bool {
-        self.loc.cluster == 0
+/// Where [`Fat32::fsck`] is at.
+struct Check {
+    report: FsckReport,
+    /// The clusters some file or directory has.
+    used: ClusterSet,
+    /// The clusters of the chain being followed.
+    chain: ClusterSet,
+    repair: bool,
+}
+
+impl Check {
+    fn problem(&mut self, problem: Problem) {
+        warn!("fsck: {}", problem);
+        self.report.problems.push(problem);
     }
+}
 
-    fn fat_entries<'f, B: BlockDevice>(
-        &self,
-        fs: &'f mut Fat32<B>,
-    ) -> impl Iterator<Item = FatEntry> + 'f {
-        FatEntries {
-            fat: fs,
-            curr_clus: self.first_data_clus().cluster,
-        }
+/// A set of cluster numbers, one bit each.
+struct ClusterSet(Vec<u64>);
+
+impl ClusterSet {
+    fn new(clusters: u32) -> Self {
+        ClusterSet(alloc::vec![0; div_ceil(clusters, 64) as usize])
     }
 
-    fn data_clusters<'f, B: BlockDevice>(
-        &self,
-        fs: &'f mut Fat32<B>,
-    ) -> impl Iterator<Item = u32> + 'f {
-        self.fat_entries(fs).map(|e: FatEntry| e.cluster)
+    fn insert(&mut self, cluster: u32) {
+        self.0[cluster as usize / 64] |= 1 << (cluster % 64);
     }
 
-    fn first_data_clus(&self) -> ClusterLoc {
-        if self.is_rootdir() {
-            ClusterLoc {
-                cluster: 2,
-                offset: 0,
-            }
-        } else {
-            todo!();
-        }
+    fn remove(&mut self, cluster: u32) {
+        self.0[cluster as usize / 64] &= !(1 << (cluster % 64));
+    }
+
+    fn contains(&self, cluster: u32) -> bool {
+        self.0[cluster as usize / 64] & 1 << (cluster % 64) != 0
     }
 }
 
+fn div_ceil(a: u32, b: u32) -> u32 {
+    a / b + (a % b != 0) as u32
+}
+
 /// Represents a FAT32 entry.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 struct FatEntry {
@@ -191,7 +2452,7 @@ impl FatEntry {
     }
 
     fn is_bad(&self) -> bool {
-        self.entry == 0x0ffffff7
+        self.entry == FAT_BAD
     }
 
     fn has_next(&self) -> bool {
@@ -221,7 +2482,7 @@ impl<B> core::iter::Iterator for FatEntries<'_, B>
 where
     B: BlockDevice,
 {
-    type Item = FatEntry;
+    type Item = Result<FatEntry, FatError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let curr_clus = self.curr_clus;
@@ -229,17 +2490,27 @@ where
             return None;
         }
 
-        if (FatEntry {
+        // Stop at the end of the chain, and at anything else that can't be
+        // followed either, like a bad cluster.
+        if !(FatEntry {
             cluster: 0,
             entry: curr_clus,
         })
-        .is_eoc()
+        .has_next()
         {
             None
         } else {
-            let next_clus = self.fat.get_fat_entry(curr_clus);
-            self.curr_clus = next_clus.into();
-            Some(next_clus)
+            match self.fat.get_fat_entry(curr_clus) {
+                Ok(next_clus) => {
+                    self.curr_clus = next_clus.into();
+                    Some(Ok(next_clus))
+                }
+                Err(e) => {
+                    // Don't keep going after an error.
+                    self.curr_clus = 0;
+                    Some(Err(e))
+                }
+            }
         }
     }
 }
@@ -291,10 +2562,6 @@ impl BiosParameterBlockPacked {
         self.jmp_boot
     }
 
-    fn oem_name(&self) -> [u8; 8] {
-        self.oem_name
-    }
-
     fn bytes_per_sector(&self) -> u16 {
         self.bytes_per_sector
     }
@@ -327,18 +2594,6 @@ impl BiosParameterBlockPacked {
         self.sectors_per_fat_16
     }
 
-    fn sectors_per_track(&self) -> u16 {
-        self.sectors_per_track
-    }
-
-    fn heads(&self) -> u16 {
-        self.heads
-    }
-
-    fn hidden_sectors(&self) -> u32 {
-        self.hidden_sectors
-    }
-
     fn total_sectors_32(&self) -> u32 {
         self.total_sectors_32
     }
@@ -363,27 +2618,873 @@ impl BiosParameterBlockPacked {
         self.fsinfo_sector
     }
 
-    fn backup_boot_sector(&self) -> u16 {
-        self.backup_boot_sector
+    /// The only FAT in use, if mirroring is turned off in `extended_flags`.
+    fn active_fat(&self) -> Option<u8> {
+        // FAT12/16 have no such thing, that's already the boot record.
+        if self.fat_type() != FatType::Fat32 {
+            return None;
+        }
+        let flags = self.extended_flags();
+        (flags & 0x80 != 0).then_some((flags & 0xf) as u8)
+    }
+
+    /// Check the geometry makes sense, for a volume on a device of
+    /// `device_size` bytes.
+    fn validate(&self, device_size: u64) -> Result<(), VolumeError> {
+        let jmp_boot = self.jmp_boot();
+        if !(jmp_boot[0] == 0xeb && jmp_boot[2] == 0x90 || jmp_boot[0] == 0xe9) {
+            return Err(VolumeError::JumpBoot(jmp_boot));
+        }
+        let bytes_per_sector = self.bytes_per_sector();
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Err(VolumeError::BytesPerSector(bytes_per_sector));
+        }
+        let sectors_per_cluster = self.sectors_per_cluster();
+        if !sectors_per_cluster.is_power_of_two() || sectors_per_cluster > 128 {
+            return Err(VolumeError::SectorsPerCluster(sectors_per_cluster));
+        }
+        if self.reserved_sectors() == 0 {
+            return Err(VolumeError::NoReservedSectors);
+        }
+        if self.fats() == 0 {
+            return Err(VolumeError::NoFats);
+        }
+        if !matches!(self.media(), 0xf0 | 0xf8..=0xff) {
+            return Err(VolumeError::Media(self.media()));
+        }
+        if self.sectors_per_fat() == 0 {
+            return Err(VolumeError::FatTooSmall);
+        }
+
+        let total = self.total_sectors();
+        let first_data_sector = self.reserved_sectors() as u64
+            + self.fats() as u64 * self.sectors_per_fat() as u64
+            + self.root_dir_sectors() as u64;
+        if total as u64 * bytes_per_sector as u64 > device_size
+            || first_data_sector + sectors_per_cluster as u64 > total as u64
+        {
+            return Err(VolumeError::TotalSectors(total));
+        }
+
+        let fat_type = self.fat_type();
+        let clusters = self.cluster_count() as u64;
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let fat_entries = self.sectors_per_fat() as u64 * bytes_per_sector as u64 * 8 / fat_bits;
+        if fat_entries < clusters + 2 {
+            return Err(VolumeError::FatTooSmall);
+        }
+
+        if fat_type != FatType::Fat32 {
+            // The fixed root directory has to be there, and be whole sectors.
+            let root_bytes = self.max_root_dir_entries() as u32 * DIR_ENTRY_SIZE as u32;
+            if root_bytes == 0 || root_bytes % bytes_per_sector as u32 != 0 {
+                return Err(VolumeError::FatType(fat_type));
+            }
+            return Ok(());
+        }
+
+        if self.max_root_dir_entries() != 0
+            || self.total_sectors_16() != 0
+            || self.sectors_per_fat_16() != 0
+        {
+            return Err(VolumeError::FatType(fat_type));
+        }
+        if self.fat_version() != 0 {
+            return Err(VolumeError::Version(self.fat_version()));
+        }
+        let root = self.root_cluster();
+        if root < 2 || root as u64 >= clusters + 2 {
+            return Err(VolumeError::RootCluster(root));
+        }
+        let fsinfo = self.fsinfo_sector();
+        if fsinfo != 0 && fsinfo != 0xffff && fsinfo >= self.reserved_sectors() {
+            return Err(VolumeError::FsInfoSector(fsinfo));
+        }
+        match self.active_fat() {
+            Some(fat) if fat >= self.fats() => Err(VolumeError::ActiveFat(fat)),
+            _ => Ok(()),
+        }
+    }
+
+    fn total_sectors(&self) -> u32 {
+        match self.total_sectors_16() {
+            0 => self.total_sectors_32(),
+            total => total as u32,
+        }
+    }
+
+    fn sectors_per_fat(&self) -> u32 {
+        match self.sectors_per_fat_16() {
+            0 => self.sectors_per_fat_32(),
+            sectors => sectors as u32,
+        }
+    }
+
+    /// How big the FAT12/16 root directory is, 0 on FAT32.
+    fn root_dir_sectors(&self) -> u32 {
+        let bytes = self.max_root_dir_entries() as u32 * DIR_ENTRY_SIZE as u32;
+        div_ceil(bytes, self.bytes_per_sector() as u32)
+    }
+
+    fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors() as u32
+            + self.fats() as u32 * self.sectors_per_fat()
+            + self.root_dir_sectors()
+    }
+
+    /// The number of data clusters, numbered from 2.
+    fn cluster_count(&self) -> u32 {
+        (self.total_sectors() - self.first_data_sector()) / self.sectors_per_cluster() as u32
+    }
+
+    fn fat_type(&self) -> FatType {
+        FatType::from_cluster_count(self.cluster_count())
+    }
+}
+
+#[test_case]
+fn test_fat32_mounts_test_image() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    assert_eq!(fat32.check_fs(), Ok(()));
+    assert_eq!(fat32.uuid(), "1234-ABCD");
+    assert_eq!(fat32.label(), "TRANSPARENT");
+
+    // The root directory is fragmented.
+    let root = fat32.root_dir();
+    let clusters: Result<Vec<_>, _> = root.data_clusters(&mut fat32).collect();
+    assert_eq!(clusters, Ok(alloc::vec![2, 3, 38]));
+}
+
+#[test_case]
+fn test_fat32_read_root_dir() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let root = fat32.root_dir();
+    let entries: Vec<_> = fat32.read_dir(&root).collect::<Result<_, _>>().unwrap();
+
+    // No volume label, no deleted file.
+    assert_eq!(entries.len(), 3 + 23);
+    assert_eq!(entries[0].name(), "README.TXT");
+    assert_eq!(entries[0].size(), 1600);
+    assert_eq!(entries[0].first_cluster(), 4);
+    assert_eq!(entries[0].attributes(), Attributes::ARCHIVE);
+    let stamp = DateTime {
+        year: 2023,
+        month: 3,
+        day: 14,
+        hour: 12,
+        minute: 34,
+        second: 56,
+        millis: 0,
+    };
+    assert_eq!(entries[0].modified(), stamp);
+    assert_eq!(
+        entries[0].created(),
+        DateTime {
+            second: 57,
+            ..stamp
+        }
+    );
+    assert_eq!(
+        entries[0].accessed(),
+        DateTime {
+            hour: 0,
+            minute: 0,
+            second: 0,
+            ..stamp
+        }
+    );
+
+    assert_eq!(
+        entries[1].name(),
+        "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt"
+    );
+    assert_eq!(entries[1].short_name(), "THIS_I~1.TXT");
+    assert_eq!(entries[1].first_cluster(), 8);
+
+    assert_eq!(entries[2].name(), "TEST_DIR");
+    assert!(entries[2].is_dir());
+    assert_eq!(entries[2].first_cluster(), 12);
+
+    for (i, entry) in entries[3..].iter().enumerate() {
+        assert_eq!(entry.name(), format!("FILE{}.TXT", i + 1));
+        assert_eq!(entry.first_cluster(), 15 + i as u32);
     }
+}
+
+#[test_case]
+fn test_fat32_lookup() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+
+    let poem = fat32.lookup("/TEST_DIR/POEM.TXT").unwrap();
+    assert_eq!(poem.size(), 1400);
+    assert_eq!(poem.first_cluster(), 13);
+    assert_eq!(fat32.lookup("test_dir/poem.txt"), Ok(poem.clone()));
+    assert_eq!(fat32.lookup("/TEST_DIR/./POEM.TXT"), Ok(poem));
+
+    let long = fat32.lookup("/THIS_IS_A_FILE_WITH_REALLY_LOOOOOOOOOOOOOOOOG_NAME.TXT");
+    assert_eq!(long.map(|e| e.first_cluster()), Ok(8));
+    assert_eq!(
+        fat32.lookup("/this_i~1.txt").map(|e| e.first_cluster()),
+        Ok(8)
+    );
+
+    let readme = fat32.lookup("/TEST_DIR/../README.TXT").unwrap();
+    assert_eq!(readme.first_cluster(), 4);
+    assert!(fat32.open_dir("/TEST_DIR/..").unwrap().is_rootdir());
+    assert!(fat32.open_dir("/..").unwrap().is_rootdir());
+
+    let test_dir = fat32.open_dir("/TEST_DIR").unwrap();
+    let names: Vec<_> = fat32
+        .read_dir(&test_dir)
+        .map(|e| e.map(|e| e.name().to_string()))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(names, [".", "..", "POEM.TXT"]);
+
+    assert_eq!(fat32.lookup("/NOPE.TXT"), Err(FatError::NoSuchFile));
+    assert_eq!(fat32.lookup("/DELETED.TXT"), Err(FatError::NoSuchFile));
+    assert_eq!(
+        fat32.lookup("/README.TXT/POEM.TXT"),
+        Err(FatError::NotADirectory)
+    );
+    assert_eq!(fat32.open_dir("/README.TXT"), Err(FatError::NotADirectory));
+
+    // Only `..` stands for the root directory.
+    fat32.create("/EMPTY.TXT").unwrap();
+    let empty = fat32.lookup("/EMPTY.TXT").unwrap();
+    assert_eq!(empty.first_cluster(), 0);
+    assert!(!empty.is_rootdir());
+    let mut test_dir = fat32.lookup("/TEST_DIR").unwrap();
+    test_dir.first_cluster = 0;
+    fat32.update_entry(&test_dir).unwrap();
+    let test_dir = fat32.lookup("/TEST_DIR").unwrap();
+    assert!(test_dir.is_dir() && !test_dir.is_rootdir());
+    assert_eq!(fat32.read_dir(&test_dir).count(), 0);
+}
+
+#[test_case]
+fn test_fat32_read_file() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let lines = |name: &str, count: usize| -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| format!("{} line {:03}\n", name, i).into_bytes())
+            .collect()
+    };
 
-    fn drive_number(&self) -> u8 {
-        self.drive_number
+    // Odd sized reads, so they straddle sector boundaries.
+    let mut readme = fat32.open("/README.TXT").unwrap();
+    let mut data = Vec::new();
+    let mut buf = [0; 77];
+    loop {
+        let n = readme.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
     }
+    assert_eq!(data, lines("README", 100));
+
+    // The poem's last cluster is 39, after a jump in the chain.
+    let poem = lines("POEM", 100);
+    let mut file = fat32.open("/TEST_DIR/POEM.TXT").unwrap();
+    assert_eq!(file.seek(SeekFrom::Start(1020)), Ok(1020));
+    assert_eq!(file.read(&mut buf), Ok(77));
+    assert_eq!(&buf[..], &poem[1020..1097]);
+    assert_eq!(file.position(), 1097);
+    assert_eq!(file.seek(SeekFrom::Current(-1000)), Ok(97));
+    assert_eq!(file.read(&mut buf[..10]), Ok(10));
+    assert_eq!(&buf[..10], &poem[97..107]);
+    assert_eq!(file.seek(SeekFrom::End(-5)), Ok(1395));
+    assert_eq!(file.read(&mut buf), Ok(5));
+    assert_eq!(&buf[..5], &poem[1395..]);
+    assert_eq!(file.read(&mut buf), Ok(0));
+    assert_eq!(
+        file.seek(SeekFrom::Current(-2000)),
+        Err(FatError::InvalidSeek)
+    );
+    assert_eq!(file.position(), 1400);
 
-    fn boot_signature(&self) -> u8 {
-        self.boot_signature
+    assert!(matches!(
+        fat32.open("/TEST_DIR"),
+        Err(FatError::IsADirectory)
+    ));
+}
+
+#[test_case]
+fn test_fat32_write_file() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let stamp = || DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+        millis: 0,
+    };
+    fat32.set_clock(stamp);
+    assert_eq!(fat32.free_clusters(), Some(65600 - 38));
+
+    // Grow FILE1.TXT by two clusters, which come from the FSInfo hint.
+    let mut file = fat32.open("/FILE1.TXT").unwrap();
+    let more: Vec<u8> = (0..1100).map(|i| i as u8).collect();
+    file.seek(SeekFrom::End(0)).unwrap();
+    assert_eq!(file.write(&more), Ok(1100));
+    assert_eq!(file.size(), 1123);
+    assert_eq!(fat32.free_clusters(), Some(65600 - 40));
+    for fat in 0..2 {
+        let sector = 32 + fat * 513;
+        let block = fat32.read_block(sector).unwrap();
+        let entry = |c: usize| u32::from_le_bytes(block[c * 4..c * 4 + 4].try_into().unwrap());
+        assert_eq!((entry(15), entry(40), entry(41)), (40, 41, FAT_EOC));
     }
 
-    fn volume_id(&self) -> u32 {
-        self.volume_id
+    let entry = fat32.lookup("/FILE1.TXT").unwrap();
+    assert_eq!(entry.size(), 1123);
+    assert_eq!(entry.modified(), stamp());
+    let mut file = fat32.open("/FILE1.TXT").unwrap();
+    let mut buf = [0; 1200];
+    assert_eq!(file.read(&mut buf), Ok(1123));
+    assert_eq!(&buf[..23], b"hey, my name is file 1\n");
+    assert_eq!(&buf[23..1123], &more[..]);
+
+    // Overwrite in the middle, then write past the end.
+    file.seek(SeekFrom::Start(16)).unwrap();
+    assert_eq!(file.write(b"ONE!"), Ok(4));
+    file.seek(SeekFrom::Start(2000)).unwrap();
+    assert_eq!(file.write(b"end"), Ok(3));
+    assert_eq!(file.size(), 2003);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut buf = [0xff; 2003];
+    assert_eq!(file.read(&mut buf), Ok(2003));
+    assert_eq!(&buf[..23], b"hey, my name is ONE! 1\n");
+    assert!(buf[1123..2000].iter().all(|&b| b == 0));
+    assert_eq!(&buf[2000..], b"end");
+
+    // Truncating gives the clusters back.
+    file.set_len(10).unwrap();
+    assert_eq!(fat32.free_clusters(), Some(65600 - 38));
+    assert_eq!(fat32.lookup("/FILE1.TXT").map(|e| e.size()), Ok(10));
+    let mut file = fat32.open("/FILE2.TXT").unwrap();
+    file.set_len(0).unwrap();
+    assert_eq!(file.entry().first_cluster(), 0);
+    assert_eq!(fat32.free_clusters(), Some(65600 - 37));
+
+    fat32.sync().unwrap();
+    let fsinfo = fat32.read_block(1).unwrap();
+    assert_eq!(&fsinfo[488..492], &(65600u32 - 37).to_le_bytes());
+}
+
+#[test_case]
+fn test_fat32_namespace() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let free = fat32.free_clusters();
+    let names = |fat32: &mut Fat32<_>, path: &str| -> Vec<(String, String)> {
+        let dir = fat32.open_dir(path).unwrap();
+        fat32
+            .read_dir(&dir)
+            .map(|e| e.map(|e| (e.name().to_string(), e.short_name())))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+
+    // 8.3 names don't need a long name, not even in lower case.
+    let mut file = fat32.create("/TEST_DIR/NEW.TXT").unwrap();
+    assert_eq!(file.write(b"new"), Ok(3));
+    fat32.create("/TEST_DIR/lower.txt").unwrap();
+    fat32.create("/TEST_DIR/A long name.text").unwrap();
+    fat32.create("/TEST_DIR/a LONG name.text2").unwrap();
+    assert_eq!(
+        names(&mut fat32, "/TEST_DIR")[3..],
+        [
+            ("NEW.TXT".into(), "NEW.TXT".into()),
+            ("lower.txt".into(), "LOWER.TXT".into()),
+            ("A long name.text".into(), "ALONGN~1.TEX".into()),
+            ("a LONG name.text2".into(), "ALONGN~2.TEX".into()),
+        ]
+    );
+    let mut buf = [0; 8];
+    let mut file = fat32.open("/test_dir/new.txt").unwrap();
+    assert_eq!(file.read(&mut buf), Ok(3));
+    assert!(matches!(
+        fat32.create("/TEST_DIR/NEW.txt"),
+        Err(FatError::AlreadyExists)
+    ));
+    assert!(matches!(
+        fat32.create("/TEST_DIR/a:b"),
+        Err(FatError::InvalidName)
+    ));
+
+    // Directories, enough files in one to make it grow.
+    let dir = fat32.mkdir("/TEST_DIR/subdir").unwrap();
+    for i in 0..20 {
+        fat32
+            .create(&format!("/TEST_DIR/subdir/file number {}", i))
+            .unwrap();
     }
+    let entries = names(&mut fat32, "/TEST_DIR/subdir");
+    assert_eq!(entries.len(), 22);
+    assert_eq!(entries[0].0, ".");
+    assert_eq!(entries[1].0, "..");
+    assert_eq!(entries[10].1, "FILENU~9");
+    assert_eq!(entries[21].1, "FILEN~20");
+    assert_eq!(
+        fat32
+            .lookup("/TEST_DIR/subdir/.")
+            .map(|e| e.first_cluster()),
+        Ok(dir.first_cluster())
+    );
+    assert_eq!(
+        fat32
+            .lookup("/TEST_DIR/subdir/..")
+            .map(|e| e.first_cluster()),
+        Ok(12)
+    );
+    assert_eq!(
+        fat32.rmdir("/TEST_DIR/subdir"),
+        Err(FatError::DirectoryNotEmpty)
+    );
+    for i in 0..20 {
+        fat32
+            .remove(&format!("/TEST_DIR/subdir/FILE NUMBER {}", i))
+            .unwrap();
+    }
+    assert_eq!(
+        fat32.remove("/TEST_DIR/subdir"),
+        Err(FatError::IsADirectory)
+    );
+    fat32.rmdir("/TEST_DIR/subdir").unwrap();
+    assert_eq!(fat32.lookup("/TEST_DIR/subdir"), Err(FatError::NoSuchFile));
+
+    // Renames, across directories too.
+    fat32
+        .rename("/README.TXT", "/TEST_DIR/read me.txt")
+        .unwrap();
+    assert_eq!(fat32.lookup("/README.TXT"), Err(FatError::NoSuchFile));
+    let moved = fat32.lookup("/TEST_DIR/READ ME.TXT").unwrap();
+    assert_eq!((moved.first_cluster(), moved.size()), (4, 1600));
+    fat32
+        .rename("/TEST_DIR/read me.txt", "/TEST_DIR/READ ME.TXT")
+        .unwrap();
+    fat32.mkdir("/other").unwrap();
+    fat32.rename("/TEST_DIR", "/other/moved").unwrap();
+    assert_eq!(
+        fat32.lookup("/other/moved/POEM.TXT").map(|e| e.size()),
+        Ok(1400)
+    );
+    let other = fat32.lookup("/other").unwrap();
+    let dotdot = fat32.lookup("/other/moved/..").unwrap();
+    assert_eq!(dotdot.first_cluster(), other.first_cluster());
+    assert_eq!(
+        fat32.rename("/other", "/other/moved/x"),
+        Err(FatError::InvalidName)
+    );
 
-    fn volume_label(&self) -> [u8; 11] {
-        self.volume_label
+    // Everything that's gone gave its clusters back.
+    fat32.remove("/other/moved/NEW.TXT").unwrap();
+    fat32.rename("/other/moved", "/TEST_DIR").unwrap();
+    fat32.rmdir("/other").unwrap();
+    assert_eq!(fat32.free_clusters(), free);
+}
+
+#[test_case]
+fn test_fat32_big_sectors() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_4k_test_image()).unwrap();
+    assert_eq!(fat32.check_fs(), Ok(()));
+    assert_eq!(fat32.bytes_per_cluster(), 32768);
+    assert_eq!(fat32.root_dir().first_cluster(), 5);
+
+    // BIG.TXT goes on from cluster 7 to cluster 3, and only the second FAT
+    // says so.
+    let big: Vec<u8> = (0..3000)
+        .flat_map(|i| format!("BIG line {:03}\n", i).into_bytes())
+        .collect();
+    let mut file = fat32.open("/BIG.TXT").unwrap();
+    let mut data = alloc::vec![0; big.len() + 1];
+    assert_eq!(file.read(&mut data), Ok(big.len()));
+    assert_eq!(&data[..big.len()], &big[..]);
+
+    let mut file = fat32.create("/NEW.TXT").unwrap();
+    assert_eq!(file.write(&big), Ok(big.len()));
+    let first = file.entry().first_cluster();
+    for (fat, entry) in [(0, 0), (1, first + 1)] {
+        let mut bytes = [0; 4];
+        fat32
+            .read_bytes(fat32.fat_start(fat), first * 4, &mut bytes)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(bytes), entry);
     }
+    let mut file = fat32.open("/NEW.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(big.len()));
+    assert_eq!(&data[..big.len()], &big[..]);
+}
+
+/// What the FAT12 and FAT16 test images have in common.
+#[cfg(test)]
+fn check_small_fat<B: BlockDevice>(fat: &mut Fat32<B>) {
+    assert_eq!(fat.check_fs(), Ok(()));
+    assert_eq!(fat.uuid(), "1234-ABCD");
+    assert_eq!(fat.label(), "TRANSPARENT");
+
+    let root = fat.root_dir();
+    let names: Vec<_> = fat
+        .read_dir(&root)
+        .map(|e| e.unwrap().name().to_string())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "README.TXT",
+            "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt",
+            "TEST_DIR"
+        ]
+    );
+
+    let readme: Vec<u8> = (0..100)
+        .flat_map(|i| format!("README line {:03}\n", i).into_bytes())
+        .collect();
+    let mut data = alloc::vec![0; 4096];
+    let mut file = fat.open("/README.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(readme.len()));
+    assert_eq!(&data[..readme.len()], &readme[..]);
+    let poem: Vec<u8> = (0..100)
+        .flat_map(|i| format!("POEM line {:03}\n", i).into_bytes())
+        .collect();
+    let mut file = fat.open("/test_dir/poem.txt").unwrap();
+    assert_eq!(file.read(&mut data), Ok(poem.len()));
+    assert_eq!(&data[..poem.len()], &poem[..]);
+    let mut file = fat
+        .open("/this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt")
+        .unwrap();
+    assert_eq!(file.read(&mut data), Ok(6));
+    assert_eq!(&data[..6], b"hello\n");
+    assert_eq!(
+        fat.lookup("/TEST_DIR/..").unwrap().first_cluster(),
+        root.first_cluster()
+    );
 
-    fn fat_type_label(&self) -> [u8; 8] {
-        self.fat_type_label
+    // Growing a file and a new directory in the root.
+    let mut file = fat.open("/README.TXT").unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    assert_eq!(file.write(&readme), Ok(readme.len()));
+    fat.mkdir("/NEW_DIR").unwrap();
+    let mut file = fat.create("/NEW_DIR/POEM.TXT").unwrap();
+    assert_eq!(file.write(&poem), Ok(poem.len()));
+    assert_eq!(
+        fat.lookup("/NEW_DIR/..").unwrap().first_cluster(),
+        root.first_cluster()
+    );
+
+    let mut file = fat.open("/README.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(2 * readme.len()));
+    assert_eq!(&data[..readme.len()], &readme[..]);
+    assert_eq!(&data[readme.len()..2 * readme.len()], &readme[..]);
+    let mut file = fat.open("/new_dir/poem.txt").unwrap();
+    assert_eq!(file.read(&mut data), Ok(poem.len()));
+    assert_eq!(&data[..poem.len()], &poem[..]);
+}
+
+#[test_case]
+fn test_fat16() {
+    let mut fat16 = Fat32::new(crate::ramdisk::fat16_test_image()).unwrap();
+    assert_eq!(fat16.fat_type(), FatType::Fat16);
+    assert_eq!(fat16.cluster_count(), 5000);
+    check_small_fat(&mut fat16);
+}
+
+#[test_case]
+fn test_fat12() {
+    let mut fat12 = Fat32::new(crate::ramdisk::fat12_test_image()).unwrap();
+    assert_eq!(fat12.fat_type(), FatType::Fat12);
+    assert_eq!(fat12.cluster_count(), 2847);
+
+    // README.TXT goes backwards, so the chain has odd and even entries.
+    let readme = fat12.lookup("/README.TXT").unwrap();
+    let clusters: Result<Vec<_>, _> = readme.data_clusters(&mut fat12).collect();
+    assert_eq!(clusters, Ok(alloc::vec![6, 5, 4, 3]));
+    check_small_fat(&mut fat12);
+
+    // Writing an entry leaves the one sharing its middle byte alone.
+    fat12.set_fat_entry(100, 0xabc).unwrap();
+    fat12.set_fat_entry(101, 0x123).unwrap();
+    assert_eq!(fat12.get_fat_entry(100).unwrap().entry, 0xabc);
+    assert_eq!(fat12.get_fat_entry(101).unwrap().entry, 0x123);
+    assert_eq!(fat12.get_fat_entry(102).unwrap().entry, 0);
+    fat12.set_fat_entry(101, 0xff7).unwrap();
+    assert!(fat12.get_fat_entry(101).unwrap().is_bad());
+    assert_eq!(fat12.get_fat_entry(100).unwrap().entry, 0xabc);
+    fat12.set_fat_entry(100, 0).unwrap();
+    fat12.set_fat_entry(101, 0).unwrap();
+
+    // The root directory has room for 224 entries, and can't grow.
+    let used = fat12.read_dir(&fat12.root_dir()).count();
+    let mut created = 0;
+    let err = loop {
+        match fat12.create(&format!("/F{}", created)) {
+            Ok(_) => created += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err, FatError::NoSpace);
+    // The label and the long name's 5 slots don't show up in `read_dir`.
+    assert_eq!(used + 1 + 5 + created, 224);
+}
+
+#[test_case]
+fn test_fsck_clean() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let report = fat32.fsck(false).unwrap();
+    assert_eq!(report.problems, []);
+    assert_eq!((report.files, report.dirs), (26, 2));
+    assert_eq!(report.used_clusters, 38);
+    assert_eq!(Some(report.free_clusters), fat32.free_clusters());
+
+    for disk in [
+        crate::ramdisk::fat32_4k_test_image(),
+        crate::ramdisk::fat16_test_image(),
+        crate::ramdisk::fat12_test_image(),
+    ] {
+        let mut fat = Fat32::new(disk).unwrap();
+        let report = fat.fsck(true).unwrap();
+        assert!(report.is_clean(), "{}", report);
+        assert!(!report.repaired);
     }
 }
+
+#[test_case]
+fn test_fsck_repairs() {
+    let mut fat32 = Fat32::new(crate::ramdisk::fat32_test_image()).unwrap();
+    let free = fat32.free_clusters().unwrap();
+    let long_name = "this_is_a_file_with_really_lOOOOOOOOOOOOOOOOg_name.txt";
+    let long = fat32.lookup(long_name).unwrap();
+
+    // The long name file runs on into README.TXT.
+    fat32.set_fat_entry(11, 7).unwrap();
+    // And its long name no longer matches.
+    let loc = long.lfn_loc.unwrap();
+    let mut slot = fat32.read_dir_slot(loc).unwrap();
+    slot[13] ^= 0xff;
+    fat32.write_dir_slot(loc, &slot).unwrap();
+    // FILE1.TXT takes the cluster of FILE2.TXT on top of its own.
+    fat32.set_fat_entry(15, 16).unwrap();
+    fat32.set_fat_entry(17, 0).unwrap();
+    fat32.set_fat_entry(18, FAT_BAD).unwrap();
+    let mut file7 = fat32.lookup("/FILE7.TXT").unwrap();
+    file7.size = 5000;
+    fat32.update_entry(&file7).unwrap();
+    fat32.set_fat_entry(39, 13).unwrap();
+    fat32.set_fat_entry(100, 101).unwrap();
+    fat32.set_fat_entry(101, FAT_EOC).unwrap();
+    // Only in the second FAT.
+    fat32
+        .write_bytes(fat32.fat_start(1), 4 * 200, &[1, 2, 3, 4])
+        .unwrap();
+
+    let path = |p: &str| p.to_string();
+    let mut expected = alloc::vec![
+        Problem::FatsDiffer { fat: 1, sectors: 1 },
+        Problem::BadLongName {
+            dir: path("/"),
+            slots: 5
+        },
+        Problem::CrossLinked {
+            path: path("/THIS_I~1.TXT"),
+            cluster: 7
+        },
+        Problem::ChainTooLong {
+            path: path("/FILE1.TXT"),
+            clusters: 2,
+            size: 23
+        },
+        Problem::EndsInFree {
+            path: path("/FILE3.TXT"),
+            cluster: 17
+        },
+        Problem::EndsInBad {
+            path: path("/FILE4.TXT"),
+            cluster: 18
+        },
+        Problem::ChainTooShort {
+            path: path("/FILE7.TXT"),
+            clusters: 1,
+            size: 5000
+        },
+        Problem::Loop {
+            path: path("/TEST_DIR/POEM.TXT"),
+            cluster: 13
+        },
+        Problem::LostChain {
+            first: 100,
+            clusters: 2
+        },
+        // The lost chain isn't free, the end of FILE3.TXT is.
+        Problem::FreeCount {
+            recorded: free,
+            actual: free - 2 + 1
+        },
+    ];
+    let report = fat32.fsck(false).unwrap();
+    assert_eq!(report.problems, expected);
+    assert!(!report.repaired);
+
+    // Once the lost chain is freed and FILE3.TXT ends properly, the count
+    // is right again.
+    expected.pop();
+    let report = fat32.fsck(true).unwrap();
+    assert_eq!(report.problems, expected);
+    assert!(report.repaired);
+    let report = fat32.fsck(false).unwrap();
+    assert!(report.is_clean(), "{}", report);
+    assert_eq!(Some(report.free_clusters), fat32.free_clusters());
+
+    let mut data = alloc::vec![0; 2000];
+    let mut file = fat32.open("/THIS_I~1.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(1600));
+    assert_eq!(&data[..16], b"README line 000\n");
+    let mut file = fat32.open("/TEST_DIR/POEM.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(1400));
+    assert_eq!(&data[1400 - 14..1400], b"POEM line 099\n");
+    let mut file = fat32.open("/FILE2.TXT").unwrap();
+    assert_eq!(file.read(&mut data), Ok(23));
+    assert_eq!(&data[..23], b"hey, my name is file 2\n");
+    assert_eq!(fat32.lookup("/FILE1.TXT").unwrap().size(), 23);
+    assert_eq!(fat32.lookup("/FILE4.TXT").unwrap().size(), 0);
+    assert_eq!(fat32.lookup("/FILE7.TXT").unwrap().size(), 512);
+}
+
+#[test_case]
+fn test_fat32_dirty_flag() {
+    let mut disk = crate::ramdisk::fat32_test_image();
+    let mut fat32 = Fat32::new(&mut disk).unwrap();
+    // Reading doesn't mark the volume as in use.
+    fat32.lookup("/TEST_DIR/POEM.TXT").unwrap();
+    fat32.sync().unwrap();
+    assert!(fat32.is_clean().unwrap());
+    // Neither does a mkdir that fails.
+    let free = fat32.free_clusters();
+    assert_eq!(fat32.mkdir("/TEST_DIR"), Err(FatError::AlreadyExists));
+    assert_eq!(fat32.mkdir("/BAD:NAME"), Err(FatError::InvalidName));
+    assert!(fat32.is_clean().unwrap());
+    assert_eq!(fat32.free_clusters(), free);
+    fat32.mkdir("/NEW_DIR").unwrap();
+    assert!(!fat32.is_clean().unwrap());
+    fat32.unmount().unwrap();
+
+    let mut fat32 = Fat32::new(&mut disk).unwrap();
+    assert!(fat32.is_clean().unwrap());
+    fat32.create("/NEW_DIR/FILE.TXT").unwrap();
+    assert!(!fat32.is_clean().unwrap());
+    // Gone without unmounting, but everything was written back first.
+    fat32.sync().unwrap();
+    drop(fat32);
+
+    // So it's checked, and there's nothing wrong with it.
+    let mut fat32 = Fat32::new(&mut disk).unwrap();
+    assert!(!fat32.needs_repair());
+    fat32.lookup("/NEW_DIR/FILE.TXT").unwrap();
+    fat32.remove("/NEW_DIR/FILE.TXT").unwrap();
+    fat32.unmount().unwrap();
+    assert!(Fat32::new(&mut disk).unwrap().is_clean().unwrap());
+
+    // FAT16 has the flag too, FAT12 doesn't.
+    let mut disk = crate::ramdisk::fat16_test_image();
+    let mut fat16 = Fat32::new(&mut disk).unwrap();
+    fat16.mkdir("/NEW_DIR").unwrap();
+    assert_eq!(fat16.read_fat_entry(1).unwrap() & FAT16_CLEAN, 0);
+    fat16.unmount().unwrap();
+    let mut fat16 = Fat32::new(&mut disk).unwrap();
+    assert_eq!(fat16.read_fat_entry(1).unwrap() & FAT16_CLEAN, FAT16_CLEAN);
+
+    let mut disk = crate::ramdisk::fat12_test_image();
+    let mut fat12 = Fat32::new(&mut disk).unwrap();
+    let before = fat12.read_fat_entry(1).unwrap();
+    fat12.mkdir("/NEW_DIR").unwrap();
+    assert_eq!(fat12.read_fat_entry(1).unwrap(), before);
+    drop(fat12);
+    assert!(!Fat32::new(&mut disk).unwrap().needs_repair());
+}
+
+#[test_case]
+fn test_fat32_interrupted_write() {
+    let mut disk = crate::ramdisk::fat32_test_image();
+    let mut fat32 = Fat32::new(&mut disk).unwrap();
+    let free = fat32.free_clusters().unwrap();
+    let mut file = fat32.create("/CRASH.TXT").unwrap();
+    file.write(b"before the crash\n").unwrap();
+    // Stop a write right after its clusters are allocated, the way a crash
+    // would.
+    assert_eq!(file.reserve(3000), Ok(3000));
+    let first = file.entry.first_cluster;
+    drop(fat32);
+
+    // The file doesn't know about the new clusters, they're just lost.
+    let mut fat32 = Fat32::new(&mut disk).unwrap();
+    assert!(fat32.needs_repair());
+    assert_eq!(
+        fat32.create("/OTHER.TXT").err(),
+        Some(FatError::NeedsRepair)
+    );
+    assert_eq!(fat32.lookup("/CRASH.TXT").unwrap().first_cluster(), first);
+    let report = fat32.fsck(true).unwrap();
+    assert_eq!(
+        report.problems[0],
+        Problem::ChainTooLong {
+            path: "/CRASH.TXT".to_string(),
+            clusters: 6,
+            size: 17
+        }
+    );
+    assert!(!fat32.needs_repair());
+    assert_eq!(fat32.free_clusters(), Some(free - 1));
+
+    let mut file = fat32.open("/CRASH.TXT").unwrap();
+    let mut data = [0; 32];
+    assert_eq!(file.read(&mut data), Ok(17));
+    assert_eq!(&data[..17], b"before the crash\n");
+    fat32.create("/OTHER.TXT").unwrap();
+    fat32.unmount().unwrap();
+    assert!(!Fat32::new(&mut disk).unwrap().needs_repair());
+}
+
+#[test_case]
+fn test_fat32_rejects_bad_geometry() {
+    let corrupt = |offset: usize, bytes: &[u8]| {
+        let mut disk = crate::ramdisk::fat32_test_image();
+        let mut sector = [0; 512];
+        disk.read(0, &mut sector).unwrap();
+        sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+        disk.write(0, &sector).unwrap();
+        Fat32::new(disk).err()
+    };
+    let invalid = |e| Some(FatError::InvalidVolume(e));
+
+    assert_eq!(
+        corrupt(11, &1000u16.to_le_bytes()),
+        invalid(VolumeError::BytesPerSector(1000))
+    );
+    assert_eq!(
+        corrupt(13, &[3]),
+        invalid(VolumeError::SectorsPerCluster(3))
+    );
+    assert_eq!(
+        corrupt(17, &512u16.to_le_bytes()),
+        invalid(VolumeError::FatType(FatType::Fat32))
+    );
+    assert_eq!(
+        corrupt(44, &[0, 0, 0, 0]),
+        invalid(VolumeError::RootCluster(0))
+    );
+    assert_eq!(corrupt(40, &[0x82, 0]), invalid(VolumeError::ActiveFat(2)));
+    assert_eq!(
+        corrupt(36, &[1, 0, 0, 0]),
+        invalid(VolumeError::FatTooSmall)
+    );
+    assert_eq!(
+        corrupt(32, &u32::MAX.to_le_bytes()),
+        invalid(VolumeError::TotalSectors(u32::MAX))
+    );
+}
@@ -0,0 +1,352 @@
+//! Futexes, keyed by physical address.
+//!
+//! Keying by physical rather than virtual address means two address spaces
+//! that map the same frame (shared mappings, threads created by `clone`)
+//! end up on the same wait queue.
+//!
+//! In the kernel, `wait` "sleeps" by `wfi`-ing the hart until someone
+//! (usually an interrupt handler) wakes it up or the timeout expires. User
+//! threads don't get to hold the hart like that, the `futex` syscall queues
+//! a [`Waiter`] and blocks the thread on it instead, see `thread::block`.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use riscv::{
+    asm::wfi,
+    register::{satp, time},
+};
+use spin::Mutex;
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    memory, timer,
+    trap::without_interrupts,
+};
+
+// Operation numbers, compatible with Linux's `futex(2)`.
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+pub const FUTEX_REQUEUE: usize = 3;
+pub const FUTEX_CMP_REQUEUE: usize = 4;
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FutexError {
+    /// The futex word didn't hold the expected value.
+    WouldBlock,
+
+    /// The timeout expired before we got woken up.
+    TimedOut,
+
+    /// The address is not mapped, or not aligned to 4 bytes.
+    Fault,
+
+    /// Unknown or unsupported futex operation.
+    InvalidOp,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FutexOp {
+    Wait,
+    Wake,
+    Requeue,
+    CmpRequeue,
+}
+
+impl FutexOp {
+    pub fn from_raw(op: usize) -> Option<FutexOp> {
+        use FutexOp::*;
+        // Every futex is keyed by physical address, so private and shared
+        // futexes behave the same.
+        Some(match op & !FUTEX_PRIVATE_FLAG {
+            FUTEX_WAIT => Wait,
+            FUTEX_WAKE => Wake,
+            FUTEX_REQUEUE => Requeue,
+            FUTEX_CMP_REQUEUE => CmpRequeue,
+            _ => return None,
+        })
+    }
+}
+
+pub struct Waiter {
+    /// The futex this waiter is currently queued on.
+    /// Changes when the waiter gets requeued.
+    key: AtomicU64,
+    woken: AtomicBool,
+}
+
+impl Waiter {
+    pub fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
+
+lazy_static! {
+    static ref FUTEXES: Mutex<BTreeMap<PhysAddr, VecDeque<Arc<Waiter>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// The `futex` "syscall", dispatching on `op`.
+///
+/// `timeout` is only used by `FUTEX_WAIT`, `val2` (the maximum number of
+/// waiters to requeue) and `uaddr2` only by `FUTEX_(CMP_)REQUEUE`.
+///
+/// Returns the number of woken (and requeued) waiters for `FUTEX_WAKE`
+/// and `FUTEX_(CMP_)REQUEUE`, and 0 for `FUTEX_WAIT`.
+pub fn futex(
+    uaddr: VirtAddr,
+    op: usize,
+    val: u32,
+    timeout: Option<Duration>,
+    val2: usize,
+    uaddr2: VirtAddr,
+    val3: u32,
+) -> Result<usize, FutexError> {
+    match FutexOp::from_raw(op).ok_or(FutexError::InvalidOp)? {
+        FutexOp::Wait => wait(uaddr, val, timeout).map(|_| 0),
+        FutexOp::Wake => wake(uaddr, val as usize),
+        FutexOp::Requeue => requeue(uaddr, val as usize, uaddr2, val2, None),
+        FutexOp::CmpRequeue => requeue(uaddr, val as usize, uaddr2, val2, Some(val3)),
+    }
+}
+
+/// Sleep on `uaddr` if it still holds `expected`, until woken up by
+/// [`wake`] or until `timeout` expires.
+pub fn wait(uaddr: VirtAddr, expected: u32, timeout: Option<Duration>) -> Result<(), FutexError> {
+    let deadline = timeout.map(timer::ticks_after);
+    let waiter = queue(uaddr, expected)?;
+    loop {
+        let ret = without_interrupts(|| {
+            if waiter.is_woken() {
+                return Some(Ok(()));
+            }
+            if matches!(deadline, Some(deadline) if time::read64() >= deadline) {
+                cancel(&waiter);
+                return Some(Err(FutexError::TimedOut));
+            }
+
+            // `wfi` returns on a pending interrupt even with interrupts
+            // disabled, which is then taken as soon as we leave
+            // `without_interrupts`. So we can't miss a wakeup.
+            // The timer interrupt makes sure we get to check the deadline.
+            unsafe { wfi() };
+            None
+        });
+        if let Some(ret) = ret {
+            return ret;
+        }
+    }
+}
+
+/// Queue a waiter on `uaddr` if it still holds `expected`, without waiting
+/// for it to be woken up. Until it is, the waiter has to be `cancel`led if
+/// the caller gives up on it.
+pub fn queue(uaddr: VirtAddr, expected: u32) -> Result<Arc<Waiter>, FutexError> {
+    let key = futex_key(uaddr)?;
+    // Checking the value and queueing must be atomic with respect to
+    // wakers, which may well be interrupt handlers.
+    without_interrupts(|| {
+        if load(key) != expected {
+            return Err(FutexError::WouldBlock);
+        }
+        let waiter = Arc::new(Waiter {
+            key: AtomicU64::new(key.as_u64()),
+            woken: AtomicBool::new(false),
+        });
+        FUTEXES
+            .lock()
+            .entry(key)
+            .or_default()
+            .push_back(waiter.clone());
+        Ok(waiter)
+    })
+}
+
+/// Wake up at most `count` waiters sleeping on `uaddr`.
+///
+/// Returns the number of waiters woken up.
+pub fn wake(uaddr: VirtAddr, count: usize) -> Result<usize, FutexError> {
+    let key = futex_key(uaddr)?;
+    Ok(without_interrupts(|| {
+        wake_locked(&mut FUTEXES.lock(), key, count)
+    }))
+}
+
+/// Wake up at most `count` waiters on `uaddr`, and move at most `limit` of
+/// the remaining ones over to `uaddr2`.
+///
+/// If `expected` is given, `uaddr` must still hold it, otherwise nothing
+/// is done and `WouldBlock` is returned.
+///
+/// Returns the number of waiters woken up and requeued.
+pub fn requeue(
+    uaddr: VirtAddr,
+    count: usize,
+    uaddr2: VirtAddr,
+    limit: usize,
+    expected: Option<u32>,
+) -> Result<usize, FutexError> {
+    let key = futex_key(uaddr)?;
+    let key2 = futex_key(uaddr2)?;
+
+    without_interrupts(|| {
+        if matches!(expected, Some(expected) if load(key) != expected) {
+            return Err(FutexError::WouldBlock);
+        }
+
+        let mut futexes = FUTEXES.lock();
+        let woken = wake_locked(&mut futexes, key, count);
+        if key == key2 {
+            return Ok(woken);
+        }
+
+        let mut moved = VecDeque::new();
+        if let Some(queue) = futexes.get_mut(&key) {
+            while moved.len() < limit {
+                match queue.pop_front() {
+                    Some(waiter) => {
+                        waiter.key.store(key2.as_u64(), Ordering::Relaxed);
+                        moved.push_back(waiter);
+                    }
+                    None => break,
+                }
+            }
+            if queue.is_empty() {
+                futexes.remove(&key);
+            }
+        }
+
+        let requeued = moved.len();
+        if requeued > 0 {
+            futexes.entry(key2).or_default().append(&mut moved);
+        }
+        Ok(woken + requeued)
+    })
+}
+
+fn wake_locked(
+    futexes: &mut BTreeMap<PhysAddr, VecDeque<Arc<Waiter>>>,
+    key: PhysAddr,
+    count: usize,
+) -> usize {
+    let queue = match futexes.get_mut(&key) {
+        Some(queue) => queue,
+        None => return 0,
+    };
+
+    let mut woken = 0;
+    while woken < count {
+        match queue.pop_front() {
+            Some(waiter) => {
+                waiter.woken.store(true, Ordering::Release);
+                woken += 1;
+            }
+            None => break,
+        }
+    }
+
+    if queue.is_empty() {
+        futexes.remove(&key);
+    }
+    woken
+}
+
+/// Remove a (timed out) waiter from whatever queue it's on.
+pub fn cancel(waiter: &Arc<Waiter>) {
+    let key = PhysAddr::new(waiter.key.load(Ordering::Relaxed));
+    let mut futexes = FUTEXES.lock();
+    if let Some(queue) = futexes.get_mut(&key) {
+        queue.retain(|w| !Arc::ptr_eq(w, waiter));
+        if queue.is_empty() {
+            futexes.remove(&key);
+        }
+    }
+}
+
+fn futex_key(uaddr: VirtAddr) -> Result<PhysAddr, FutexError> {
+    if uaddr.as_u64() % 4 != 0 {
+        return Err(FutexError::Fault);
+    }
+    // Before paging is enabled, every address is a physical one.
+    if satp::read().mode() == satp::Mode::Bare {
+        return Ok(PhysAddr::new(uaddr.as_u64()));
+    }
+    unsafe { memory::translate_addr(uaddr) }.ok_or(FutexError::Fault)
+}
+
+fn load(key: PhysAddr) -> u32 {
+    // Physical memory is identity mapped.
+    unsafe { core::ptr::read_volatile(key.as_u64() as *const u32) }
+}
+
+#[test_case]
+fn test_futex_wake_and_requeue() {
+    use core::sync::atomic::AtomicU32;
+
+    static WORDS: [AtomicU32; 2] = [AtomicU32::new(1), AtomicU32::new(2)];
+    let uaddr = |i: usize| VirtAddr::new(&WORDS[i] as *const AtomicU32 as u64);
+
+    let waiters: alloc::vec::Vec<_> = (0..4).map(|_| queue(uaddr(0), 1).unwrap()).collect();
+    assert_eq!(queue(uaddr(0), 2).err(), Some(FutexError::WouldBlock));
+    assert_eq!(wake(uaddr(0), 1), Ok(1));
+    assert!(waiters[0].is_woken() && !waiters[1].is_woken());
+
+    // Wake one more and move one of the other two over.
+    assert_eq!(
+        requeue(uaddr(0), 1, uaddr(1), 1, Some(2)),
+        Err(FutexError::WouldBlock)
+    );
+    assert_eq!(requeue(uaddr(0), 1, uaddr(1), 1, Some(1)), Ok(2));
+    assert!(waiters[1].is_woken() && !waiters[2].is_woken());
+    assert_eq!(wake(uaddr(1), usize::MAX), Ok(1));
+    assert!(waiters[2].is_woken() && !waiters[3].is_woken());
+    assert_eq!(
+        futex(
+            uaddr(0),
+            FUTEX_WAKE | FUTEX_PRIVATE_FLAG,
+            5,
+            None,
+            0,
+            uaddr(0),
+            0
+        ),
+        Ok(1)
+    );
+    assert_eq!(wake(uaddr(0), 1), Ok(0));
+}
+
+#[test_case]
+fn test_futex_wait() {
+    use core::sync::atomic::AtomicU32;
+
+    static WORD: AtomicU32 = AtomicU32::new(7);
+    let uaddr = VirtAddr::new(&WORD as *const AtomicU32 as u64);
+
+    assert_eq!(wait(uaddr, 8, None), Err(FutexError::WouldBlock));
+    assert_eq!(
+        wait(uaddr, 7, Some(Duration::ZERO)),
+        Err(FutexError::TimedOut)
+    );
+    // Timing out took the waiter off the queue.
+    assert_eq!(wake(uaddr, 1), Ok(0));
+
+    let waiter = queue(uaddr, 7).unwrap();
+    cancel(&waiter);
+    assert_eq!(wake(uaddr, 1), Ok(0));
+    assert!(!waiter.is_woken());
+
+    let unaligned = VirtAddr::new(uaddr.as_u64() + 2);
+    assert_eq!(wake(unaligned, 1), Err(FutexError::Fault));
+    assert_eq!(
+        futex(uaddr, 42, 0, None, 0, uaddr, 0),
+        Err(FutexError::InvalidOp)
+    );
+}
@@ -0,0 +1,283 @@
+//! A block device that checksums every block it stores.
+//!
+//! The end of the underlying device is reserved for metadata: a CRC32C of
+//! every data block, followed by a header in the very last block. Every
+//! read is verified against the stored checksum, and a mismatch is
+//! reported as `BlockError::Checksum` instead of handing out bad data.
+//!
+//! ```text
+//! | data blocks ... | checksum blocks ... | header |
+//! ```
+//!
+//! All checksums are kept in memory while the device is open, and written
+//! through on every write, after the data.
+
+use alloc::{boxed::Box, format, vec, vec::Vec};
+
+use log::{info, warn};
+
+use crate::{
+    blkdev::{self, RegistryError},
+    block::{check_range, BlockDevice, BlockError, BlockStats},
+    cmdline,
+    crc::crc32c,
+};
+
+const MAGIC: &[u8; 8] = b"BLKCSUM1";
+
+pub struct Integrity<B>
+where
+    B: BlockDevice,
+{
+    dev: B,
+    data_blocks: usize,
+    sums: Vec<u32>,
+}
+
+impl<B> Integrity<B>
+where
+    B: BlockDevice,
+{
+    /// Lay out checksums on `dev`, covering whatever the data blocks hold
+    /// right now.
+    pub fn format(mut dev: B) -> Result<Self, BlockError> {
+        let data_blocks = data_blocks(dev.block_size(), dev.block_count())?;
+        let mut sums = Vec::with_capacity(data_blocks);
+        let mut buf = vec![0; dev.block_size()];
+        for blk_id in 0..data_blocks {
+            dev.read(blk_id, &mut buf)?;
+            sums.push(crc32c(&buf));
+        }
+
+        let mut integrity = Integrity {
+            dev,
+            data_blocks,
+            sums,
+        };
+        for meta_blk in 0..integrity.sum_blocks() {
+            integrity.write_sums(meta_blk)?;
+        }
+        integrity.write_header()?;
+        integrity.dev.flush()?;
+        Ok(integrity)
+    }
+
+    /// Open a device `format`ted before.
+    ///
+    /// Returns `Unsupported` if it never was, and `Checksum` if the
+    /// metadata itself is corrupt.
+    pub fn open(mut dev: B) -> Result<Self, BlockError> {
+        let block_size = dev.block_size();
+        let data_blocks = data_blocks(block_size, dev.block_count())?;
+        let mut buf = vec![0; block_size];
+        dev.read(dev.block_count() - 1, &mut buf)?;
+        if &buf[..8] != MAGIC {
+            return Err(BlockError::Unsupported);
+        }
+        if le_u32(&buf[16..]) != crc32c(&buf[..16]) {
+            return Err(BlockError::Checksum);
+        }
+        if le_u64(&buf[8..]) as usize != data_blocks {
+            // Formatted for a device of another size.
+            return Err(BlockError::Checksum);
+        }
+
+        let mut integrity = Integrity {
+            dev,
+            data_blocks,
+            sums: Vec::with_capacity(data_blocks),
+        };
+        for meta_blk in 0..integrity.sum_blocks() {
+            integrity.dev.read(data_blocks + meta_blk, &mut buf)?;
+            integrity.sums.extend(
+                buf.chunks(4)
+                    .map(le_u32)
+                    .take(data_blocks - integrity.sums.len()),
+            );
+        }
+        Ok(integrity)
+    }
+
+    fn sums_per_block(&self) -> usize {
+        self.dev.block_size() / 4
+    }
+
+    fn sum_blocks(&self) -> usize {
+        (self.data_blocks + self.sums_per_block() - 1) / self.sums_per_block()
+    }
+
+    /// Write back the checksum block `meta_blk`.
+    fn write_sums(&mut self, meta_blk: usize) -> Result<(), BlockError> {
+        let per_block = self.sums_per_block();
+        let mut buf = vec![0; self.dev.block_size()];
+        let sums = self.sums.iter().skip(meta_blk * per_block).take(per_block);
+        for (chunk, sum) in buf.chunks_mut(4).zip(sums) {
+            chunk.copy_from_slice(&sum.to_le_bytes());
+        }
+        self.dev.write(self.data_blocks + meta_blk, &buf)
+    }
+
+    fn write_header(&mut self) -> Result<(), BlockError> {
+        let mut buf = vec![0; self.dev.block_size()];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..16].copy_from_slice(&(self.data_blocks as u64).to_le_bytes());
+        let crc = crc32c(&buf[..16]);
+        buf[16..20].copy_from_slice(&crc.to_le_bytes());
+        let header = self.dev.block_count() - 1;
+        self.dev.write(header, &buf)
+    }
+}
+
+/// How many data blocks fit on a device of `block_count` blocks, along
+/// with their checksums and the header.
+fn data_blocks(block_size: usize, block_count: usize) -> Result<usize, BlockError> {
+    let per_block = block_size / 4;
+    let available = block_count.checked_sub(1).ok_or(BlockError::OutOfRange)?;
+    // Every `per_block` data blocks need one more block of checksums.
+    let mut data = available * per_block / (per_block + 1);
+    while data + (data + per_block - 1) / per_block > available {
+        data -= 1;
+    }
+    if data == 0 {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(data)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl<B> BlockDevice for Integrity<B>
+where
+    B: BlockDevice,
+{
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.data_blocks
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        self.dev.read_blocks(blk_id, buf)?;
+        let block_size = self.block_size();
+        for (i, block) in buf.chunks(block_size).enumerate() {
+            if crc32c(block) != self.sums[blk_id + i] {
+                warn!("checksum mismatch in block {}", blk_id + i);
+                return Err(BlockError::Checksum);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        // Data first, so that a crash in between shows up as a checksum
+        // error rather than as stale data passing the check.
+        self.dev.write_blocks(blk_id, buf)?;
+
+        let block_size = self.block_size();
+        for (i, block) in buf.chunks(block_size).enumerate() {
+            self.sums[blk_id + i] = crc32c(block);
+        }
+        let per_block = self.sums_per_block();
+        let blocks = buf.len() / block_size;
+        for meta_blk in blk_id / per_block..=(blk_id + blocks - 1) / per_block {
+            self.write_sums(meta_blk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        self.dev.stats()
+    }
+}
+
+/// Register `<dev>-integrity` for every device in the `integrity=` option,
+/// a comma separated list. Devices listed as `<dev>:format` are formatted
+/// first.
+pub fn setup_from_cmdline() -> Result<(), RegistryError> {
+    let devices = match cmdline::get("integrity") {
+        Some(devices) => devices,
+        None => return Ok(()),
+    };
+    for spec in devices.split(',') {
+        let (name, format) = match spec.strip_suffix(":format") {
+            Some(name) => (name, true),
+            None => (spec, false),
+        };
+        let node = blkdev::devices()
+            .into_iter()
+            .find(|d| d.name == name)
+            .map(|d| d.node)
+            .unwrap_or_default();
+        let dev = blkdev::claim(name)?;
+        let integrity = if format {
+            info!("{}: formatting for integrity checking", name);
+            Integrity::format(dev)
+        } else {
+            Integrity::open(dev)
+        };
+        match integrity {
+            Ok(integrity) => {
+                let integrity_name = format!("{}-integrity", name);
+                info!(
+                    "{}: {} checksummed blocks",
+                    integrity_name, integrity.data_blocks
+                );
+                blkdev::register_as(integrity_name, &node, Box::new(integrity));
+            }
+            Err(e) => warn!("{}: can't check integrity: {:?}", name, e),
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_integrity_detects_corruption() {
+    use crate::ramdisk::RamDisk;
+
+    // 197 data blocks, 2 blocks of checksums and the header.
+    assert_eq!(data_blocks(512, 200), Ok(197));
+
+    let mut integrity = Integrity::format(RamDisk::new(200 * 512)).unwrap();
+    assert_eq!(integrity.block_count(), 197);
+    let mut buf = [0; 2 * 512];
+    integrity.write_blocks(127, &[7; 2 * 512]).unwrap();
+    integrity.read_blocks(127, &mut buf).unwrap();
+    assert_eq!(buf, [7; 2 * 512]);
+
+    // The checksums survive reopening.
+    let mut integrity = Integrity::open(integrity.dev).unwrap();
+    integrity.read_blocks(127, &mut buf).unwrap();
+
+    // Flip a bit behind its back.
+    integrity.dev.read(128, &mut buf[..512]).unwrap();
+    buf[100] ^= 0x10;
+    integrity.dev.write(128, &buf[..512]).unwrap();
+    assert_eq!(integrity.read(127, &mut buf[..512]), Ok(()));
+    assert_eq!(
+        integrity.read(128, &mut buf[..512]),
+        Err(BlockError::Checksum)
+    );
+
+    // Not formatted at all.
+    assert_eq!(
+        Integrity::open(RamDisk::new(200 * 512)).err(),
+        Some(BlockError::Unsupported)
+    );
+}
@@ -0,0 +1,97 @@
+//! Loading user programs from ELF executables.
+
+use elf::{
+    abi::{EM_RISCV, ET_EXEC, PF_W, PF_X, PT_LOAD},
+    endian::NativeEndian,
+    ElfBytes, ParseError,
+};
+
+use crate::{
+    addr::{PageTableFlags, VirtAddr},
+    allocator::PAGE_SIZE,
+    memory::{self, MapToError},
+};
+
+/// The top of the first thread's stack, in the same gigabyte programs are
+/// linked to (see `user/userspace.ld`).
+const STACK_TOP: usize = 0x1_4000_0000;
+const STACK_PAGES: usize = 16;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Parse(ParseError),
+
+    /// Not a RISC-V executable.
+    NotExecutable,
+
+    /// A segment that doesn't fit in the user part of the address space.
+    BadSegment,
+
+    Map(MapToError),
+}
+
+impl From<ParseError> for LoadError {
+    fn from(e: ParseError) -> Self {
+        LoadError::Parse(e)
+    }
+}
+
+impl From<MapToError> for LoadError {
+    fn from(e: MapToError) -> Self {
+        LoadError::Map(e)
+    }
+}
+
+/// Map the `PT_LOAD` segments of `image` and a stack. Returns the entry
+/// point and the stack pointer to start the first thread with.
+pub fn load(image: &[u8]) -> Result<(usize, usize), LoadError> {
+    let file = ElfBytes::<NativeEndian>::minimal_parse(image)?;
+    if file.ehdr.e_machine != EM_RISCV || file.ehdr.e_type != ET_EXEC {
+        return Err(LoadError::NotExecutable);
+    }
+    let segments = file.segments().ok_or(LoadError::NotExecutable)?;
+    for ph in segments.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let mut flags = PageTableFlags::READABLE;
+        if ph.p_flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if ph.p_flags & PF_X != 0 {
+            flags |= PageTableFlags::EXECUTABLE;
+        }
+        let data = file.segment_data(&ph)?;
+        if ph.p_filesz > ph.p_memsz {
+            return Err(LoadError::BadSegment);
+        }
+        let start = ph.p_vaddr as usize;
+        let end = start
+            .checked_add(ph.p_memsz as usize)
+            .ok_or(LoadError::BadSegment)?;
+        map(start, end, flags, data)?;
+    }
+
+    let flags = PageTableFlags::READABLE | PageTableFlags::WRITABLE;
+    map(STACK_TOP - STACK_PAGES * PAGE_SIZE, STACK_TOP, flags, &[])?;
+    // Room for an empty argc, argv and envp, the pages are zeroed.
+    Ok((file.ehdr.e_entry as usize, STACK_TOP - 32))
+}
+
+/// Map `start..end` for user mode with `flags`, and copy `data` to the
+/// start of it. The rest reads as zeros.
+fn map(start: usize, end: usize, flags: PageTableFlags, data: &[u8]) -> Result<(), LoadError> {
+    let mut page = start / PAGE_SIZE * PAGE_SIZE;
+    while page < end {
+        let vaddr = VirtAddr::try_new(page as u64).map_err(|_| LoadError::BadSegment)?;
+        let frame = memory::map_user_page(vaddr, flags)?;
+        // The part of `data` that goes into this page.
+        let from = page.max(start);
+        let to = (page + PAGE_SIZE).min(start + data.len());
+        if from < to {
+            // Physical memory is identity mapped.
+            let dst = (frame.as_u64() as usize + from - page) as *mut u8;
+            let src = &data[from - start..to - start];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
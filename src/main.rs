@@ -14,26 +14,54 @@
 
 extern crate alloc;
 
-use ::log::info;
+use ::log::{info, warn};
+use alloc::{string::String, vec};
 use riscv::asm::wfi;
 use sbi::hart_state_management::hart_status;
 
-use crate::{block::BLK, fat32::Fat32};
+use crate::{
+    blkdev::BlockHandle,
+    block::{BlockDevice, BlockError, BlockStats},
+    fat32::{Fat32, FatError},
+    overlay::Overlay,
+    ramdisk::RamDisk,
+    trap::Frame,
+};
 
 mod addr;
+mod aes;
 mod align;
 mod allocator;
 mod assembly;
+mod bcache;
+mod blkdev;
 mod block;
+mod cmdline;
 mod console;
+mod crc;
+mod crypt;
 mod device;
+// Only the tests mount exFAT volumes so far.
+#[cfg_attr(not(test), allow(dead_code))]
+mod exfat;
+// The kernel only reads FAT volumes so far, the tests use the rest.
+#[cfg_attr(not(test), allow(dead_code))]
 mod fat32;
+mod futex;
+mod integrity;
+mod loader;
 mod log;
 mod memory;
+mod overlay;
 mod panic;
+mod partition;
+mod pci;
 mod plic;
 mod qemu;
+mod ramdisk;
+mod syscall;
 mod testing;
+mod thread;
 mod timer;
 mod trap;
 mod uart;
@@ -42,6 +70,7 @@ mod uart;
 pub fn rust_start(hartid: usize, device_tree_paddr: usize) -> ! {
     #[cfg(test)]
     {
+        allocator::init();
         test_main();
         loop {}
     }
@@ -50,6 +79,89 @@ pub fn rust_start(hartid: usize, device_tree_paddr: usize) -> ! {
     main(hartid, device_tree_paddr);
 }
 
+/// Where the root filesystem lives, unless the command line says otherwise
+/// with `root=`: a device name (`vda`, `ram0`), or `UUID=1234-ABCD` /
+/// `LABEL=NAME` to look it up on every disk.
+const ROOT: &str = "LABEL=TRANSPARENT";
+
+/// Writes to the root filesystem go to an overlay in memory of this size,
+/// so that runs never modify `fs.img`. `rootoverlay=` on the command line
+/// picks another size (`16M`), or `off` to write to the disk itself.
+const ROOT_OVERLAY_SIZE: usize = 4 * 1024 * 1024;
+
+/// The root disk, with or without the overlay in front.
+enum RootDevice {
+    Direct(BlockHandle),
+    Overlay(Overlay<BlockHandle, RamDisk>),
+}
+
+impl RootDevice {
+    fn handle(&self) -> &BlockHandle {
+        match self {
+            RootDevice::Direct(handle) => handle,
+            RootDevice::Overlay(overlay) => overlay.base(),
+        }
+    }
+}
+
+impl BlockDevice for RootDevice {
+    fn block_size(&self) -> usize {
+        match self {
+            RootDevice::Direct(dev) => dev.block_size(),
+            RootDevice::Overlay(dev) => dev.block_size(),
+        }
+    }
+
+    fn block_count(&self) -> usize {
+        match self {
+            RootDevice::Direct(dev) => dev.block_count(),
+            RootDevice::Overlay(dev) => dev.block_count(),
+        }
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Direct(dev) => dev.read_blocks(blk_id, buf),
+            RootDevice::Overlay(dev) => dev.read_blocks(blk_id, buf),
+        }
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Direct(dev) => dev.write_blocks(blk_id, buf),
+            RootDevice::Overlay(dev) => dev.write_blocks(blk_id, buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Direct(dev) => dev.flush(),
+            RootDevice::Overlay(dev) => dev.flush(),
+        }
+    }
+
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Direct(dev) => dev.discard(blk_id, count),
+            RootDevice::Overlay(dev) => dev.discard(blk_id, count),
+        }
+    }
+
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        match self {
+            RootDevice::Direct(dev) => dev.write_zeroes(blk_id, count),
+            RootDevice::Overlay(dev) => dev.write_zeroes(blk_id, count),
+        }
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        match self {
+            RootDevice::Direct(dev) => dev.stats(),
+            RootDevice::Overlay(dev) => dev.stats(),
+        }
+    }
+}
+
 #[no_mangle]
 pub fn main(_hartid: usize, device_tree_paddr: usize) -> ! {
     log::init();
@@ -76,10 +188,40 @@ pub fn main(_hartid: usize, device_tree_paddr: usize) -> ! {
         info!("{:x?} -> {:x?}", addr, paddr);
     }
 
-    let blk = unsafe { BLK.take().unwrap() };
-    let mut fat32 = Fat32::new(blk);
-    fat32.check_fs();
-    fat32.ls_rootdir();
+    // Integrity checking goes under encryption, if both are asked for.
+    if let Err(e) = integrity::setup_from_cmdline() {
+        warn!("failed to set up integrity checking: {:?}", e);
+    }
+    if let Err(e) = crypt::setup_from_cmdline() {
+        warn!("failed to set up the encrypted disk: {:?}", e);
+    }
+
+    for dev in blkdev::devices() {
+        info!(
+            "{}: {} blocks of {} bytes ({})",
+            dev.name, dev.block_count, dev.block_size, dev.node
+        );
+    }
+
+    let root = cmdline::get("root").unwrap_or_else(|| String::from(ROOT));
+    let overlay_size = match cmdline::get("rootoverlay").as_deref() {
+        None => Some(ROOT_OVERLAY_SIZE),
+        Some("off") => None,
+        Some(size) => Some(cmdline::parse_size(size).expect("invalid rootoverlay= size")),
+    };
+    let mut fat32 = mount_root(&root, overlay_size).expect("failed to mount the root filesystem");
+    fat32.check_fs().expect("invalid root filesystem");
+    fat32.ls("/").expect("failed to list the root directory");
+    info!("buffer cache stats: {:?}", fat32.cache_stats());
+    if let RootDevice::Overlay(overlay) = fat32.device() {
+        info!(
+            "{} blocks modified in the root overlay",
+            overlay.dirty_blocks()
+        );
+    }
+    if let Some(stats) = fat32.device().stats() {
+        info!("block device stats:\n{}", stats);
+    }
 
     /*
     let mut buf = vec![0; 512];
@@ -101,20 +243,13 @@ pub fn main(_hartid: usize, device_tree_paddr: usize) -> ! {
     info!("Hello, world!");
     info!("hart #0 status: {:?}", hart_status(0));
 
-    // let elf = include_bytes!("../user/busy.elf");
-    // let file = ElfBytes::<NativeEndian>::minimal_parse(elf).unwrap();
-    // assert_eq!(file.ehdr.e_machine, 0xf3); // is RISC-V
-    // assert_eq!(file.ehdr.e_type, 2); // is executable
-
-    // let first_load_ph = file
-    //     .segments()
-    //     .unwrap()
-    //     .iter()
-    //     .find(|ph| ph.p_type == 1)
-    //     .unwrap(); // PT_LOAD
-
-    // let code = file.segment_data(&first_load_ph).unwrap();
-    // info!("code: {:0>2x?}", code);
+    // The first user program, like `init=/THREADS.ELF`.
+    if let Some(init) = cmdline::get("init") {
+        if let Some(tid) = start_init(&mut fat32, &init) {
+            info!("started {} as thread {}", init, tid);
+            thread::run();
+        }
+    }
 
     /*
     unsafe {
@@ -128,6 +263,84 @@ pub fn main(_hartid: usize, device_tree_paddr: usize) -> ! {
     wfi_loop();
 }
 
+/// Mount the volume `spec` names, with an overlay of `overlay_size` bytes
+/// in front unless that's `None`.
+fn mount_root(spec: &str, overlay_size: Option<usize>) -> Result<Fat32<RootDevice>, FatError> {
+    let matches = |fat32: &Fat32<RootDevice>| {
+        if let Some(uuid) = spec.strip_prefix("UUID=") {
+            fat32.uuid().eq_ignore_ascii_case(uuid)
+        } else if let Some(label) = spec.strip_prefix("LABEL=") {
+            fat32.label() == label
+        } else {
+            true
+        }
+    };
+
+    let by_name = !spec.starts_with("UUID=") && !spec.starts_with("LABEL=");
+    for dev in blkdev::devices() {
+        if by_name && dev.name != spec {
+            continue;
+        }
+        let handle = match blkdev::claim(&dev.name) {
+            Ok(handle) => handle,
+            Err(_) => continue,
+        };
+        let dev = match overlay_size {
+            Some(size) => {
+                // The delta has to use the same block size as the disk it covers.
+                let delta = RamDisk::with_block_size(size, handle.block_size());
+                match Overlay::new(handle, delta) {
+                    Ok(overlay) => RootDevice::Overlay(overlay),
+                    Err(_) => continue,
+                }
+            }
+            None => RootDevice::Direct(handle),
+        };
+        // Devices without a FAT volume simply don't match.
+        match Fat32::new(dev) {
+            Ok(fat32) if matches(&fat32) => {
+                info!(
+                    "mounted {} as the root filesystem",
+                    fat32.device().handle().name()
+                );
+                return Ok(fat32);
+            }
+            Err(e) if by_name => return Err(e),
+            _ => continue,
+        }
+    }
+    Err(FatError::NotFound)
+}
+
+/// Load the program at `path` on the root filesystem, and start its first
+/// thread.
+fn start_init(fat32: &mut Fat32<RootDevice>, path: &str) -> Option<thread::Tid> {
+    let mut read = || {
+        let mut file = fat32.open(path)?;
+        let mut image = vec![0; file.size() as usize];
+        file.read(&mut image)?;
+        Ok::<_, FatError>(image)
+    };
+    let image = match read() {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("failed to read {}: {:?}", path, e);
+            return None;
+        }
+    };
+    match loader::load(&image) {
+        Ok((entry, sp)) => {
+            let tid = thread::alloc_tid();
+            thread::spawn(tid, Frame::user(entry, sp), None);
+            Some(tid)
+        }
+        Err(e) => {
+            warn!("failed to load {}: {:?}", path, e);
+            None
+        }
+    }
+}
+
 fn wfi_loop() -> ! {
     loop {
         unsafe { wfi() };
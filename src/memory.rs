@@ -1,9 +1,14 @@
 // NOTE: We support only SV-39 now.
 // FIXME: Add tests
 
+use alloc::alloc::{alloc_zeroed, Layout};
+
 use riscv::{asm::sfence_vma_all, register::satp};
 
-use crate::addr::{PageTable, PageTableFlags, PhysAddr, VirtAddr};
+use crate::{
+    addr::{PTEntry, PageTable, PageTableFlags, PhysAddr, VirtAddr},
+    allocator::PAGE_SIZE,
+};
 
 static mut ROOT_PAGE_TABLE: PageTable = PageTable::new();
 
@@ -37,6 +42,19 @@ pub fn init() {
     }
     .expect("map failed");
 
+    // ... and the 32-bit PCI memory window, where BARs get placed.
+    unsafe {
+        map_to_with_pt(
+            root_table_addr,
+            VirtAddr::new(0x40000000),
+            PhysAddr::new(0x40000000),
+            PageTableFlags::VRW,
+            0,
+            &mut FrameAllocator,
+        )
+    }
+    .expect("map failed");
+
     // enable virtual memory
     unsafe {
         satp::set(
@@ -73,6 +91,55 @@ pub unsafe fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
 /// the whole body of unsafe functions as an unsafe block. This function must
 /// only be reachable through `unsafe fn` from outside of this module.
 fn translate_addr_inner(addr: VirtAddr) -> Option<PhysAddr> {
+    walk(addr).map(|(paddr, _)| paddr)
+}
+
+/// Translates a user address, or `None` unless it's mapped for user mode
+/// with (at least) `flags`.
+pub fn translate_user(addr: VirtAddr, flags: PageTableFlags) -> Option<PhysAddr> {
+    let (paddr, leaf_flags) = walk(addr)?;
+    leaf_flags
+        .contains(flags | PageTableFlags::USER_ACCESSIBLE)
+        .then_some(paddr)
+}
+
+/// Copies `buf.len()` bytes from user memory at `addr`. Fails if any of it
+/// isn't readable from user mode.
+pub fn read_user(addr: usize, buf: &mut [u8]) -> Option<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let at = addr.checked_add(done)?;
+        let len = (buf.len() - done).min(PAGE_SIZE - at % PAGE_SIZE);
+        let vaddr = VirtAddr::try_new(at as u64).ok()?;
+        let paddr = translate_user(vaddr, PageTableFlags::READABLE)?;
+        // Physical memory is identity mapped.
+        let src = unsafe { core::slice::from_raw_parts(paddr.as_u64() as *const u8, len) };
+        buf[done..done + len].copy_from_slice(src);
+        done += len;
+    }
+    Some(())
+}
+
+/// Stores `value` to the user word at `addr`, which has to be aligned and
+/// writable from user mode.
+pub fn write_user_u32(addr: usize, value: u32) -> Option<()> {
+    if addr % 4 != 0 {
+        return None;
+    }
+    let vaddr = VirtAddr::try_new(addr as u64).ok()?;
+    let paddr = translate_user(vaddr, PageTableFlags::WRITABLE)?;
+    unsafe { core::ptr::write_volatile(paddr.as_u64() as *mut u32, value) };
+    Some(())
+}
+
+/// The physical address `addr` maps to, and the flags of the leaf entry.
+fn walk(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    // Before paging is enabled (as in the tests), every address is a
+    // physical one, and nothing restricts access to it.
+    if satp::read().mode() == satp::Mode::Bare {
+        return Some((PhysAddr::new(addr.as_u64()), PageTableFlags::all()));
+    }
+
     // read the active level 4 frame from the CR3 register
     let level_3_table_frame = (satp::read().ppn() << 12) as u64;
 
@@ -98,12 +165,13 @@ fn translate_addr_inner(addr: VirtAddr) -> Option<PhysAddr> {
                 1 => ((addr.vpn0() as u64) << 12),
                 _ => 0,
             };
-            break;
+            // calculate the physical address by adding the page offset
+            return Some((PhysAddr::new(frame + addr.page_offset()), entry.flags()));
         }
     }
 
-    // calculate the physical address by adding the page offset
-    Some(PhysAddr::new(frame + addr.page_offset()))
+    // A level 0 entry that isn't a leaf.
+    None
 }
 
 /// An allocator that allocates 4KiB physical frames.
@@ -114,8 +182,11 @@ impl FrameAllocator {
         Self {}
     }
 
+    /// A zeroed frame from the kernel heap, which is identity mapped.
     pub fn alloc_frame(&mut self) -> Option<PhysAddr> {
-        None
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let frame = unsafe { alloc_zeroed(layout) };
+        (!frame.is_null()).then(|| PhysAddr::new(frame as u64))
     }
 
     pub fn dealloc_frame(&mut self, _frame: PhysAddr) {}
@@ -215,3 +286,45 @@ pub unsafe fn map_to_with_pt(
     // FIXME: Return a `MapperFlush` helper that can be used to flush the TLB.
     Ok(())
 }
+
+/// Maps the 4 KiB page at `page` for user mode, and returns the frame
+/// behind it. A page that is mapped already keeps its frame and gets
+/// `flags` added, for segments that share a page.
+pub fn map_user_page(page: VirtAddr, flags: PageTableFlags) -> Result<PhysAddr, MapToError> {
+    let flags = flags | PageTableFlags::VALID | PageTableFlags::USER_ACCESSIBLE;
+    let entry = unsafe { page_entry(page, &mut FrameAllocator) }?;
+    if entry.is_valid() {
+        entry.set(entry.addr(), entry.flags() | flags);
+    } else {
+        let frame = FrameAllocator
+            .alloc_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        entry.set(frame, flags);
+    }
+    unsafe { sfence_vma_all() };
+    Ok(entry.addr())
+}
+
+/// The level 2 (4 KiB page) entry for `page` in the active page table,
+/// allocating the tables on the way there if needed.
+unsafe fn page_entry(
+    page: VirtAddr,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<&'static mut PTEntry, MapToError> {
+    let mut pt = (satp::read().ppn() << 12) as u64;
+    for index in [page.vpn2(), page.vpn1()] {
+        let table = &mut *(pt as *mut PageTable);
+        let entry = &mut table[index];
+        if !entry.is_valid() {
+            let frame = frame_allocator
+                .alloc_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            entry.set(frame, PageTableFlags::VALID);
+        } else if entry.flags().is_leaf() {
+            return Err(MapToError::ParentEntryHugePage);
+        }
+        pt = entry.addr().as_u64();
+    }
+    let table = &mut *(pt as *mut PageTable);
+    Ok(&mut table[page.vpn0()])
+}
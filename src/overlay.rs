@@ -0,0 +1,211 @@
+//! A copy-on-write overlay over a block device.
+//!
+//! Reads come from the base device unless the block was written through
+//! the overlay, in which case they come from the delta. Writes only ever
+//! go to the delta, so the base stays untouched until `commit`.
+//!
+//! The delta is itself a block device: a `RamDisk`, or a second disk to
+//! keep changes around across reboots. Either way, which block of the base
+//! lives where in the delta is only kept in memory.
+
+use alloc::{collections::BTreeMap, vec};
+
+use crate::block::{check_range, BlockDevice, BlockError, BlockStats};
+
+pub struct Overlay<B, D>
+where
+    B: BlockDevice,
+    D: BlockDevice,
+{
+    base: B,
+    delta: D,
+
+    /// Where each modified block of the base lives in the delta.
+    remap: BTreeMap<usize, usize>,
+
+    /// The next unused block of the delta.
+    next_slot: usize,
+}
+
+impl<B, D> Overlay<B, D>
+where
+    B: BlockDevice,
+    D: BlockDevice,
+{
+    /// Fails with `Unsupported` if the two don't have the same block size.
+    pub fn new(base: B, delta: D) -> Result<Self, BlockError> {
+        if base.block_size() != delta.block_size() {
+            return Err(BlockError::Unsupported);
+        }
+        Ok(Overlay {
+            base,
+            delta,
+            remap: BTreeMap::new(),
+            next_slot: 0,
+        })
+    }
+
+    /// Number of blocks that differ from the base.
+    pub fn dirty_blocks(&self) -> usize {
+        self.remap.len()
+    }
+
+    /// Write every modified block to the base device, and start over with
+    /// an empty delta.
+    pub fn commit(&mut self) -> Result<(), BlockError> {
+        let mut buf = vec![0; self.base.block_size()];
+        while let Some((&blk_id, &slot)) = self.remap.iter().next() {
+            self.delta.read(slot, &mut buf)?;
+            self.base.write(blk_id, &buf)?;
+            self.remap.remove(&blk_id);
+        }
+        self.next_slot = 0;
+        self.base.flush()
+    }
+
+    /// Throw away everything written through the overlay.
+    pub fn discard_changes(&mut self) {
+        self.remap.clear();
+        self.next_slot = 0;
+    }
+
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Get the base device back, dropping any uncommitted changes.
+    pub fn into_base(self) -> B {
+        self.base
+    }
+
+    /// The slot in the delta for `blk_id`, allocating one if needed.
+    fn slot(&mut self, blk_id: usize) -> Result<usize, BlockError> {
+        if let Some(&slot) = self.remap.get(&blk_id) {
+            return Ok(slot);
+        }
+        // Slots are only freed all at once, by `commit` or
+        // `discard_changes`, so there's no need to look for holes.
+        let slot = self.next_slot;
+        if slot >= self.delta.block_count() {
+            return Err(BlockError::NoSpace);
+        }
+        self.remap.insert(blk_id, slot);
+        self.next_slot += 1;
+        Ok(slot)
+    }
+}
+
+impl<B, D> BlockDevice for Overlay<B, D>
+where
+    B: BlockDevice,
+    D: BlockDevice,
+{
+    fn block_size(&self) -> usize {
+        self.base.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.base.block_count()
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        let block_size = self.block_size();
+        let blocks = buf.len() / block_size;
+
+        // Read untouched runs from the base in one go.
+        let mut i = 0;
+        while i < blocks {
+            let run = (i..blocks)
+                .take_while(|j| !self.remap.contains_key(&(blk_id + j)))
+                .count();
+            if run > 0 {
+                self.base
+                    .read_blocks(blk_id + i, &mut buf[i * block_size..(i + run) * block_size])?;
+                i += run;
+                continue;
+            }
+            let slot = self.remap[&(blk_id + i)];
+            self.delta
+                .read(slot, &mut buf[i * block_size..(i + 1) * block_size])?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        for (i, block) in buf.chunks(self.block_size()).enumerate() {
+            let slot = self.slot(blk_id + i)?;
+            self.delta.write(slot, block)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.delta.flush()
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        self.base.stats()
+    }
+}
+
+#[test_case]
+fn test_overlay_keeps_base_intact() {
+    use crate::{block::MemDevice, ramdisk::RamDisk};
+
+    let mut base = MemDevice::new(8);
+    base.blocks[3].fill(3);
+    let mut overlay = Overlay::new(base, RamDisk::new(4 * 512)).unwrap();
+
+    let mut buf = [0; 3 * 512];
+    overlay.write(4, &[4; 512]).unwrap();
+    overlay.read_blocks(2, &mut buf).unwrap();
+    assert_eq!(buf[..512], [0; 512]);
+    assert_eq!(buf[512..1024], [3; 512]);
+    assert_eq!(buf[1024..], [4; 512]);
+    assert_eq!(overlay.dirty_blocks(), 1);
+
+    // Nothing reached the base.
+    overlay.discard_changes();
+    overlay.read(4, &mut buf[..512]).unwrap();
+    assert_eq!(buf[..512], [0; 512]);
+
+    overlay.write(3, &[5; 512]).unwrap();
+    overlay.write(3, &[6; 512]).unwrap();
+    assert_eq!(overlay.dirty_blocks(), 1);
+    overlay.commit().unwrap();
+    assert_eq!(overlay.dirty_blocks(), 0);
+    let base = overlay.into_base();
+    assert_eq!(base.blocks[3], [6; 512]);
+    assert_eq!(base.writes, 1);
+}
+
+#[test_case]
+fn test_overlay_full() {
+    use crate::{block::MemDevice, ramdisk::RamDisk};
+
+    let mut overlay = Overlay::new(MemDevice::new(8), RamDisk::new(2 * 512)).unwrap();
+    overlay.write_blocks(0, &[1; 2 * 512]).unwrap();
+    // Blocks already in the overlay can still be written.
+    overlay.write(1, &[2; 512]).unwrap();
+    assert_eq!(overlay.write(2, &[3; 512]), Err(BlockError::NoSpace));
+    assert_eq!(overlay.dirty_blocks(), 2);
+
+    // Until the changes are committed, which frees up the overlay again.
+    overlay.commit().unwrap();
+    overlay.write(2, &[3; 512]).unwrap();
+    assert_eq!(overlay.base().blocks[1], [2; 512]);
+}
+
+#[test_case]
+fn test_overlay_block_size_mismatch() {
+    use crate::{block::MemDevice, ramdisk::RamDisk};
+
+    let base = MemDevice::new(8);
+    assert!(matches!(
+        Overlay::new(base, RamDisk::with_block_size(4 * 4096, 4096)),
+        Err(BlockError::Unsupported)
+    ));
+}
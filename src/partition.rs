@@ -0,0 +1,557 @@
+//! MBR and GPT partition tables.
+//!
+//! Every partition found on a disk becomes a `BlockDevice` of its own,
+//! sharing the disk with its siblings. `register_partitions` puts them in
+//! the block device registry as `vda1`, `vda2`, ...
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::{
+    blkdev::{self, BlockHandle},
+    block::{check_range, BlockDevice, BlockError, BlockStats},
+    crc::crc32,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+
+    /// The disk has no partition table we recognise.
+    NoTable,
+
+    /// The partition table is there, but broken.
+    Corrupt,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(e: BlockError) -> Self {
+        PartitionError::Block(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// An MBR partition, with its partition type.
+    Mbr(u8),
+
+    Gpt {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Numbered from 1, like Linux does. Logical MBR partitions start at 5.
+    pub number: usize,
+
+    /// First block of the partition.
+    pub start: usize,
+
+    /// Number of blocks in the partition.
+    pub count: usize,
+
+    pub kind: PartitionKind,
+}
+
+/// Read the partition table of `dev`.
+///
+/// A GPT is preferred over the protective MBR in front of it.
+pub fn scan<B>(dev: &mut B) -> Result<Vec<PartitionInfo>, PartitionError>
+where
+    B: BlockDevice + ?Sized,
+{
+    let mut mbr = vec![0; dev.block_size()];
+    dev.read(0, &mut mbr)?;
+    let entries = mbr_entries(&mbr, dev.block_count()).ok_or(PartitionError::NoTable)?;
+
+    if entries.iter().any(|e| e.kind == MBR_GPT_PROTECTIVE) {
+        return scan_gpt(dev);
+    }
+
+    let mut partitions = Vec::new();
+    let mut logical = 5;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.kind == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&entry.kind) {
+            scan_extended(dev, entry.start, &mut logical, &mut partitions)?;
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start: entry.start,
+            count: entry.count,
+            kind: PartitionKind::Mbr(entry.kind),
+        });
+    }
+    Ok(partitions)
+}
+
+const MBR_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Offset of the partition entries in an MBR or EBR.
+const MBR_ENTRIES: usize = 446;
+
+struct MbrEntry {
+    kind: u8,
+    start: usize,
+    count: usize,
+}
+
+/// Parse the four entries of an MBR or EBR, relative to the sector it's in.
+///
+/// Returns `None` if it doesn't look like one. A FAT boot sector has the
+/// same 0x55aa signature, but boot code where the entries would be.
+fn mbr_entries(sector: &[u8], block_count: usize) -> Option<[MbrEntry; 4]> {
+    if sector[510] != 0x55 || sector[511] != 0xaa {
+        return None;
+    }
+    let entry = |i: usize| {
+        let e = &sector[MBR_ENTRIES + i * 16..][..16];
+        let status = e[0];
+        let entry = MbrEntry {
+            kind: e[4],
+            start: le_u32(&e[8..]) as usize,
+            count: le_u32(&e[12..]) as usize,
+        };
+        let valid = (status == 0 || status == 0x80)
+            && (entry.kind == 0 || (entry.count > 0 && entry.start + entry.count <= block_count));
+        valid.then_some(entry)
+    };
+    Some([entry(0)?, entry(1)?, entry(2)?, entry(3)?])
+}
+
+/// Follow the chain of EBRs of the extended partition at `ext_start`.
+fn scan_extended<B>(
+    dev: &mut B,
+    ext_start: usize,
+    number: &mut usize,
+    partitions: &mut Vec<PartitionInfo>,
+) -> Result<(), PartitionError>
+where
+    B: BlockDevice + ?Sized,
+{
+    let mut ebr = vec![0; dev.block_size()];
+    let mut next = ext_start;
+    // A loop in the chain would otherwise keep us here forever.
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        dev.read(next, &mut ebr)?;
+        let entries = mbr_entries(&ebr, dev.block_count()).ok_or(PartitionError::Corrupt)?;
+        let (part, link) = (&entries[0], &entries[1]);
+        // `mbr_entries` only checked them relative to the EBR.
+        let fits = |start: usize, count: usize| matches!(start.checked_add(count), Some(end) if end <= dev.block_count());
+        if part.kind != 0 {
+            let start = next + part.start;
+            if !fits(start, part.count) {
+                return Err(PartitionError::Corrupt);
+            }
+            partitions.push(PartitionInfo {
+                number: *number,
+                start,
+                count: part.count,
+                kind: PartitionKind::Mbr(part.kind),
+            });
+            *number += 1;
+        }
+        if link.kind == 0 {
+            return Ok(());
+        }
+        // The next EBR is relative to the start of the extended partition.
+        next = ext_start + link.start;
+        if !fits(next, 1) {
+            return Err(PartitionError::Corrupt);
+        }
+    }
+    Err(PartitionError::Corrupt)
+}
+
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// Limits on what a GPT header can ask us to allocate and read. The usual
+/// table is 128 entries of 128 bytes.
+const MAX_GPT_ENTRIES: usize = 1024;
+const MAX_GPT_ENTRY_SIZE: usize = 4096;
+const MAX_GPT_TABLE_SIZE: usize = 1 << 20;
+
+struct GptHeader {
+    entries_lba: usize,
+    num_entries: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+fn scan_gpt<B>(dev: &mut B) -> Result<Vec<PartitionInfo>, PartitionError>
+where
+    B: BlockDevice + ?Sized,
+{
+    match read_gpt(dev, 1) {
+        Ok(partitions) => Ok(partitions),
+        Err(PartitionError::Corrupt) => {
+            warn!("primary GPT is corrupt, trying the backup");
+            read_gpt(dev, dev.block_count() - 1)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the GPT whose header is at `lba`, validating both checksums.
+fn read_gpt<B>(dev: &mut B, lba: usize) -> Result<Vec<PartitionInfo>, PartitionError>
+where
+    B: BlockDevice + ?Sized,
+{
+    let block_size = dev.block_size();
+    let mut block = vec![0; block_size];
+    dev.read(lba, &mut block)?;
+    let header = parse_gpt_header(&block, lba).ok_or(PartitionError::Corrupt)?;
+
+    let len = header.num_entries * header.entry_size;
+    let mut entries = vec![0; (len + block_size - 1) / block_size * block_size];
+    dev.read_blocks(header.entries_lba, &mut entries)
+        .map_err(|e| match e {
+            BlockError::OutOfRange => PartitionError::Corrupt,
+            e => PartitionError::Block(e),
+        })?;
+    if crc32(&entries[..len]) != header.entries_crc {
+        return Err(PartitionError::Corrupt);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, e) in entries[..len].chunks(header.entry_size).enumerate() {
+        let type_guid: [u8; 16] = e[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first = le_u64(&e[32..]) as usize;
+        let last = le_u64(&e[40..]) as usize;
+        if last < first || last >= dev.block_count() {
+            return Err(PartitionError::Corrupt);
+        }
+        let name: Vec<u16> = e[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start: first,
+            count: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: e[16..32].try_into().unwrap(),
+                name: String::from_utf16_lossy(&name),
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+fn parse_gpt_header(block: &[u8], lba: usize) -> Option<GptHeader> {
+    if &block[0..8] != GPT_SIGNATURE {
+        return None;
+    }
+    let header_size = le_u32(&block[12..]) as usize;
+    if !(92..=block.len()).contains(&header_size) {
+        return None;
+    }
+    // The checksum is calculated with its own field zeroed.
+    let mut header = block[..header_size].to_vec();
+    header[16..20].fill(0);
+    if crc32(&header) != le_u32(&block[16..]) || le_u64(&block[24..]) as usize != lba {
+        return None;
+    }
+
+    let header = GptHeader {
+        entries_lba: le_u64(&block[72..]) as usize,
+        num_entries: le_u32(&block[80..]) as usize,
+        entry_size: le_u32(&block[84..]) as usize,
+        entries_crc: le_u32(&block[88..]),
+    };
+    // Entries are at least 128 bytes, and a multiple of 8.
+    if !(128..=MAX_GPT_ENTRY_SIZE).contains(&header.entry_size)
+        || header.entry_size % 8 != 0
+        || header.num_entries > MAX_GPT_ENTRIES
+        || header.num_entries * header.entry_size > MAX_GPT_TABLE_SIZE
+    {
+        return None;
+    }
+    Some(header)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// A partition of a disk, shared with the other partitions on it.
+pub struct Partition<B>
+where
+    B: BlockDevice,
+{
+    disk: Arc<Mutex<B>>,
+    info: PartitionInfo,
+}
+
+impl<B> Partition<B>
+where
+    B: BlockDevice,
+{
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+}
+
+/// Split `disk` into its partitions.
+pub fn open<B>(mut disk: B) -> Result<Vec<Partition<B>>, PartitionError>
+where
+    B: BlockDevice,
+{
+    let partitions = scan(&mut disk)?;
+    let disk = Arc::new(Mutex::new(disk));
+    Ok(partitions
+        .into_iter()
+        .map(|info| Partition {
+            disk: disk.clone(),
+            info,
+        })
+        .collect())
+}
+
+impl<B> BlockDevice for Partition<B>
+where
+    B: BlockDevice,
+{
+    fn block_size(&self) -> usize {
+        self.disk.lock().block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.info.count
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        self.disk.lock().read_blocks(self.info.start + blk_id, buf)
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        self.disk.lock().write_blocks(self.info.start + blk_id, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.disk.lock().flush()
+    }
+
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        match blk_id.checked_add(count) {
+            Some(end) if end <= self.info.count => {}
+            _ => return Err(BlockError::OutOfRange),
+        }
+        self.disk.lock().discard(self.info.start + blk_id, count)
+    }
+
+    fn write_zeroes(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        match blk_id.checked_add(count) {
+            Some(end) if end <= self.info.count => {}
+            _ => return Err(BlockError::OutOfRange),
+        }
+        self.disk
+            .lock()
+            .write_zeroes(self.info.start + blk_id, count)
+    }
+
+    fn stats(&self) -> Option<BlockStats> {
+        self.disk.lock().stats()
+    }
+}
+
+/// Register every partition on the registered disk `name` as a device of
+/// its own, named after the disk (`vda1`, `vda2`, ...).
+///
+/// The disk itself stays claimed by its partitions from then on. Disks
+/// without a partition table are left alone.
+pub fn register_partitions(name: &str) -> Result<usize, PartitionError> {
+    let node = blkdev::devices()
+        .into_iter()
+        .find(|d| d.name == name)
+        .map(|d| d.node)
+        .ok_or(PartitionError::NoTable)?;
+    let disk = blkdev::claim(name).map_err(|_| PartitionError::NoTable)?;
+    let partitions = open::<BlockHandle>(disk)?;
+    let count = partitions.len();
+    for part in partitions {
+        let info = part.info();
+        let part_name = format!("{}{}", name, info.number);
+        info!(
+            "{}: {} blocks at {}, {:?}",
+            part_name, info.count, info.start, info.kind
+        );
+        blkdev::register_as(part_name, &node, Box::new(part));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+use crate::block::MemDevice;
+
+#[cfg(test)]
+fn mbr_entry(sector: &mut [u8], i: usize, kind: u8, start: u32, count: u32) {
+    let e = &mut sector[MBR_ENTRIES + i * 16..][..16];
+    e[4] = kind;
+    e[8..12].copy_from_slice(&start.to_le_bytes());
+    e[12..16].copy_from_slice(&count.to_le_bytes());
+}
+
+#[test_case]
+fn test_mbr_with_extended_partitions() {
+    let mut disk = MemDevice::new(64);
+    for blk_id in [0, 20, 30] {
+        disk.blocks[blk_id][510] = 0x55;
+        disk.blocks[blk_id][511] = 0xaa;
+    }
+    mbr_entry(&mut disk.blocks[0], 0, 0x0c, 2, 18);
+    mbr_entry(&mut disk.blocks[0], 1, 0x05, 20, 40);
+    // Logical partitions, relative to their EBR, chained relative to the
+    // extended partition.
+    mbr_entry(&mut disk.blocks[20], 0, 0x83, 1, 9);
+    mbr_entry(&mut disk.blocks[20], 1, 0x05, 10, 20);
+    mbr_entry(&mut disk.blocks[30], 0, 0x0b, 2, 8);
+
+    let partitions = scan(&mut disk).unwrap();
+    let layout: Vec<_> = partitions
+        .iter()
+        .map(|p| (p.number, p.start, p.count, p.kind.clone()))
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, 2, 18, PartitionKind::Mbr(0x0c)),
+            (5, 21, 9, PartitionKind::Mbr(0x83)),
+            (6, 32, 8, PartitionKind::Mbr(0x0b)),
+        ]
+    );
+
+    // Partitions can't reach outside of themselves.
+    let mut parts = open(disk).unwrap();
+    let mut buf = [0; 512];
+    parts[1].write(8, &[0xaa; 512]).unwrap();
+    assert_eq!(parts[1].write(9, &buf), Err(BlockError::OutOfRange));
+    parts[1].read(8, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa; 512]);
+    assert_eq!(parts[1].disk.lock().blocks[29], [0xaa; 512]);
+}
+
+#[test_case]
+fn test_mbr_logical_partition_past_the_end() {
+    let mut disk = MemDevice::new(64);
+    for blk_id in [0, 20, 50] {
+        disk.blocks[blk_id][510] = 0x55;
+        disk.blocks[blk_id][511] = 0xaa;
+    }
+    mbr_entry(&mut disk.blocks[0], 0, 0x05, 20, 44);
+    mbr_entry(&mut disk.blocks[20], 0, 0x83, 1, 9);
+    mbr_entry(&mut disk.blocks[20], 1, 0x05, 30, 14);
+    // Fits after the EBR, but not on the disk.
+    mbr_entry(&mut disk.blocks[50], 0, 0x83, 2, 20);
+    assert_eq!(scan(&mut disk), Err(PartitionError::Corrupt));
+}
+
+#[test_case]
+fn test_gpt_falls_back_to_backup_header() {
+    const BLOCKS: usize = 64;
+    let mut disk = MemDevice::new(BLOCKS);
+    mbr_entry(
+        &mut disk.blocks[0],
+        0,
+        MBR_GPT_PROTECTIVE,
+        1,
+        BLOCKS as u32 - 1,
+    );
+    disk.blocks[0][510] = 0x55;
+    disk.blocks[0][511] = 0xaa;
+
+    // One partition, named "boot", in both copies of the entries.
+    let mut entry = [0; 128];
+    entry[0..16].copy_from_slice(&[0x28; 16]);
+    entry[16..32].copy_from_slice(&[0x11; 16]);
+    entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&19u64.to_le_bytes());
+    for (i, c) in "boot".encode_utf16().enumerate() {
+        entry[56 + i * 2..][..2].copy_from_slice(&c.to_le_bytes());
+    }
+    let mut entries = [0; 512];
+    entries[..128].copy_from_slice(&entry);
+    disk.blocks[2] = entries;
+    disk.blocks[BLOCKS - 2] = entries;
+
+    let header = |lba: u64, entries_lba: u64| {
+        let mut h = [0; 512];
+        h[0..8].copy_from_slice(GPT_SIGNATURE);
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&lba.to_le_bytes());
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&4u32.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        h
+    };
+    disk.blocks[1] = header(1, 2);
+    disk.blocks[BLOCKS - 1] = header(BLOCKS as u64 - 1, BLOCKS as u64 - 2);
+
+    let expected = [PartitionInfo {
+        number: 1,
+        start: 10,
+        count: 10,
+        kind: PartitionKind::Gpt {
+            type_guid: [0x28; 16],
+            unique_guid: [0x11; 16],
+            name: "boot".into(),
+        },
+    }];
+    assert_eq!(scan(&mut disk).unwrap(), expected);
+
+    // Corrupt the primary header.
+    disk.blocks[1][40] ^= 1;
+    assert_eq!(scan(&mut disk).unwrap(), expected);
+
+    // And the backup's entries.
+    disk.blocks[BLOCKS - 2][60] ^= 1;
+    assert_eq!(scan(&mut disk), Err(PartitionError::Corrupt));
+}
+
+#[test_case]
+fn test_gpt_header_limits() {
+    let header = |num_entries: u32, entry_size: u32| {
+        let mut h = [0; 512];
+        h[0..8].copy_from_slice(GPT_SIGNATURE);
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&1u64.to_le_bytes());
+        h[72..80].copy_from_slice(&2u64.to_le_bytes());
+        h[80..84].copy_from_slice(&num_entries.to_le_bytes());
+        h[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        let crc = crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        parse_gpt_header(&h, 1).map(|h| (h.num_entries, h.entry_size))
+    };
+    assert_eq!(header(128, 128), Some((128, 128)));
+    assert_eq!(header(256, 4096), Some((256, 4096)));
+    assert_eq!(header(1, 4104), None);
+    assert_eq!(header(1024, 0xffff_fff8), None);
+    assert_eq!(header(1024, 2048), None);
+    assert_eq!(header(1025, 128), None);
+    assert_eq!(header(4, 100), None);
+}
@@ -0,0 +1,644 @@
+//! PCI Express, behind a `pci-host-ecam-generic` host bridge.
+//!
+//! Nobody before us sets up the bus on QEMU's virt machine, so we do what
+//! firmware would do on a PC: number the buses, hand out BARs from the
+//! windows in the bridge's `ranges`, and work out which PLIC source every
+//! device interrupts on from its `interrupt-map`.
+//!
+//! Configuration space is memory mapped (ECAM): 4 KiB per function, found at
+//! `base + (bus << 20 | device << 15 | function << 12)`.
+
+use alloc::{format, string::String, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use device_tree::Node;
+use log::{info, warn};
+
+const VENDOR_ID: usize = 0x00;
+const DEVICE_ID: usize = 0x02;
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const CLASS: usize = 0x08;
+const HEADER_TYPE: usize = 0x0e;
+const BAR0: usize = 0x10;
+const SUBSYSTEM_ID: usize = 0x2e;
+const CAPABILITIES: usize = 0x34;
+const INTERRUPT_PIN: usize = 0x3d;
+
+// Type 1 (bridge) header.
+const PRIMARY_BUS: usize = 0x18;
+const SECONDARY_BUS: usize = 0x19;
+const SUBORDINATE_BUS: usize = 0x1a;
+const IO_BASE: usize = 0x1c;
+const IO_LIMIT: usize = 0x1d;
+const MEMORY_BASE: usize = 0x20;
+const MEMORY_LIMIT: usize = 0x22;
+const PREFETCH_BASE: usize = 0x24;
+const PREFETCH_LIMIT: usize = 0x26;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Bridges forward memory in 1 MiB granules, and I/O in 4 KiB ones.
+const BRIDGE_MEMORY_ALIGN: u64 = 1 << 20;
+const BRIDGE_IO_ALIGN: u64 = 1 << 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// An assigned BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bar {
+    pub kind: BarKind,
+
+    /// Where the CPU finds it.
+    pub addr: usize,
+    pub size: usize,
+}
+
+/// A capability in the configuration space of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+
+    /// Of the capability header in configuration space.
+    pub offset: usize,
+}
+
+/// A function found on the bus.
+#[derive(Debug)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_id: u16,
+
+    /// Class, subclass and programming interface.
+    pub class: u32,
+
+    pub bars: [Option<Bar>; 6],
+
+    /// The PLIC source of its INTx pin, if it has one.
+    pub irq: Option<u32>,
+
+    /// Where its configuration space is mapped.
+    config: usize,
+}
+
+impl PciDevice {
+    /// `bb:dd.f`, like lspci.
+    pub fn address(&self) -> String {
+        format!("{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        config_read8(self.config, offset)
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        config_read16(self.config, offset)
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        config_read32(self.config, offset)
+    }
+
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let next = if self.read16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read8(CAPABILITIES) as usize & !0x3
+        } else {
+            0
+        };
+        Capabilities { dev: self, next }
+    }
+}
+
+/// Walks the capability list of a device.
+pub struct Capabilities<'a> {
+    dev: &'a PciDevice,
+    next: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // The list lives past the standard header, and 0 ends it.
+        if self.next < 0x40 {
+            return None;
+        }
+        let offset = self.next;
+        self.next = self.dev.read8(offset + 1) as usize & !0x3;
+        Some(Capability {
+            id: self.dev.read8(offset),
+            offset,
+        })
+    }
+}
+
+fn config_read8(config: usize, offset: usize) -> u8 {
+    unsafe { read_volatile((config + offset) as *const u8) }
+}
+
+fn config_read16(config: usize, offset: usize) -> u16 {
+    unsafe { read_volatile((config + offset) as *const u16) }
+}
+
+fn config_read32(config: usize, offset: usize) -> u32 {
+    unsafe { read_volatile((config + offset) as *const u32) }
+}
+
+fn config_write8(config: usize, offset: usize, value: u8) {
+    unsafe { write_volatile((config + offset) as *mut u8, value) }
+}
+
+fn config_write16(config: usize, offset: usize, value: u16) {
+    unsafe { write_volatile((config + offset) as *mut u16, value) }
+}
+
+fn config_write32(config: usize, offset: usize, value: u32) {
+    unsafe { write_volatile((config + offset) as *mut u32, value) }
+}
+
+/// A window of bus addresses the host bridge forwards, from `ranges`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    /// Address on the PCI side.
+    pci: u64,
+
+    /// Where the CPU sees `pci`.
+    cpu: u64,
+    size: u64,
+
+    /// Next free PCI address.
+    next: u64,
+}
+
+impl Window {
+    /// Take `size` bytes aligned to `align`, returning the PCI address.
+    fn alloc(&mut self, size: u64, align: u64) -> Option<u64> {
+        let start = align_up(self.next, align);
+        if start + size > self.pci + self.size {
+            return None;
+        }
+        self.next = start + size;
+        Some(start)
+    }
+
+    fn to_cpu(self, pci: u64) -> u64 {
+        self.cpu + (pci - self.pci)
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn cells(raw: &[u8]) -> Vec<u32> {
+    raw.chunks_exact(4)
+        .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+/// The I/O and 32-bit memory windows in `ranges`.
+///
+/// NOTE: 64-bit BARs go in the 32-bit window too, the 64-bit one lies past
+/// what we map. Assumes the parent has 2 address cells, like on QEMU.
+fn parse_ranges(ranges: &[u8]) -> (Option<Window>, Option<Window>) {
+    let (mut io, mut mem) = (None, None);
+    for entry in cells(ranges).chunks_exact(7) {
+        let space = (entry[0] >> 24) & 0x3;
+        let pci = (entry[1] as u64) << 32 | entry[2] as u64;
+        let cpu = (entry[3] as u64) << 32 | entry[4] as u64;
+        let size = (entry[5] as u64) << 32 | entry[6] as u64;
+        let window = Some(Window {
+            pci,
+            cpu,
+            size,
+            next: pci,
+        });
+        match space {
+            0b01 => io = window,
+            0b10 => mem = window,
+            _ => {}
+        }
+    }
+    (io, mem)
+}
+
+/// Where the INTx pins of the slots on the root bus go, from
+/// `interrupt-map` and `interrupt-map-mask`.
+#[derive(Debug)]
+struct InterruptMap {
+    mask: [u32; 4],
+
+    /// Unit address (phys.hi only, the rest is always 0) and pin, to the
+    /// parent interrupt.
+    entries: Vec<(u32, u32, u32)>,
+}
+
+impl InterruptMap {
+    /// NOTE: Assumes the interrupt parent is a PLIC, with no address cells
+    /// and a single interrupt cell.
+    fn parse(map: &[u8], mask: &[u8]) -> Self {
+        let mask = cells(mask);
+        let mask = if mask.len() == 4 {
+            [mask[0], mask[1], mask[2], mask[3]]
+        } else {
+            [!0; 4]
+        };
+        let entries = cells(map)
+            .chunks_exact(6)
+            .map(|e| (e[0] & mask[0], e[3] & mask[3], e[5]))
+            .collect();
+        InterruptMap { mask, entries }
+    }
+
+    /// The parent interrupt of `pin` (1 for INTA) of the root bus slot
+    /// `device`.
+    fn route(&self, device: u8, function: u8, pin: u8) -> Option<u32> {
+        let phys_hi = (device as u32) << 11 | (function as u32) << 8;
+        let (phys_hi, pin) = (phys_hi & self.mask[0], pin as u32 & self.mask[3]);
+        self.entries
+            .iter()
+            .find(|(hi, p, _)| *hi == phys_hi && *p == pin)
+            .map(|(_, _, irq)| *irq)
+    }
+}
+
+/// The pin seen on the other side of a bridge, for a device in slot
+/// `device` on its secondary bus. The PCI-to-PCI bridge spec's swizzle.
+///
+/// Swizzles add up, so crossing several bridges is a single swizzle by the
+/// sum of the slots.
+fn swizzle(pin: u8, device: u8) -> u8 {
+    (pin - 1 + device % 4) % 4 + 1
+}
+
+struct Enumerator {
+    ecam: usize,
+    bus_end: u8,
+    next_bus: u8,
+    io: Option<Window>,
+    mem: Option<Window>,
+    irq_map: InterruptMap,
+    devices: Vec<PciDevice>,
+}
+
+impl Enumerator {
+    fn config(&self, bus: u8, device: u8, function: u8) -> usize {
+        self.ecam + ((bus as usize) << 20 | (device as usize) << 15 | (function as usize) << 12)
+    }
+
+    /// Scan `bus`. `upstream` is the root bus slot the bus hangs off, and
+    /// the sum of the slots of the bridges in between, `None` for the root
+    /// bus.
+    fn scan_bus(&mut self, bus: u8, upstream: Option<(u8, u8)>) {
+        for device in 0..32 {
+            for function in 0..8 {
+                let config = self.config(bus, device, function);
+                if config_read16(config, VENDOR_ID) == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                let header_type = config_read8(config, HEADER_TYPE);
+                match header_type & 0x7f {
+                    0 => self.add_device(bus, device, function, upstream),
+                    1 => self.add_bridge(bus, device, function, upstream),
+                    t => warn!(
+                        "pci {:02x}:{:02x}.{}: unknown header type {}",
+                        bus, device, function, t
+                    ),
+                }
+                if function == 0 && header_type & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_device(&mut self, bus: u8, device: u8, function: u8, upstream: Option<(u8, u8)>) {
+        let config = self.config(bus, device, function);
+        // Keep it quiet while the BARs move.
+        config_write16(config, COMMAND, 0);
+
+        let mut bars = [None; 6];
+        let mut i = 0;
+        while i < 6 {
+            let (bar, slots) = self.assign_bar(config, i);
+            bars[i] = bar;
+            i += slots;
+        }
+
+        let pin = config_read8(config, INTERRUPT_PIN);
+        let irq = match (pin, upstream) {
+            (1..=4, None) => self.irq_map.route(device, function, pin),
+            (1..=4, Some((slot, swizzle_by))) => {
+                self.irq_map
+                    .route(slot, 0, swizzle(pin, device + swizzle_by))
+            }
+            _ => None,
+        };
+
+        let mut command = COMMAND_BUS_MASTER;
+        if bars.iter().flatten().any(|bar| bar.kind == BarKind::Io) {
+            command |= COMMAND_IO;
+        }
+        if bars.iter().flatten().any(|bar| bar.kind != BarKind::Io) {
+            command |= COMMAND_MEMORY;
+        }
+        if irq.is_none() {
+            command |= COMMAND_INTX_DISABLE;
+        }
+        config_write16(config, COMMAND, command);
+
+        let dev = PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id: config_read16(config, VENDOR_ID),
+            device_id: config_read16(config, DEVICE_ID),
+            subsystem_id: config_read16(config, SUBSYSTEM_ID),
+            class: config_read32(config, CLASS) >> 8,
+            bars,
+            irq,
+            config,
+        };
+        info!(
+            "pci {}: {:04x}:{:04x} class {:06x}, irq {:?}",
+            dev.address(),
+            dev.vendor_id,
+            dev.device_id,
+            dev.class,
+            dev.irq
+        );
+        self.devices.push(dev);
+    }
+
+    /// Size and place BAR `i`, returning it and how many BAR slots it
+    /// takes.
+    fn assign_bar(&mut self, config: usize, i: usize) -> (Option<Bar>, usize) {
+        let offset = BAR0 + i * 4;
+        let orig = config_read32(config, offset);
+        config_write32(config, offset, !0);
+        let probe = config_read32(config, offset);
+        config_write32(config, offset, orig);
+
+        if orig & 0x1 != 0 {
+            let size = (!(probe & !0x3)).wrapping_add(1) as u64 & 0xffff;
+            if probe == 0 || size == 0 {
+                return (None, 1);
+            }
+            let window = match self.io.as_mut() {
+                Some(window) => window,
+                None => return (None, 1),
+            };
+            return match window.alloc(size, size) {
+                Some(pci) => {
+                    config_write32(config, offset, pci as u32);
+                    let addr = window.to_cpu(pci) as usize;
+                    let kind = BarKind::Io;
+                    let size = size as usize;
+                    (Some(Bar { kind, addr, size }), 1)
+                }
+                None => {
+                    warn!("pci: out of I/O space");
+                    (None, 1)
+                }
+            };
+        }
+
+        let is_64 = (orig >> 1) & 0x3 == 0x2;
+        let mut mask = (probe & !0xf) as u64;
+        if is_64 {
+            let high = offset + 4;
+            let orig_high = config_read32(config, high);
+            config_write32(config, high, !0);
+            mask |= (config_read32(config, high) as u64) << 32;
+            config_write32(config, high, orig_high);
+        } else {
+            mask |= 0xffff_ffff_0000_0000;
+        }
+        let slots = if is_64 { 2 } else { 1 };
+        if mask & 0xffff_fff0 == 0 && mask >> 32 == 0xffff_ffff {
+            // Not implemented.
+            return (None, slots);
+        }
+        let size = (!mask).wrapping_add(1);
+        if size == 0 {
+            return (None, slots);
+        }
+        let window = match self.mem.as_mut() {
+            Some(window) => window,
+            None => return (None, slots),
+        };
+        match window.alloc(size, size) {
+            Some(pci) => {
+                config_write32(config, offset, pci as u32);
+                if is_64 {
+                    config_write32(config, offset + 4, (pci >> 32) as u32);
+                }
+                let kind = if is_64 {
+                    BarKind::Memory64
+                } else {
+                    BarKind::Memory32
+                };
+                let addr = window.to_cpu(pci) as usize;
+                (
+                    Some(Bar {
+                        kind,
+                        addr,
+                        size: size as usize,
+                    }),
+                    slots,
+                )
+            }
+            None => {
+                warn!("pci: out of memory space for a {:#x} byte BAR", size);
+                (None, slots)
+            }
+        }
+    }
+
+    /// Number the bus behind a PCI-to-PCI bridge, scan it, and open
+    /// windows for whatever ended up behind it.
+    fn add_bridge(&mut self, bus: u8, device: u8, function: u8, upstream: Option<(u8, u8)>) {
+        let config = self.config(bus, device, function);
+        if self.next_bus > self.bus_end {
+            warn!("pci: out of bus numbers");
+            return;
+        }
+        let secondary = self.next_bus;
+        self.next_bus += 1;
+        config_write16(config, COMMAND, 0);
+        config_write8(config, PRIMARY_BUS, bus);
+        config_write8(config, SECONDARY_BUS, secondary);
+        config_write8(config, SUBORDINATE_BUS, self.bus_end);
+
+        if let Some(io) = self.io.as_mut() {
+            io.next = align_up(io.next, BRIDGE_IO_ALIGN);
+        }
+        if let Some(mem) = self.mem.as_mut() {
+            mem.next = align_up(mem.next, BRIDGE_MEMORY_ALIGN);
+        }
+        let io_start = self.io.map(|w| w.next);
+        let mem_start = self.mem.map(|w| w.next);
+
+        // Pins of devices behind us get swizzled by our slot on the way
+        // up, unless we're the bridge on the root bus that interrupts are
+        // routed by.
+        let upstream = match upstream {
+            None => Some((device, 0)),
+            Some((slot, swizzle_by)) => Some((slot, (swizzle_by + device) % 4)),
+        };
+        self.scan_bus(secondary, upstream);
+        config_write8(config, SUBORDINATE_BUS, self.next_bus - 1);
+
+        // Windows are inclusive, so an empty one is base > limit.
+        if let (Some(io), Some(start)) = (self.io.as_mut(), io_start) {
+            io.next = align_up(io.next, BRIDGE_IO_ALIGN);
+            if io.next > start {
+                config_write8(config, IO_BASE, (start >> 8) as u8 & 0xf0);
+                config_write8(config, IO_LIMIT, ((io.next - 1) >> 8) as u8 & 0xf0);
+            } else {
+                config_write8(config, IO_BASE, 0xf0);
+                config_write8(config, IO_LIMIT, 0);
+            }
+        }
+        if let (Some(mem), Some(start)) = (self.mem.as_mut(), mem_start) {
+            mem.next = align_up(mem.next, BRIDGE_MEMORY_ALIGN);
+            if mem.next > start {
+                config_write16(config, MEMORY_BASE, (start >> 16) as u16 & 0xfff0);
+                config_write16(config, MEMORY_LIMIT, ((mem.next - 1) >> 16) as u16 & 0xfff0);
+            } else {
+                config_write16(config, MEMORY_BASE, 0xfff0);
+                config_write16(config, MEMORY_LIMIT, 0);
+            }
+        }
+        // Everything goes through the non-prefetchable window.
+        config_write16(config, PREFETCH_BASE, 0xfff0);
+        config_write16(config, PREFETCH_LIMIT, 0);
+
+        config_write16(
+            config,
+            COMMAND,
+            COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+        info!(
+            "pci {:02x}:{:02x}.{}: bridge to bus {:02x}",
+            bus, device, function, secondary
+        );
+    }
+}
+
+/// Set up everything behind the host bridge `node` and return the functions
+/// found, bridges aside.
+pub fn enumerate(node: &Node) -> Vec<PciDevice> {
+    let reg = match node.prop_raw("reg") {
+        Some(reg) => cells(reg),
+        None => return Vec::new(),
+    };
+    if reg.len() < 4 {
+        return Vec::new();
+    }
+    let ecam = ((reg[0] as u64) << 32 | reg[1] as u64) as usize;
+    let ecam_size = (reg[2] as u64) << 32 | reg[3] as u64;
+    let (bus_start, bus_end) = match node.prop_raw("bus-range").map(|r| cells(r)) {
+        Some(range) if range.len() == 2 => (range[0] as u8, range[1] as u8),
+        _ => (0, ((ecam_size >> 20).max(1) - 1).min(0xff) as u8),
+    };
+    let (io, mem) = node
+        .prop_raw("ranges")
+        .map_or((None, None), |r| parse_ranges(r));
+    let irq_map = match (
+        node.prop_raw("interrupt-map"),
+        node.prop_raw("interrupt-map-mask"),
+    ) {
+        (Some(map), Some(mask)) => InterruptMap::parse(map, mask),
+        _ => InterruptMap::parse(&[], &[]),
+    };
+    info!(
+        "pci: ECAM at {:#x}, buses {:02x}-{:02x}",
+        ecam, bus_start, bus_end
+    );
+
+    // The ECAM starts at the first bus of the range.
+    let mut enumerator = Enumerator {
+        ecam: ecam - ((bus_start as usize) << 20),
+        bus_end,
+        next_bus: bus_start + 1,
+        io,
+        mem,
+        irq_map,
+        devices: Vec::new(),
+    };
+    enumerator.scan_bus(bus_start, None);
+    enumerator.devices
+}
+
+#[test_case]
+fn test_pci_interrupt_routing() {
+    fn be(cells: &[u32]) -> Vec<u8> {
+        cells.iter().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    // What QEMU's virt machine has: slot s, pin p goes to 32 + (s + p - 1) % 4.
+    let plic = 9;
+    let mut map = Vec::new();
+    for slot in 0..4u32 {
+        for pin in 1..=4u32 {
+            map.extend([slot << 11, 0, 0, pin, plic, 32 + (slot + pin - 1) % 4]);
+        }
+    }
+    let map = InterruptMap::parse(&be(&map), &be(&[0x1800, 0, 0, 7]));
+    assert_eq!(map.route(0, 0, 1), Some(32));
+    assert_eq!(map.route(1, 0, 1), Some(33));
+    assert_eq!(map.route(3, 0, 2), Some(32));
+    // Only the low two bits of the slot count.
+    assert_eq!(map.route(5, 3, 1), Some(33));
+
+    // Behind a bridge in slot 2, a device in slot 1 raising INTA.
+    assert_eq!(swizzle(1, 1), 2);
+    assert_eq!(map.route(2, 0, swizzle(1, 1)), Some(35));
+
+    let (io, mem) = parse_ranges(&be(&[
+        0x0100_0000,
+        0,
+        0,
+        0,
+        0x0300_0000,
+        0,
+        0x1_0000, // I/O
+        0x0200_0000,
+        0,
+        0x4000_0000,
+        0,
+        0x4000_0000,
+        0,
+        0x4000_0000, // 32-bit memory
+        0x0300_0000,
+        4,
+        0,
+        4,
+        0,
+        4,
+        0, // 64-bit memory
+    ]));
+    let mut mem = mem.unwrap();
+    assert_eq!(io.unwrap().to_cpu(0x1000), 0x0300_1000);
+    assert_eq!(mem.alloc(0x1000, 0x1000), Some(0x4000_0000));
+    assert_eq!(mem.alloc(0x4000, 0x4000), Some(0x4000_4000));
+    assert_eq!(mem.alloc(0x8000_0000, 0x8000_0000), None);
+}
@@ -1,6 +1,6 @@
 use core::ptr::{read_volatile, write_volatile};
 
-use crate::{print, trap::Frame, uart};
+use crate::{block, print, trap::Frame, uart};
 
 const PRIORITY: *mut u32 = 0xc000000 as *mut u32;
 //const PENDING: *mut u32 = 0xc001000 as *mut u32;
@@ -13,10 +13,14 @@ const COMPLETE: *mut u32 = 0xc201004 as *mut u32;
 // Also add disable method.
 // It's becoming more of a Rust exercise than OS's.
 pub fn init() {
-    enable(QemuSource::Uart0);
-    enable(QemuSource::Virtio8);
-    set_priority(QemuSource::Uart0, 1);
-    set_priority(QemuSource::Virtio8, 1);
+    use QemuSource::*;
+    for intr in [
+        Uart0, Virtio1, Virtio2, Virtio3, Virtio4, Virtio5, Virtio6, Virtio7, Virtio8, PcieA,
+        PcieB, PcieC, PcieD,
+    ] {
+        enable(intr);
+        set_priority(intr, 1);
+    }
     set_thresold(0);
     unsafe {
         riscv::register::sie::set_sext();
@@ -33,15 +37,16 @@ pub fn handle_interrupts(_frame: &mut Frame) {
                 b => print!("{}", b as char),
             },
 
-            Virtio8 => {
-                //info!("virtio8");
+            Virtio1 | Virtio2 | Virtio3 | Virtio4 | Virtio5 | Virtio6 | Virtio7 | Virtio8 => {
+                block::handle_interrupt(intr.0 as u32)
             }
 
+            // INTx lines are shared, every device on it gets to look.
+            PcieA | PcieB | PcieC | PcieD => block::handle_interrupt(intr.0 as u32),
+
             // TODO: We can forget to handle Unknown variant,
             // causing the ClaimedSource to Drop with completing id 54.
             Unknown => unimplemented!("unkonwn plic interrupt"),
-
-            _ => unimplemented!(),
         }
     }
 }
@@ -56,9 +61,11 @@ impl Drop for ClaimedSource {
 }
 
 fn enable(intr: QemuSource) {
-    let actual_id = 1 << intr as u32;
+    // One bit per source, 32 sources per word.
+    let reg = unsafe { INT_ENABLE.add(intr as usize / 32) };
+    let actual_id = 1 << (intr as u32 % 32);
     unsafe {
-        write_volatile(INT_ENABLE, read_volatile(INT_ENABLE) | actual_id);
+        write_volatile(reg, read_volatile(reg) | actual_id);
     }
 }
 
@@ -96,6 +103,10 @@ enum QemuSource {
     Virtio7 = 7,
     Virtio8 = 8,
     Uart0 = 10,
+    PcieA = 32,
+    PcieB = 33,
+    PcieC = 34,
+    PcieD = 35,
     Unknown = 54,
 }
 
@@ -113,6 +124,10 @@ impl QemuSource {
             7 => Virtio7,
             8 => Virtio8,
             10 => Uart0,
+            32 => PcieA,
+            33 => PcieB,
+            34 => PcieC,
+            35 => PcieD,
             _ => Unknown,
         })
     }
@@ -0,0 +1,262 @@
+//! Block devices backed by memory.
+
+#[cfg(test)]
+use alloc::collections::BTreeMap;
+use alloc::{vec, vec::Vec};
+
+use device_tree::{util::SliceRead, Node};
+use spin::Mutex;
+
+use crate::block::{check_range, BlockDevice, BlockError};
+
+/// Unless asked for something else, see `RamDisk::with_block_size`.
+pub const BLOCK_SIZE: usize = 512;
+
+enum Storage {
+    Owned(Vec<u8>),
+
+    /// Memory we don't own, like the initrd.
+    Region(&'static mut [u8]),
+
+    /// Only the blocks that were written, the rest read as zeros.
+    #[cfg(test)]
+    Sparse {
+        blocks: BTreeMap<usize, Vec<u8>>,
+        count: usize,
+    },
+}
+
+pub struct RamDisk {
+    storage: Storage,
+    block_size: usize,
+}
+
+impl RamDisk {
+    /// An empty (zeroed) disk of `size` bytes, rounded up to whole blocks.
+    pub fn new(size: usize) -> Self {
+        Self::with_block_size(size, BLOCK_SIZE)
+    }
+
+    /// Like `new`, but with blocks of `block_size` bytes, to match a
+    /// device it stands in for.
+    pub fn with_block_size(size: usize, block_size: usize) -> Self {
+        assert!(block_size.is_power_of_two());
+        let blocks = (size + block_size - 1) / block_size;
+        RamDisk {
+            storage: Storage::Owned(vec![0; blocks * block_size]),
+            block_size,
+        }
+    }
+
+    /// A disk holding `data`, which must be whole blocks.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        RamDisk {
+            storage: Storage::Owned(data),
+            block_size: BLOCK_SIZE,
+        }
+    }
+
+    /// A disk over memory we don't own, like the initrd.
+    ///
+    /// Anything after the last whole block is left out.
+    pub fn from_region(data: &'static mut [u8]) -> Self {
+        let len = data.len() / BLOCK_SIZE * BLOCK_SIZE;
+        RamDisk {
+            storage: Storage::Region(&mut data[..len]),
+            block_size: BLOCK_SIZE,
+        }
+    }
+
+    /// An empty disk of `size` bytes that only takes up memory for the
+    /// blocks written to it, for test images too big to keep whole.
+    #[cfg(test)]
+    pub fn sparse(size: usize) -> Self {
+        RamDisk {
+            storage: Storage::Sparse {
+                blocks: BTreeMap::new(),
+                count: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            },
+            block_size: BLOCK_SIZE,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match &self.storage {
+            Storage::Owned(data) => data,
+            Storage::Region(data) => data,
+            #[cfg(test)]
+            Storage::Sparse { .. } => unreachable!("sparse disks aren't contiguous"),
+        }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        match &mut self.storage {
+            Storage::Owned(data) => data,
+            Storage::Region(data) => data,
+            #[cfg(test)]
+            Storage::Sparse { .. } => unreachable!("sparse disks aren't contiguous"),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> usize {
+        #[cfg(test)]
+        if let Storage::Sparse { count, .. } = &self.storage {
+            return *count;
+        }
+        self.data().len() / self.block_size
+    }
+
+    fn read_blocks(&mut self, blk_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        #[cfg(test)]
+        if let Storage::Sparse { blocks, .. } = &self.storage {
+            for (i, chunk) in buf.chunks_mut(self.block_size).enumerate() {
+                match blocks.get(&(blk_id + i)) {
+                    Some(block) => chunk.copy_from_slice(block),
+                    None => chunk.fill(0),
+                }
+            }
+            return Ok(());
+        }
+        let start = blk_id * self.block_size;
+        buf.copy_from_slice(&self.data()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, blk_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, blk_id, buf.len())?;
+        #[cfg(test)]
+        if let Storage::Sparse { blocks, .. } = &mut self.storage {
+            for (i, chunk) in buf.chunks(self.block_size).enumerate() {
+                blocks.insert(blk_id + i, chunk.to_vec());
+            }
+            return Ok(());
+        }
+        let start = blk_id * self.block_size;
+        self.data_mut()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Discarded blocks read back as zeros.
+    fn discard(&mut self, blk_id: usize, count: usize) -> Result<(), BlockError> {
+        check_range(self, blk_id, count * self.block_size)?;
+        #[cfg(test)]
+        if let Storage::Sparse { blocks, .. } = &mut self.storage {
+            for blk in blk_id..blk_id + count {
+                blocks.remove(&blk);
+            }
+            return Ok(());
+        }
+        let (start, len) = (blk_id * self.block_size, count * self.block_size);
+        self.data_mut()[start..start + len].fill(0);
+        Ok(())
+    }
+}
+
+/// Where the initrd is, once `device` found it, until someone takes it.
+static INITRD: Mutex<Option<(usize, usize)>> = Mutex::new(None);
+
+/// # Safety
+///
+/// `start..end` has to be mapped, and not used for anything else.
+pub unsafe fn set_initrd(start: usize, end: usize) {
+    *INITRD.lock() = Some((start, end));
+}
+
+/// The initrd's memory, to use as a disk or, when it's not a disk image but
+/// a plain file, to read. Only the first caller gets it.
+pub fn take_initrd() -> Option<&'static mut [u8]> {
+    let (start, end) = INITRD.lock().take()?;
+    // See `set_initrd`, and it can't be taken twice.
+    Some(unsafe { core::slice::from_raw_parts_mut(start as *mut u8, end - start) })
+}
+
+/// Where the bootloader put the initrd, from the `/chosen` node.
+pub fn initrd_region(chosen: &Node) -> Option<(usize, usize)> {
+    // Either property can be 32 or 64 bits wide.
+    let prop = |name| {
+        let raw = chosen.prop_raw(name)?;
+        let raw = raw.as_slice();
+        match raw.len() {
+            4 => raw.read_be_u32(0).ok().map(|v| v as usize),
+            8 => raw.read_be_u64(0).ok().map(|v| v as usize),
+            _ => None,
+        }
+    };
+    let start = prop("linux,initrd-start")?;
+    let end = prop("linux,initrd-end")?;
+    (start < end).then_some((start, end))
+}
+
+/// Unpack an image made by `mktestimg.py`.
+#[cfg(test)]
+pub fn from_sparse(image: &[u8]) -> RamDisk {
+    let le_u32 = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap()) as usize;
+    assert_eq!(le_u32(0), BLOCK_SIZE);
+    let mut disk = RamDisk::sparse(le_u32(4) * BLOCK_SIZE);
+    let mut at = 8;
+    while at < image.len() {
+        let blk_id = le_u32(at);
+        disk.write(blk_id, &image[at + 4..at + 4 + BLOCK_SIZE])
+            .unwrap();
+        at += 4 + BLOCK_SIZE;
+    }
+    disk
+}
+
+/// The FAT32 volume from `testdata/`.
+#[cfg(test)]
+pub fn fat32_test_image() -> RamDisk {
+    from_sparse(include_bytes!("../testdata/fat32.img.sparse"))
+}
+
+/// The FAT32 volume with 4 KiB sectors and 32 KiB clusters from `testdata/`.
+#[cfg(test)]
+pub fn fat32_4k_test_image() -> RamDisk {
+    from_sparse(include_bytes!("../testdata/fat32-4k.img.sparse"))
+}
+
+/// A FAT16 volume with the same files as the FAT32 one, from `testdata/`.
+#[cfg(test)]
+pub fn fat16_test_image() -> RamDisk {
+    from_sparse(include_bytes!("../testdata/fat16.img.sparse"))
+}
+
+/// A 1.44M floppy with a FAT12 volume on it, from `testdata/`.
+#[cfg(test)]
+pub fn fat12_test_image() -> RamDisk {
+    from_sparse(include_bytes!("../testdata/fat12.img.sparse"))
+}
+
+/// An exFAT volume from `testdata/`.
+#[cfg(test)]
+pub fn exfat_test_image() -> RamDisk {
+    from_sparse(include_bytes!("../testdata/exfat.img.sparse"))
+}
+
+#[test_case]
+fn test_ramdisk() {
+    let mut disk = RamDisk::new(1000);
+    assert_eq!(disk.block_count(), 2);
+
+    let mut buf = [0; 2 * BLOCK_SIZE];
+    disk.write(1, &[0xaa; BLOCK_SIZE]).unwrap();
+    disk.read_blocks(0, &mut buf).unwrap();
+    assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 0));
+    assert!(buf[BLOCK_SIZE..].iter().all(|&b| b == 0xaa));
+
+    assert_eq!(
+        disk.read(2, &mut buf[..BLOCK_SIZE]),
+        Err(BlockError::OutOfRange)
+    );
+    disk.discard(1, 1).unwrap();
+    disk.read(1, &mut buf[..BLOCK_SIZE]).unwrap();
+    assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == 0));
+}
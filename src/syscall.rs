@@ -0,0 +1,320 @@
+//! System calls, with Linux's numbers and calling convention: the number in
+//! `a7`, arguments in `a0` to `a5`, and the result or a negated errno back
+//! in `a0`.
+
+use alloc::{string::String, vec};
+use core::time::Duration;
+
+use log::warn;
+
+use crate::{
+    addr::{PageTableFlags, VirtAddr},
+    allocator::PAGE_SIZE,
+    futex::{self, FutexError, FutexOp},
+    memory, print, thread, timer,
+    trap::{Frame, A0, A7, SP, TP},
+};
+
+const SYS_WRITE: usize = 64;
+const SYS_EXIT: usize = 93;
+const SYS_EXIT_GROUP: usize = 94;
+const SYS_FUTEX: usize = 98;
+const SYS_SCHED_YIELD: usize = 124;
+const SYS_GETTID: usize = 178;
+const SYS_CLONE: usize = 220;
+
+const EAGAIN: usize = 11;
+const EFAULT: usize = 14;
+const EINVAL: usize = 22;
+const ENOSYS: usize = 38;
+pub const ETIMEDOUT: usize = 110;
+
+// `clone` flags.
+const CLONE_VM: usize = 0x100;
+const CLONE_FS: usize = 0x200;
+const CLONE_FILES: usize = 0x400;
+const CLONE_SIGHAND: usize = 0x800;
+const CLONE_THREAD: usize = 0x10000;
+const CLONE_SYSVSEM: usize = 0x40000;
+const CLONE_SETTLS: usize = 0x80000;
+const CLONE_PARENT_SETTID: usize = 0x100000;
+const CLONE_CHILD_CLEARTID: usize = 0x200000;
+const CLONE_CHILD_SETTID: usize = 0x1000000;
+
+/// Everything is shared anyway, so these don't change anything.
+const CLONE_SHARED: usize =
+    CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM;
+
+/// `a0` for a syscall that failed with `errno`.
+pub fn errno(errno: usize) -> usize {
+    errno.wrapping_neg()
+}
+
+pub fn handle(frame: &mut Frame) {
+    frame.skip_instruction();
+    let args = [0, 1, 2, 3, 4, 5].map(|i| frame.reg(A0 + i));
+    let ret = match frame.reg(A7) {
+        SYS_WRITE => write(args[0], args[1], args[2]),
+        SYS_EXIT => return thread::exit(frame),
+        SYS_EXIT_GROUP => return thread::exit_group(frame, args[0] as i32),
+        SYS_FUTEX => match sys_futex(frame, args) {
+            // The thread is blocked, and gets its result when it's woken up.
+            None => return,
+            Some(ret) => ret,
+        },
+        SYS_SCHED_YIELD => {
+            frame.set_reg(A0, 0);
+            return thread::schedule(frame);
+        }
+        SYS_GETTID => Ok(thread::current_tid()),
+        SYS_CLONE => clone(frame, args),
+        n => {
+            warn!("unknown syscall {}", n);
+            Err(ENOSYS)
+        }
+    };
+    frame.set_reg(A0, ret.unwrap_or_else(errno));
+}
+
+/// Only to the console, for now. Writes at most a page at a time.
+fn write(fd: usize, buf: usize, len: usize) -> Result<usize, usize> {
+    if fd != 1 && fd != 2 {
+        return Err(EINVAL);
+    }
+    let mut data = vec![0; len.min(PAGE_SIZE)];
+    memory::read_user(buf, &mut data).ok_or(EFAULT)?;
+    print!("{}", String::from_utf8_lossy(&data));
+    Ok(data.len())
+}
+
+/// `futex(uaddr, op, val, timeout or val2, uaddr2, val3)`. Returns `None`
+/// when the thread went to sleep.
+fn sys_futex(frame: &mut Frame, args: [usize; 6]) -> Option<Result<usize, usize>> {
+    let [uaddr, op, val, timeout, uaddr2, val3] = args;
+    let uaddr = match user_word(uaddr, PageTableFlags::READABLE) {
+        Ok(uaddr) => uaddr,
+        Err(e) => return Some(Err(e)),
+    };
+    let uaddr2 = match FutexOp::from_raw(op) {
+        Some(FutexOp::Wait) => None,
+        Some(FutexOp::Requeue | FutexOp::CmpRequeue) => {
+            Some(user_word(uaddr2, PageTableFlags::READABLE))
+        }
+        // Unused, and not necessarily valid.
+        _ => Some(Ok(uaddr)),
+    };
+    if let Some(uaddr2) = uaddr2 {
+        let ret = uaddr2.and_then(|uaddr2| {
+            // `timeout` is `val2` here.
+            futex::futex(uaddr, op, val as u32, None, timeout, uaddr2, val3 as u32)
+                .map_err(futex_errno)
+        });
+        return Some(ret);
+    }
+
+    let deadline = match timeout {
+        0 => None,
+        ptr => match read_timespec(ptr) {
+            Ok(timeout) => Some(timer::ticks_after(timeout)),
+            Err(e) => return Some(Err(e)),
+        },
+    };
+    match futex::queue(uaddr, val as u32) {
+        Ok(waiter) => {
+            thread::block(frame, waiter, deadline);
+            None
+        }
+        Err(e) => Some(Err(futex_errno(e))),
+    }
+}
+
+/// `clone(flags, stack, parent_tid, tls, child_tid)`, for threads only.
+fn clone(frame: &mut Frame, args: [usize; 6]) -> Result<usize, usize> {
+    let [flags, stack, parent_tid, tls, child_tid, _] = args;
+    let known = CLONE_SHARED
+        | CLONE_SETTLS
+        | CLONE_PARENT_SETTID
+        | CLONE_CHILD_CLEARTID
+        | CLONE_CHILD_SETTID;
+    // No processes, only threads. The low byte is the exit signal.
+    if flags & CLONE_VM == 0 || flags & !known & !0xff != 0 {
+        return Err(EINVAL);
+    }
+
+    let clear_child_tid = match flags & CLONE_CHILD_CLEARTID {
+        0 => None,
+        _ => Some(user_word(child_tid, PageTableFlags::WRITABLE)?.as_u64() as usize),
+    };
+    // Before the thread exists, so that there's nothing to undo if the
+    // pointers are bad. A tid that's never used is no loss.
+    let tid = thread::alloc_tid();
+    if flags & CLONE_PARENT_SETTID != 0 {
+        memory::write_user_u32(parent_tid, tid as u32).ok_or(EFAULT)?;
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        memory::write_user_u32(child_tid, tid as u32).ok_or(EFAULT)?;
+    }
+
+    // Picks up right after the `ecall` too, seeing 0 returned.
+    let mut child = *frame;
+    child.set_reg(A0, 0);
+    if stack != 0 {
+        child.set_reg(SP, stack);
+    }
+    if flags & CLONE_SETTLS != 0 {
+        child.set_reg(TP, tls);
+    }
+    thread::spawn(tid, child, clear_child_tid);
+    Ok(tid)
+}
+
+/// `addr` if it's an aligned word user mode can access with `flags`.
+fn user_word(addr: usize, flags: PageTableFlags) -> Result<VirtAddr, usize> {
+    let vaddr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if addr % 4 != 0 || memory::translate_user(vaddr, flags).is_none() {
+        return Err(EFAULT);
+    }
+    Ok(vaddr)
+}
+
+/// A relative `struct timespec`.
+fn read_timespec(addr: usize) -> Result<Duration, usize> {
+    let mut raw = [0; 16];
+    memory::read_user(addr, &mut raw).ok_or(EFAULT)?;
+    let secs = i64::from_le_bytes(raw[..8].try_into().unwrap());
+    let nanos = i64::from_le_bytes(raw[8..].try_into().unwrap());
+    if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(EINVAL);
+    }
+    Ok(Duration::new(secs as u64, nanos as u32))
+}
+
+fn futex_errno(e: FutexError) -> usize {
+    match e {
+        FutexError::WouldBlock => EAGAIN,
+        FutexError::TimedOut => ETIMEDOUT,
+        FutexError::Fault => EFAULT,
+        FutexError::InvalidOp => ENOSYS,
+    }
+}
+
+/// Make syscall `n` from `frame`, and return what `a0` holds afterwards,
+/// in whatever context that is.
+#[cfg(test)]
+fn call(frame: &mut Frame, n: usize, args: &[usize]) -> usize {
+    frame.set_reg(A7, n);
+    for (i, &arg) in args.iter().enumerate() {
+        frame.set_reg(A0 + i, arg);
+    }
+    handle(frame);
+    frame.reg(A0)
+}
+
+/// `a0` in the idle context the tests return to.
+#[cfg(test)]
+const IDLE: usize = 0x1d1e;
+
+/// A thread, and the idle context it runs from.
+#[cfg(test)]
+fn test_frames() -> (Frame, Frame) {
+    let mut idle = Frame::user(0, 0);
+    idle.set_reg(A0, IDLE);
+    (Frame::user(0x1000, 0x8000), idle)
+}
+
+#[test_case]
+fn test_syscall_dispatch() {
+    let (mut frame, idle) = test_frames();
+    let tid = thread::enter(frame, idle);
+    assert_eq!(call(&mut frame, SYS_GETTID, &[]), tid);
+
+    let msg = b"[write] ";
+    let buf = msg.as_ptr() as usize;
+    assert_eq!(call(&mut frame, SYS_WRITE, &[1, buf, msg.len()]), msg.len());
+    assert_eq!(
+        call(&mut frame, SYS_WRITE, &[3, buf, msg.len()]),
+        errno(EINVAL)
+    );
+    assert_eq!(call(&mut frame, 4242, &[]), errno(ENOSYS));
+    // No processes, only threads.
+    assert_eq!(
+        call(&mut frame, SYS_CLONE, &[17, 0, 0, 0, 0]),
+        errno(EINVAL)
+    );
+    assert_eq!(
+        call(&mut frame, SYS_FUTEX, &[buf + 1, 0, 0, 0, 0, 0]),
+        errno(EFAULT)
+    );
+    assert_eq!(
+        call(&mut frame, SYS_FUTEX, &[buf, 42, 0, 0, 0, 0]),
+        errno(ENOSYS)
+    );
+
+    // With nothing else to run, the same thread carries on.
+    assert_eq!(call(&mut frame, SYS_SCHED_YIELD, &[]), 0);
+    assert_eq!(thread::current_tid(), tid);
+    assert_eq!(frame.reg(SP), 0x8000);
+
+    call(&mut frame, SYS_EXIT_GROUP, &[0]);
+    assert_eq!(frame.reg(A0), IDLE);
+}
+
+#[test_case]
+fn test_syscall_clone_and_futex() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::futex::{FUTEX_WAIT, FUTEX_WAKE};
+
+    // Where `pthread_join` would wait for the child to exit.
+    static CHILD_TID: AtomicU32 = AtomicU32::new(0);
+    let ctid = &CHILD_TID as *const AtomicU32 as usize;
+
+    let (mut frame, idle) = test_frames();
+    let parent = thread::enter(frame, idle);
+    let flags = CLONE_SHARED | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+    let clone = |frame: &mut Frame, ptid| call(frame, SYS_CLONE, &[flags, 0x4000, ptid, 7, ctid]);
+
+    // A bad pointer fails the call before there's a thread.
+    assert_eq!(clone(&mut frame, ctid + 2), errno(EFAULT));
+    assert_eq!(call(&mut frame, SYS_SCHED_YIELD, &[]), 0);
+    assert_eq!(thread::current_tid(), parent);
+
+    let child = clone(&mut frame, ctid);
+    assert_eq!(CHILD_TID.load(Ordering::Relaxed), child as u32);
+    assert_eq!(thread::current_tid(), parent);
+    assert_eq!(
+        call(&mut frame, SYS_FUTEX, &[ctid, FUTEX_WAIT, child + 1, 0]),
+        errno(EAGAIN)
+    );
+
+    // Waiting for the child runs it, from the same place with its own
+    // stack and thread pointer.
+    call(&mut frame, SYS_FUTEX, &[ctid, FUTEX_WAIT, child, 0]);
+    assert_eq!(thread::current_tid(), child);
+    assert_eq!(frame.reg(A0), 0);
+    assert_eq!(frame.reg(SP), 0x4000);
+    assert_eq!(frame.reg(TP), 7);
+
+    // A wait that times out straight away comes back to the child, the
+    // parent is still waiting.
+    static TIMEOUT: [u64; 2] = [0; 2];
+    let timeout = TIMEOUT.as_ptr() as usize;
+    assert_eq!(
+        call(&mut frame, SYS_FUTEX, &[ctid, FUTEX_WAIT, child, timeout]),
+        errno(ETIMEDOUT)
+    );
+    assert_eq!(thread::current_tid(), child);
+    assert_eq!(call(&mut frame, SYS_FUTEX, &[ctid, FUTEX_WAKE, 2]), 1);
+    assert_eq!(call(&mut frame, SYS_FUTEX, &[ctid, FUTEX_WAKE, 2]), 0);
+
+    // Exiting clears the tid, and the parent gets its turn.
+    call(&mut frame, SYS_EXIT, &[0]);
+    assert_eq!(CHILD_TID.load(Ordering::Relaxed), 0);
+    assert_eq!(thread::current_tid(), parent);
+    assert_eq!(frame.reg(A0), 0);
+    assert_eq!(frame.reg(SP), 0x8000);
+
+    // Once the last thread is gone, the idle context is back.
+    call(&mut frame, SYS_EXIT, &[0]);
+    assert_eq!(frame.reg(A0), IDLE);
+}
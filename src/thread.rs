@@ -0,0 +1,229 @@
+//! User threads, scheduled round-robin on the timer tick.
+//!
+//! There's a single address space, so a thread is just a saved trap frame.
+//! Switching threads means swapping the frame the trap handler returns
+//! through. The kernel context that called [`run`] becomes the idle context,
+//! and only runs when no thread can.
+//!
+//! NOTE: Floating point registers aren't saved, user programs must not use
+//! them (`user/` builds with `-march=rv64imac`).
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use log::info;
+use riscv::{asm::wfi, register::time};
+use spin::Mutex;
+
+use crate::{
+    addr::VirtAddr,
+    futex::{self, Waiter},
+    memory, syscall,
+    trap::{Frame, A0},
+};
+
+pub type Tid = usize;
+
+enum State {
+    Ready,
+
+    /// Blocked in `FUTEX_WAIT`, until woken up or the time CSR reaches
+    /// `deadline`.
+    Waiting {
+        waiter: Arc<Waiter>,
+        deadline: Option<u64>,
+    },
+}
+
+struct Thread {
+    tid: Tid,
+    frame: Frame,
+    state: State,
+
+    /// Zeroed and woken when the thread exits, for `pthread_join`.
+    /// Set by `clone` with `CLONE_CHILD_CLEARTID`.
+    clear_child_tid: Option<usize>,
+}
+
+impl Thread {
+    /// Whether the thread can run now. A waiting thread that was woken up
+    /// or timed out becomes ready, with the result of its `futex` call.
+    fn poll(&mut self, now: u64) -> bool {
+        let (waiter, deadline) = match &self.state {
+            State::Ready => return true,
+            State::Waiting { waiter, deadline } => (waiter, *deadline),
+        };
+        let ret = if waiter.is_woken() {
+            0
+        } else if matches!(deadline, Some(deadline) if now >= deadline) {
+            futex::cancel(waiter);
+            syscall::errno(syscall::ETIMEDOUT)
+        } else {
+            return false;
+        };
+        self.frame.set_reg(A0, ret);
+        self.state = State::Ready;
+        true
+    }
+}
+
+struct Scheduler {
+    /// The running thread, `None` in the idle context.
+    current: Option<Thread>,
+
+    /// The other threads, in the order they get to run.
+    threads: VecDeque<Thread>,
+
+    /// Where the idle context left off, while a thread runs.
+    idle: Option<Frame>,
+
+    next_tid: Tid,
+}
+
+impl Scheduler {
+    /// Put the context in `frame` aside, and switch to the next one.
+    fn reschedule(&mut self, frame: &mut Frame) {
+        match self.current.take() {
+            Some(mut thread) => {
+                thread.frame = *frame;
+                self.threads.push_back(thread);
+            }
+            None => self.idle = Some(*frame),
+        }
+        self.switch(frame);
+    }
+
+    /// Load the first thread that can run into `frame`, or the idle context
+    /// if there's none. The current context must have been put aside.
+    fn switch(&mut self, frame: &mut Frame) {
+        let now = time::read64();
+        let next = self.threads.iter_mut().position(|thread| thread.poll(now));
+        match next.and_then(|i| self.threads.remove(i)) {
+            Some(thread) => {
+                *frame = thread.frame;
+                self.current = Some(thread);
+            }
+            None => *frame = self.idle.take().expect("no idle context to return to"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        current: None,
+        threads: VecDeque::new(),
+        idle: None,
+        next_tid: 1,
+    });
+}
+
+/// Set by `run`, from then on traps in the kernel come from the idle loop.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// A tid for a new thread, to hand out before it exists.
+pub fn alloc_tid() -> Tid {
+    let mut sched = SCHEDULER.lock();
+    let tid = sched.next_tid;
+    sched.next_tid += 1;
+    tid
+}
+
+/// Add a thread that starts out with the registers in `frame`.
+/// `clear_child_tid` is zeroed and woken up when it exits.
+pub fn spawn(tid: Tid, frame: Frame, clear_child_tid: Option<usize>) {
+    SCHEDULER.lock().threads.push_back(Thread {
+        tid,
+        frame,
+        state: State::Ready,
+        clear_child_tid,
+    });
+}
+
+/// Hand the hart over to the threads. The first one starts on the next
+/// timer tick.
+pub fn run() -> ! {
+    RUNNING.store(true, Ordering::Release);
+    loop {
+        unsafe { wfi() };
+    }
+}
+
+/// Let the next thread run, if there's one that can. Called on the timer
+/// tick, and by `sched_yield`.
+pub fn schedule(frame: &mut Frame) {
+    // Before `run`, the kernel may be anywhere, holding any lock.
+    if RUNNING.load(Ordering::Acquire) {
+        SCHEDULER.lock().reschedule(frame);
+    }
+}
+
+/// The thread making a syscall.
+pub fn current_tid() -> Tid {
+    SCHEDULER
+        .lock()
+        .current
+        .as_ref()
+        .expect("syscall outside of a thread")
+        .tid
+}
+
+/// Block the current thread on `waiter`, and switch to another one.
+/// `deadline` is in ticks of the time CSR, and only checked on the timer
+/// tick.
+pub fn block(frame: &mut Frame, waiter: Arc<Waiter>, deadline: Option<u64>) {
+    let mut sched = SCHEDULER.lock();
+    let mut thread = sched.current.take().expect("blocking outside of a thread");
+    thread.frame = *frame;
+    thread.state = State::Waiting { waiter, deadline };
+    sched.threads.push_back(thread);
+    sched.switch(frame);
+}
+
+/// End the current thread.
+pub fn exit(frame: &mut Frame) {
+    let mut sched = SCHEDULER.lock();
+    let thread = sched.current.take().expect("exit outside of a thread");
+    if let Some(addr) = thread.clear_child_tid {
+        // Like Linux, which is what `pthread_join` waits for.
+        if memory::write_user_u32(addr, 0).is_some() {
+            let _ = futex::wake(VirtAddr::new(addr as u64), 1);
+        }
+    }
+    if sched.threads.is_empty() {
+        info!("the last thread {} exited", thread.tid);
+    }
+    sched.switch(frame);
+}
+
+/// Make a new thread running `frame` the current one, as if it had just
+/// trapped, with `idle` to go back to once no thread can run.
+#[cfg(test)]
+pub fn enter(frame: Frame, idle: Frame) -> Tid {
+    let tid = alloc_tid();
+    let mut sched = SCHEDULER.lock();
+    assert!(sched.current.is_none() && sched.threads.is_empty());
+    RUNNING.store(true, Ordering::Release);
+    sched.idle = Some(idle);
+    sched.current = Some(Thread {
+        tid,
+        frame,
+        state: State::Ready,
+        clear_child_tid: None,
+    });
+    tid
+}
+
+/// End every thread, after one of them asked to with `exit_group` or broke.
+pub fn exit_group(frame: &mut Frame, status: i32) {
+    let mut sched = SCHEDULER.lock();
+    let sched = &mut *sched;
+    let thread = sched.current.take().expect("exit outside of a thread");
+    info!("thread {} ended all threads, status {}", thread.tid, status);
+    for thread in sched.threads.drain(..) {
+        if let State::Waiting { waiter, .. } = &thread.state {
+            futex::cancel(waiter);
+        }
+    }
+    sched.switch(frame);
+}
@@ -1,3 +1,5 @@
+use core::time::Duration;
+
 use riscv::register::time;
 use sbi::legacy::set_timer;
 
@@ -15,3 +17,16 @@ pub fn init() {
 pub fn set_next_timer() {
     set_timer(time::read64() + (QEMU_FREQ / TICKS_PER_SEC) as u64);
 }
+
+/// Returns the value of the `time` CSR `dur` from now.
+pub fn ticks_after(dur: Duration) -> u64 {
+    time::read64() + duration_to_ticks(dur)
+}
+
+pub fn duration_to_ticks(dur: Duration) -> u64 {
+    (dur.as_micros() * QEMU_FREQ as u128 / 1_000_000) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_micros((ticks as u128 * 1_000_000 / QEMU_FREQ as u128) as u64)
+}
@@ -3,20 +3,61 @@ use log::warn;
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt},
-    sstatus::{self, Sstatus},
-    stvec,
+    sstatus, stvec,
 };
 
-use crate::{plic, print, timer};
+use crate::{plic, print, syscall, thread, timer};
 
-#[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub struct Frame {
     gprs: [usize; 32],
-    sstatus: Sstatus,
+    sstatus: usize,
     sepc: usize,
 }
 
+// Indices into `Frame::gprs`, by ABI name.
+pub const SP: usize = 2;
+pub const TP: usize = 4;
+pub const A0: usize = 10;
+pub const A7: usize = 17;
+
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
+impl Frame {
+    /// A frame that `sret`s to `entry` in user mode, with the stack at `sp`
+    /// and everything else zeroed.
+    pub fn user(entry: usize, sp: usize) -> Frame {
+        let mut gprs = [0; 32];
+        gprs[SP] = sp;
+        Frame {
+            gprs,
+            // Interrupts are always on in user mode, SPIE keeps them on once
+            // we're back in the kernel.
+            sstatus: sstatus::read().bits() & !SSTATUS_SPP | SSTATUS_SPIE,
+            sepc: entry,
+        }
+    }
+
+    pub fn reg(&self, reg: usize) -> usize {
+        self.gprs[reg]
+    }
+
+    pub fn set_reg(&mut self, reg: usize, value: usize) {
+        self.gprs[reg] = value;
+    }
+
+    /// Whether the trap came from user mode.
+    pub fn is_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// Return to the instruction after the one that trapped, an `ecall`.
+    pub fn skip_instruction(&mut self) {
+        self.sepc += 4;
+    }
+}
+
 //type HandlerFn = extern "C" fn(&mut Frame);
 
 macro_rules! handler {
@@ -26,11 +67,16 @@ macro_rules! handler {
         extern "C" fn wrapper() {
             unsafe {
                 asm!(
+                    // `sscratch` is the kernel stack to switch to while in
+                    // user mode, and 0 while in the kernel.
+                    "csrrw sp, sscratch, sp",
+                    "bnez sp, 1f",
+                    "csrr sp, sscratch",
+                    "1:",
                     "addi sp, sp, -{frame_size}",
 
                     "sd x0,  0*8(sp)",
                     "sd x1,  1*8(sp)",
-                    "#sd x2,  2*8(sp)",
                     "sd x3,  3*8(sp)",
                     "sd x4,  4*8(sp)",
                     "sd x5,  5*8(sp)",
@@ -61,6 +107,10 @@ macro_rules! handler {
                     "sd x30, 30*8(sp)",
                     "sd x31, 31*8(sp)",
 
+                    // The stack pointer from before the trap.
+                    "csrrw t0, sscratch, zero",
+                    "sd t0, 2*8(sp)",
+
                     "csrr s0, sstatus",
                     "csrr s1, sepc",
 
@@ -76,9 +126,16 @@ macro_rules! handler {
                     "csrw sstatus, s0",
                     "csrw sepc,    s1",
 
+                    // Going to user mode, possibly another thread than the
+                    // one that trapped. The next trap gets this stack.
+                    "andi s0, s0, {spp}",
+                    "bnez s0, 2f",
+                    "addi s1, sp, {frame_size}",
+                    "csrw sscratch, s1",
+                    "2:",
+
                     "#ld x0,  0*8(sp)",
                     "ld x1,  1*8(sp)",
-                    "#ld x2,  2*8(sp)",
                     "ld x3,  3*8(sp)",
                     "ld x4,  4*8(sp)",
                     "ld x5,  5*8(sp)",
@@ -109,11 +166,12 @@ macro_rules! handler {
                     "ld x30, 30*8(sp)",
                     "ld x31, 31*8(sp)",
 
-                    "addi sp, sp, {frame_size}",
+                    "ld x2,  2*8(sp)",
 
                     "sret",
 
                     frame_size = const core::mem::size_of::<Frame>(),
+                    spp = const SSTATUS_SPP,
                     handler_fn = sym $name,
                     options(noreturn)
                 );
@@ -125,6 +183,7 @@ macro_rules! handler {
 
 pub fn init() {
     unsafe {
+        asm!("csrw sscratch, zero");
         stvec::write(handler!(handle_trap) as usize, TrapMode::Direct);
         sstatus::set_sie();
     }
@@ -145,7 +204,8 @@ pub fn handle_interrupts(frame: &mut Frame, tval: usize, intr: Interrupt) {
         Interrupt::UserTimer => todo!(),
         Interrupt::SupervisorTimer => {
             print!(".");
-            timer::set_next_timer()
+            timer::set_next_timer();
+            thread::schedule(frame);
         }
         Interrupt::UserExternal => todo!(),
         Interrupt::SupervisorExternal => plic::handle_interrupts(frame),
@@ -158,6 +218,18 @@ pub fn handle_interrupts(frame: &mut Frame, tval: usize, intr: Interrupt) {
 }
 
 pub fn handle_exceptions(frame: &mut Frame, tval: usize, except: Exception) {
+    if let Exception::UserEnvCall = except {
+        return syscall::handle(frame);
+    }
+    // A broken program only takes itself down.
+    if frame.is_user() {
+        warn!(
+            "{:?} in user mode at 0x{:x}, stval 0x{:x}",
+            except, frame.sepc, tval
+        );
+        return thread::exit_group(frame, -1);
+    }
+
     warn!("Fuck at 0x{:x}", frame.sepc);
     match except {
         Exception::InstructionMisaligned => todo!(),
@@ -170,7 +242,7 @@ pub fn handle_exceptions(frame: &mut Frame, tval: usize, except: Exception) {
         Exception::LoadFault => todo!(),
         Exception::StoreMisaligned => todo!(),
         Exception::StoreFault => todo!(),
-        Exception::UserEnvCall => todo!(),
+        Exception::UserEnvCall => unreachable!(),
         Exception::InstructionPageFault => todo!(),
         Exception::LoadPageFault => todo!(),
         Exception::StorePageFault => todo!(),
@@ -0,0 +1,8 @@
+#include "syscall.h"
+
+int main(void);
+
+void _start(void)
+{
+    sys_exit_group(main());
+}
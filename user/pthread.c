@@ -0,0 +1,123 @@
+#include "pthread.h"
+#include "syscall.h"
+
+#define EINVAL 22
+
+#define CLONE_VM 0x100
+#define CLONE_FS 0x200
+#define CLONE_FILES 0x400
+#define CLONE_SIGHAND 0x800
+#define CLONE_THREAD 0x10000
+#define CLONE_SYSVSEM 0x40000
+#define CLONE_SETTLS 0x80000
+#define CLONE_PARENT_SETTID 0x100000
+#define CLONE_CHILD_CLEARTID 0x200000
+
+/* There's no mmap, stacks come from a fixed pool. */
+#define PTHREAD_MAX 16
+#define STACK_SIZE (16 * 1024)
+
+struct pthread {
+    void *(*start)(void *);
+    void *arg;
+    void *result;
+    /* Set by clone, and zeroed (with a futex wake) by the kernel when the
+     * thread exits. */
+    volatile int tid;
+    /* Whether the slot is taken, until joined. */
+    int used;
+};
+
+/* In clone.S: runs fn(arg) on `stack` in a new thread. */
+long __clone(int (*fn)(void *), void *stack, long flags, void *arg, volatile int *ptid,
+             void *tls, volatile int *ctid);
+
+static struct pthread threads[PTHREAD_MAX];
+static char stacks[PTHREAD_MAX][STACK_SIZE] __attribute__((aligned(16)));
+static struct pthread main_thread;
+
+static int start(void *arg)
+{
+    struct pthread *self = arg;
+    self->result = self->start(self->arg);
+    return 0;
+}
+
+int pthread_create(pthread_t *thread, const pthread_attr_t *attr, void *(*fn)(void *), void *arg)
+{
+    if (attr)
+        return EINVAL;
+    for (int i = 0; i < PTHREAD_MAX; i++) {
+        struct pthread *t = &threads[i];
+        if (__atomic_exchange_n(&t->used, 1, __ATOMIC_ACQUIRE))
+            continue;
+        t->start = fn;
+        t->arg = arg;
+        t->result = 0;
+        long flags = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD |
+                     CLONE_SYSVSEM | CLONE_SETTLS | CLONE_PARENT_SETTID |
+                     CLONE_CHILD_CLEARTID;
+        long tid = __clone(start, stacks[i] + STACK_SIZE, flags, t, &t->tid, t, &t->tid);
+        if (tid < 0) {
+            __atomic_store_n(&t->used, 0, __ATOMIC_RELEASE);
+            return -tid;
+        }
+        *thread = t;
+        return 0;
+    }
+    return EAGAIN;
+}
+
+int pthread_join(pthread_t thread, void **result)
+{
+    int tid;
+    /* Not a private futex: like Linux, the kernel wakes this one up as a
+     * shared futex when the thread exits. */
+    while ((tid = thread->tid) != 0)
+        syscall6(SYS_futex, (long)&thread->tid, FUTEX_WAIT, tid, 0, 0, 0);
+    if (result)
+        *result = thread->result;
+    __atomic_store_n(&thread->used, 0, __ATOMIC_RELEASE);
+    return 0;
+}
+
+void pthread_exit(void *result)
+{
+    pthread_self()->result = result;
+    /* Just this thread, unlike exit_group. */
+    syscall6(SYS_exit, 0, 0, 0, 0, 0, 0);
+    __builtin_unreachable();
+}
+
+pthread_t pthread_self(void)
+{
+    /* The thread pointer, set by clone. */
+    struct pthread *self;
+    asm("mv %0, tp" : "=r"(self));
+    return self ? self : &main_thread;
+}
+
+/* The mutex from Ulrich Drepper's "Futexes Are Tricky". */
+int pthread_mutex_lock(pthread_mutex_t *mutex)
+{
+    int c = 0;
+    if (__atomic_compare_exchange_n(&mutex->state, &c, 1, 0, __ATOMIC_ACQUIRE,
+                                    __ATOMIC_RELAXED))
+        return 0;
+    if (c != 2)
+        c = __atomic_exchange_n(&mutex->state, 2, __ATOMIC_ACQUIRE);
+    while (c != 0) {
+        futex_wait(&mutex->state, 2, 0);
+        c = __atomic_exchange_n(&mutex->state, 2, __ATOMIC_ACQUIRE);
+    }
+    return 0;
+}
+
+int pthread_mutex_unlock(pthread_mutex_t *mutex)
+{
+    if (__atomic_fetch_sub(&mutex->state, 1, __ATOMIC_RELEASE) != 1) {
+        __atomic_store_n(&mutex->state, 0, __ATOMIC_RELEASE);
+        futex_wake(&mutex->state, 1);
+    }
+    return 0;
+}
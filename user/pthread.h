@@ -0,0 +1,25 @@
+/* Just enough pthreads to run threads.c: create, join, exit and mutexes. */
+#ifndef PTHREAD_H
+#define PTHREAD_H
+
+typedef struct pthread *pthread_t;
+typedef struct pthread_attr pthread_attr_t;
+
+typedef struct {
+    /* 0: unlocked, 1: locked, 2: locked with (maybe) waiters. */
+    volatile int state;
+} pthread_mutex_t;
+
+#define PTHREAD_MUTEX_INITIALIZER { 0 }
+
+/* `attr` has to be NULL. At most PTHREAD_MAX threads exist at once. */
+int pthread_create(pthread_t *thread, const pthread_attr_t *attr, void *(*start)(void *),
+                   void *arg);
+int pthread_join(pthread_t thread, void **result);
+void pthread_exit(void *result) __attribute__((noreturn));
+pthread_t pthread_self(void);
+
+int pthread_mutex_lock(pthread_mutex_t *mutex);
+int pthread_mutex_unlock(pthread_mutex_t *mutex);
+
+#endif
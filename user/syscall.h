@@ -0,0 +1,78 @@
+/* System calls, with the kernel's (Linux's) numbers. */
+#ifndef SYSCALL_H
+#define SYSCALL_H
+
+typedef unsigned long size_t;
+typedef long ssize_t;
+
+#define SYS_write 64
+#define SYS_exit 93
+#define SYS_exit_group 94
+#define SYS_futex 98
+#define SYS_sched_yield 124
+#define SYS_gettid 178
+#define SYS_clone 220
+
+#define EAGAIN 11
+#define ETIMEDOUT 110
+
+#define FUTEX_WAIT 0
+#define FUTEX_WAKE 1
+#define FUTEX_PRIVATE_FLAG 128
+
+struct timespec {
+    long tv_sec;
+    long tv_nsec;
+};
+
+static inline long syscall6(long n, long a, long b, long c, long d, long e, long f)
+{
+    register long a7 asm("a7") = n;
+    register long a0 asm("a0") = a;
+    register long a1 asm("a1") = b;
+    register long a2 asm("a2") = c;
+    register long a3 asm("a3") = d;
+    register long a4 asm("a4") = e;
+    register long a5 asm("a5") = f;
+    asm volatile("ecall"
+                 : "+r"(a0)
+                 : "r"(a7), "r"(a1), "r"(a2), "r"(a3), "r"(a4), "r"(a5)
+                 : "memory");
+    return a0;
+}
+
+static inline ssize_t sys_write(int fd, const void *buf, size_t len)
+{
+    return syscall6(SYS_write, fd, (long)buf, len, 0, 0, 0);
+}
+
+static inline void sys_exit_group(int status)
+{
+    syscall6(SYS_exit_group, status, 0, 0, 0, 0, 0);
+    __builtin_unreachable();
+}
+
+static inline int sys_sched_yield(void)
+{
+    return syscall6(SYS_sched_yield, 0, 0, 0, 0, 0, 0);
+}
+
+static inline int sys_gettid(void)
+{
+    return syscall6(SYS_gettid, 0, 0, 0, 0, 0, 0);
+}
+
+/* Returns 0 once woken up, or -EAGAIN / -ETIMEDOUT. */
+static inline int futex_wait(volatile int *uaddr, int val, const struct timespec *timeout)
+{
+    return syscall6(SYS_futex, (long)uaddr, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, val,
+                    (long)timeout, 0, 0);
+}
+
+/* Returns how many waiters were woken up. */
+static inline int futex_wake(volatile int *uaddr, int count)
+{
+    return syscall6(SYS_futex, (long)uaddr, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, count, 0, 0, 0);
+}
+
+#endif
@@ -0,0 +1,73 @@
+/* Threads bumping a counter under a mutex, as a test for pthread.c and the
+ * futex and clone syscalls. Run it with `init=/THREADS.ELF`. */
+#include "pthread.h"
+#include "syscall.h"
+
+#define THREADS 4
+#define ROUNDS 1000
+
+static pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+static long counter;
+
+static void print(const char *s)
+{
+    size_t len = 0;
+    while (s[len])
+        len++;
+    while (len > 0) {
+        ssize_t n = sys_write(1, s, len);
+        if (n <= 0)
+            return;
+        s += n;
+        len -= n;
+    }
+}
+
+static int fail(const char *why)
+{
+    print("threads: FAIL: ");
+    print(why);
+    print("\n");
+    return 1;
+}
+
+static void *work(void *arg)
+{
+    for (int i = 0; i < ROUNDS; i++) {
+        pthread_mutex_lock(&lock);
+        long seen = counter;
+        /* Let the others run into the locked mutex. */
+        if (i % 100 == 0)
+            sys_sched_yield();
+        counter = seen + 1;
+        pthread_mutex_unlock(&lock);
+    }
+    return arg;
+}
+
+int main(void)
+{
+    volatile int word = 0;
+    struct timespec timeout = { 0, 200 * 1000 * 1000 };
+    if (futex_wait(&word, 1, 0) != -EAGAIN)
+        return fail("FUTEX_WAIT on a changed value");
+    if (futex_wait(&word, 0, &timeout) != -ETIMEDOUT)
+        return fail("FUTEX_WAIT timeout");
+
+    pthread_t threads[THREADS];
+    for (long i = 0; i < THREADS; i++) {
+        if (pthread_create(&threads[i], 0, work, (void *)(i + 1)))
+            return fail("pthread_create");
+    }
+    for (long i = 0; i < THREADS; i++) {
+        void *result;
+        pthread_join(threads[i], &result);
+        if (result != (void *)(i + 1))
+            return fail("thread result");
+    }
+    if (counter != THREADS * ROUNDS)
+        return fail("lost updates");
+
+    print("threads: ok\n");
+    return 0;
+}